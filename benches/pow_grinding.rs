@@ -0,0 +1,47 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use plonky2::field::goldilocks_field::GoldilocksField;
+use plonky2::field::types::{Field, PrimeField64};
+use plonky2::hash::hashing::PlonkyPermutation;
+use plonky2_maybe_rayon::*;
+use plonky2_poseidon2::poseidon2_hash::grinding::Poseidon2PowGrinder;
+use plonky2_poseidon2::poseidon2_hash::poseidon2::{Poseidon2Permutation, WIDTH};
+
+type F = GoldilocksField;
+
+/// The search of plonky2's FRI proof of work, with a full permutation per
+/// candidate.
+fn grind_permutation(state: [F; WIDTH], pow_bits: u32) -> F {
+    let min_leading_zeros = pow_bits + (64 - F::order().bits()) as u32;
+    (0..=F::NEG_ONE.to_canonical_u64())
+        .into_par_iter()
+        .find_any(|&candidate| {
+            let mut perm = Poseidon2Permutation::new(state);
+            perm.set_elt(F::from_canonical_u64(candidate), 0);
+            perm.permute();
+            let challenge = *perm.squeeze().last().unwrap();
+            challenge.to_canonical_u64().leading_zeros() >= min_leading_zeros
+        })
+        .map(F::from_canonical_u64)
+        .unwrap()
+}
+
+fn bench_pow_grinding(c: &mut Criterion) {
+    let mut group = c.benchmark_group("poseidon2-pow-grinding");
+    let state = core::array::from_fn(F::from_canonical_usize);
+
+    for pow_bits in [16, 18, 20] {
+        group.bench_function(format!("{} bits, permutation", pow_bits).as_str(), |b| {
+            b.iter(|| grind_permutation(state, pow_bits))
+        });
+        group.bench_function(format!("{} bits, grinder", pow_bits).as_str(), |b| {
+            b.iter(|| Poseidon2PowGrinder::new(state, 0).grind(pow_bits))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = bench_pow_grinding);
+criterion_main!(benches);
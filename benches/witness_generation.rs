@@ -0,0 +1,81 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use plonky2::field::goldilocks_field::GoldilocksField;
+use plonky2::field::types::Field;
+use plonky2::hash::hashing::PlonkyPermutation;
+use plonky2::iop::generator::generate_partial_witness;
+use plonky2::iop::target::Target;
+use plonky2::iop::witness::{PartialWitness, WitnessWrite};
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+use plonky2::plonk::circuit_data::{CircuitConfig, ProverCircuitData};
+use plonky2_poseidon2::config::Poseidon2GoldilocksConfig;
+use plonky2_poseidon2::gate::poseidon2_batch::{build_with_poseidon2_batching, DEFAULT_BATCH_SIZE};
+use plonky2_poseidon2::poseidon2_hash::poseidon2::{Poseidon2Hash, Poseidon2Permutation, WIDTH};
+
+const D: usize = 2;
+type C = Poseidon2GoldilocksConfig;
+type F = GoldilocksField;
+
+/// Builds a circuit with `2^log_num_perms` permutations, either independent
+/// from each other or chained, optionally with batch witness generators.
+fn build_circuit(
+    log_num_perms: usize,
+    chained: bool,
+    batched: bool,
+) -> (ProverCircuitData<F, C, D>, Vec<Target>) {
+    let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+    let inputs = builder.add_virtual_targets(WIDTH);
+    let mut state = Poseidon2Permutation::new(inputs.clone());
+    for _ in 0..1 << log_num_perms {
+        let input = if chained {
+            state
+        } else {
+            Poseidon2Permutation::new(inputs.clone())
+        };
+        state = builder.permute::<Poseidon2Hash>(input);
+    }
+    builder.register_public_inputs(state.as_ref());
+    let data = if batched {
+        build_with_poseidon2_batching::<F, C, D>(builder, DEFAULT_BATCH_SIZE).prover_data()
+    } else {
+        builder.build_prover::<C>()
+    };
+    (data, inputs)
+}
+
+fn bench_witness_generation(c: &mut Criterion) {
+    let mut group = c.benchmark_group("poseidon2-witness-generation");
+
+    for log_num_perms in [10, 13] {
+        for chained in [false, true] {
+            for batched in [false, true] {
+                let (data, inputs) = build_circuit(log_num_perms, chained, batched);
+                let name = format!(
+                    "2^{} {} permutations, {} generators",
+                    log_num_perms,
+                    if chained { "chained" } else { "independent" },
+                    if batched { "batch" } else { "per-row" },
+                );
+                group.bench_function(name.as_str(), |b| {
+                    b.iter_batched(
+                        || {
+                            let mut pw = PartialWitness::new();
+                            for (i, &t) in inputs.iter().enumerate() {
+                                pw.set_target(t, F::from_canonical_usize(i));
+                            }
+                            pw
+                        },
+                        |pw| generate_partial_witness(pw, &data.prover_only, &data.common),
+                        BatchSize::PerIteration,
+                    )
+                });
+            }
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = bench_witness_generation);
+criterion_main!(benches);
@@ -0,0 +1,83 @@
+//! Runs the generator of a `Poseidon2Gate` on arbitrary inputs and swap flag,
+//! and checks that the generated row satisfies the gate constraints and holds
+//! the native permutation of the inputs.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use plonky2::gates::gate::Gate;
+use plonky2::hash::hash_types::HashOut;
+use plonky2::iop::generator::GeneratedValues;
+use plonky2::iop::target::Target;
+use plonky2::iop::witness::{PartitionWitness, Witness, WitnessWrite};
+use plonky2::plonk::vars::{EvaluationVars, EvaluationVarsBaseBatch};
+use plonky2_field::extension::{Extendable, FieldExtension};
+use plonky2_field::goldilocks_field::GoldilocksField;
+use plonky2_field::types::Field;
+use plonky2_poseidon2::gate::poseidon2::Poseidon2Gate;
+use plonky2_poseidon2::poseidon2_hash::poseidon2::{Poseidon2, WIDTH};
+
+const D: usize = 2;
+type F = GoldilocksField;
+type FE = <F as Extendable<D>>::Extension;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 8 * WIDTH + 1 {
+        return;
+    }
+    let mut inputs: [F; WIDTH] = core::array::from_fn(|i| {
+        F::from_noncanonical_u64(u64::from_le_bytes(
+            data[8 * i..8 * (i + 1)].try_into().unwrap(),
+        ))
+    });
+    let swap = data[8 * WIDTH] & 1 == 1;
+
+    // A single row, where each wire is its own partition.
+    let gate = Poseidon2Gate::<F, D>::new();
+    let representative_map = (0..gate.num_wires()).collect::<Vec<_>>();
+    let mut witness = PartitionWitness::new(gate.num_wires(), 1, &representative_map);
+    for (i, &input) in inputs.iter().enumerate() {
+        witness.set_target(Target::wire(0, Poseidon2Gate::<F, D>::wire_input(i)), input);
+    }
+    witness.set_target(
+        Target::wire(0, Poseidon2Gate::<F, D>::WIRE_SWAP),
+        F::from_bool(swap),
+    );
+
+    for generator in gate.generators(0, &[]) {
+        let mut out_buffer = GeneratedValues::empty();
+        assert!(generator.0.run(&witness, &mut out_buffer));
+        for (target, value) in out_buffer.target_values {
+            witness.set_target(target, value);
+        }
+    }
+    let wires = (0..gate.num_wires())
+        .map(|column| witness.get_target(Target::wire(0, column)))
+        .collect::<Vec<_>>();
+
+    if swap {
+        for i in 0..4 {
+            inputs.swap(i, 4 + i);
+        }
+    }
+    let outputs = (0..WIDTH).map(|i| wires[Poseidon2Gate::<F, D>::wire_output(i)]);
+    assert!(outputs.eq(F::poseidon2(inputs)));
+
+    let public_inputs_hash = HashOut::ZERO;
+    let vars_base = EvaluationVarsBaseBatch::new(1, &[], &wires, &public_inputs_hash);
+    assert!(gate
+        .eval_unfiltered_base_batch(vars_base)
+        .iter()
+        .all(|c| c.is_zero()));
+
+    let ext_wires = wires
+        .iter()
+        .map(|&w| <FE as FieldExtension<D>>::from_basefield(w))
+        .collect::<Vec<_>>();
+    let vars = EvaluationVars {
+        local_constants: &[],
+        local_wires: &ext_wires,
+        public_inputs_hash: &public_inputs_hash,
+    };
+    assert!(gate.eval_unfiltered(vars).iter().all(|c| c.is_zero()));
+});
@@ -0,0 +1,39 @@
+//! Deserializes a `Poseidon2Generator` from arbitrary bytes. This must never
+//! panic, and a generator which is read back must serialize to the bytes
+//! read.
+
+#![no_main]
+
+use std::sync::OnceLock;
+
+use libfuzzer_sys::fuzz_target;
+use plonky2::iop::generator::SimpleGenerator;
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+use plonky2::plonk::circuit_data::{CircuitConfig, CommonCircuitData};
+use plonky2::util::serialization::Buffer;
+use plonky2_field::goldilocks_field::GoldilocksField;
+use plonky2_poseidon2::config::Poseidon2GoldilocksConfig;
+use plonky2_poseidon2::gate::poseidon2::Poseidon2Generator;
+
+const D: usize = 2;
+type F = GoldilocksField;
+
+fn common_data() -> &'static CommonCircuitData<F, D> {
+    static COMMON: OnceLock<CommonCircuitData<F, D>> = OnceLock::new();
+    COMMON.get_or_init(|| {
+        CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config())
+            .build::<Poseidon2GoldilocksConfig>()
+            .common
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let common = common_data();
+    let mut src = Buffer::new(data);
+    if let Ok(generator) = Poseidon2Generator::<F, D>::deserialize(&mut src, common) {
+        let mut bytes = Vec::new();
+        generator.serialize(&mut bytes, common).unwrap();
+        assert_eq!(bytes, data[..src.pos()]);
+        let _ = generator.dependencies();
+    }
+});
@@ -0,0 +1,44 @@
+//! Compares `Poseidon2Hash::hash_no_pad` on inputs of arbitrary length with a
+//! plain sponge over `Poseidon2::poseidon2`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use plonky2::hash::hash_types::{HashOut, NUM_HASH_OUT_ELTS};
+use plonky2::plonk::config::Hasher;
+use plonky2_field::goldilocks_field::GoldilocksField;
+use plonky2_field::types::Field;
+use plonky2_poseidon2::poseidon2_hash::poseidon2::{Poseidon2, Poseidon2Hash, WIDTH};
+
+type F = GoldilocksField;
+
+/// Number of input elements absorbed by each permutation.
+const RATE: usize = 8;
+
+/// Overwrite-mode sponge with a zero initial state and no padding: each chunk
+/// of `RATE` inputs replaces the start of the state, which is then permuted.
+fn reference_hash_no_pad(inputs: &[F]) -> HashOut<F> {
+    let mut state = [F::ZERO; WIDTH];
+    for chunk in inputs.chunks(RATE) {
+        state[..chunk.len()].copy_from_slice(chunk);
+        state = F::poseidon2(state);
+    }
+    HashOut {
+        elements: state[..NUM_HASH_OUT_ELTS].try_into().unwrap(),
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let inputs = data
+        .chunks(8)
+        .map(|chunk| {
+            let mut bytes = [0; 8];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            F::from_noncanonical_u64(u64::from_le_bytes(bytes))
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        Poseidon2Hash::hash_no_pad(&inputs),
+        reference_hash_no_pad(&inputs)
+    );
+});
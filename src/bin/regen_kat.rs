@@ -0,0 +1,31 @@
+//! Regenerates the known-answer test vectors of
+//! [`plonky2_poseidon2::poseidon2_hash::kat`].
+//!
+//! Usage: `cargo run --release --bin regen_kat [DIR]`, where `DIR` defaults to
+//! the `test_vectors` directory of the crate. Fails without writing anything
+//! if the implementation no longer matches the reference vectors.
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+use plonky2_poseidon2::poseidon2_hash::kat::{KnownAnswerTests, KAT_DIR};
+
+fn main() -> Result<()> {
+    let dir = std::env::args()
+        .nth(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(KAT_DIR));
+
+    let kats = KnownAnswerTests::generate();
+    kats.check_native()?;
+    kats.write(&dir)?;
+    println!(
+        "Wrote {} permutation, {} hash_no_pad, {} two_to_one and {} Merkle root vectors to {}",
+        kats.permutation.len(),
+        kats.hash_no_pad.len(),
+        kats.two_to_one.len(),
+        kats.merkle_root.len(),
+        dir.display()
+    );
+    Ok(())
+}
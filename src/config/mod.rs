@@ -1,8 +1,15 @@
+pub mod presets;
+
+use plonky2::hash::keccak::KeccakHash;
+use plonky2::hash::poseidon::PoseidonHash;
 use plonky2::plonk::config::GenericConfig;
 use plonky2_field::extension::quadratic::QuadraticExtension;
+use plonky2_field::extension::quartic::QuarticExtension;
+use plonky2_field::extension::quintic::QuinticExtension;
 use plonky2_field::goldilocks_field::GoldilocksField;
 use serde::{Deserialize, Serialize};
 use crate::poseidon2_hash::poseidon2::Poseidon2Hash;
+use crate::poseidon2_hash::poseidon2_bn254::Poseidon2Bn254Hash;
 
 /// Configuration using Poseidon2 over the Goldilocks field.
 #[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
@@ -12,4 +19,164 @@ impl GenericConfig<2> for Poseidon2GoldilocksConfig {
     type FE = QuadraticExtension<Self::F>;
     type Hasher = Poseidon2Hash;
     type InnerHasher = Poseidon2Hash;
-}
\ No newline at end of file
+}
+
+/// Configuration using Poseidon2 over the Goldilocks field, with challenges
+/// drawn from its quartic extension. FRI and the permutation argument are
+/// then sound over a 256-bit field rather than a 128-bit one.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Poseidon2GoldilocksQuarticConfig;
+impl GenericConfig<4> for Poseidon2GoldilocksQuarticConfig {
+    type F = GoldilocksField;
+    type FE = QuarticExtension<Self::F>;
+    type Hasher = Poseidon2Hash;
+    type InnerHasher = Poseidon2Hash;
+}
+
+/// Configuration using Poseidon2 over the Goldilocks field, with challenges
+/// drawn from its quintic extension, a 320-bit field.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Poseidon2GoldilocksQuinticConfig;
+impl GenericConfig<5> for Poseidon2GoldilocksQuinticConfig {
+    type F = GoldilocksField;
+    type FE = QuinticExtension<Self::F>;
+    type Hasher = Poseidon2Hash;
+    type InnerHasher = Poseidon2Hash;
+}
+
+/// Configuration using Poseidon as the outer hasher and Poseidon2 as the inner
+/// hasher over the Goldilocks field.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PoseidonPoseidon2GoldilocksConfig;
+impl GenericConfig<2> for PoseidonPoseidon2GoldilocksConfig {
+    type F = GoldilocksField;
+    type FE = QuadraticExtension<Self::F>;
+    type Hasher = PoseidonHash;
+    type InnerHasher = Poseidon2Hash;
+}
+
+/// Configuration using Poseidon2 as the outer hasher and Poseidon as the inner
+/// hasher over the Goldilocks field.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Poseidon2PoseidonGoldilocksConfig;
+impl GenericConfig<2> for Poseidon2PoseidonGoldilocksConfig {
+    type F = GoldilocksField;
+    type FE = QuadraticExtension<Self::F>;
+    type Hasher = Poseidon2Hash;
+    type InnerHasher = PoseidonHash;
+}
+
+/// Configuration using truncated Keccak as the outer hasher and Poseidon2 as
+/// the inner hasher over the Goldilocks field. Proofs under this config are
+/// cheap to verify on the EVM, but not inside a Plonky2 circuit; see
+/// [`crate::recursion::wrap_proof_keccak`].
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Poseidon2KeccakGoldilocksConfig;
+impl GenericConfig<2> for Poseidon2KeccakGoldilocksConfig {
+    type F = GoldilocksField;
+    type FE = QuadraticExtension<Self::F>;
+    type Hasher = KeccakHash<25>;
+    type InnerHasher = Poseidon2Hash;
+}
+
+/// Configuration using Poseidon2 over BN254 as the outer hasher and Poseidon2
+/// over Goldilocks as the inner hasher. Merkle caps and Fiat-Shamir challenges
+/// of proofs under this config are native to BN254, so that they can be
+/// verified inside a BN254-based SNARK; see
+/// [`crate::recursion::wrap_proof_bn254`].
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Poseidon2Bn254GoldilocksConfig;
+impl GenericConfig<2> for Poseidon2Bn254GoldilocksConfig {
+    type F = GoldilocksField;
+    type FE = QuadraticExtension<Self::F>;
+    type Hasher = Poseidon2Bn254Hash;
+    type InnerHasher = Poseidon2Hash;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use anyhow::Result;
+    use plonky2::hash::hashing::PlonkyPermutation;
+    use plonky2::hash::hash_types::RichField;
+    use plonky2::iop::witness::{PartialWitness, WitnessWrite};
+    use plonky2::plonk::circuit_builder::CircuitBuilder;
+    use plonky2::plonk::circuit_data::CircuitConfig;
+    use plonky2::plonk::config::GenericConfig;
+    use plonky2_field::extension::Extendable;
+
+    use crate::config::{
+        Poseidon2GoldilocksConfig, Poseidon2GoldilocksQuarticConfig,
+        Poseidon2GoldilocksQuinticConfig,
+    };
+    use crate::poseidon2_hash::poseidon2::{Poseidon2, Poseidon2Hash, Poseidon2Permutation, WIDTH};
+
+    /// Proves a chain of 64 permutations with the standard FRI parameters, then
+    /// verifies the proof natively and in a circuit built with `outer_config`.
+    fn prove_and_verify<F, C, const D: usize>(outer_config: CircuitConfig) -> Result<()>
+    where
+        F: RichField + Extendable<D> + Poseidon2,
+        C: GenericConfig<D, F = F, Hasher = Poseidon2Hash>,
+    {
+        let config = CircuitConfig::standard_recursion_config();
+        let mut builder = CircuitBuilder::<F, D>::new(config);
+        let inputs = builder.add_virtual_targets(WIDTH);
+        let mut state = Poseidon2Permutation::new(inputs.clone());
+        for _ in 0..64 {
+            state = builder.permute::<Poseidon2Hash>(state);
+        }
+        builder.register_public_inputs(state.as_ref());
+        let data = builder.build::<C>();
+
+        let input_values = core::array::from_fn(|i| F::from_canonical_usize(i));
+        let mut pw = PartialWitness::new();
+        pw.set_target_arr(&inputs, &input_values);
+        let start = Instant::now();
+        let proof = data.prove(pw)?;
+        println!(
+            "D = {}: proof size {} bytes, proved in {:?}",
+            D,
+            proof.to_bytes().len(),
+            start.elapsed()
+        );
+        let expected = (0..64).fold(input_values, |state, _| F::poseidon2(state));
+        assert_eq!(proof.public_inputs, expected);
+        data.verify(proof.clone())?;
+
+        let mut builder = CircuitBuilder::<F, D>::new(outer_config);
+        let proof_t = builder.add_virtual_proof_with_pis(&data.common);
+        let verifier_t = builder.constant_verifier_data(&data.verifier_only);
+        builder.verify_proof::<C>(&proof_t, &verifier_t, &data.common);
+        let outer_data = builder.build::<C>();
+        let mut pw = PartialWitness::new();
+        pw.set_proof_with_pis_target(&proof_t, &proof);
+        let outer_proof = outer_data.prove(pw)?;
+        outer_data.verify(outer_proof)
+    }
+
+    #[test]
+    fn quadratic() -> Result<()> {
+        prove_and_verify::<_, Poseidon2GoldilocksConfig, 2>(
+            CircuitConfig::standard_recursion_config(),
+        )
+    }
+
+    #[test]
+    fn quartic() -> Result<()> {
+        prove_and_verify::<_, Poseidon2GoldilocksQuarticConfig, 4>(
+            CircuitConfig::standard_recursion_config(),
+        )
+    }
+
+    #[test]
+    fn quintic() -> Result<()> {
+        // Folding an arity 16 FRI layer over the quintic extension needs 91
+        // routed wires in the recursive verifier.
+        let outer_config = CircuitConfig {
+            num_routed_wires: 100,
+            ..CircuitConfig::standard_recursion_config()
+        };
+        prove_and_verify::<_, Poseidon2GoldilocksQuinticConfig, 5>(outer_config)
+    }
+}
@@ -0,0 +1,246 @@
+//! `CircuitConfig` presets for circuits built around the [`Poseidon2Gate`].
+//!
+//! All presets target 100 bits of conjectured FRI security and allow quotient
+//! degree factor 8, which the degree-7 Poseidon2 gates need together with
+//! their selector. The quotient is evaluated on the LDE domain, so the FRI
+//! rate can be at most 1/8.
+//!
+//! | preset                        | routed wires | rate  | queries | cap height |
+//! |-------------------------------|--------------|-------|---------|------------|
+//! | [`fast_prover_config`]        | 28           | 1/8   | 28      | 4          |
+//! | [`small_proof_config`]        | 80           | 1/128 | 12      | 0          |
+//! | [`recursion_friendly_config`] | 80           | 1/8   | 28      | 4          |
+//! | [`zk_config`]                 | 80           | 1/8   | 28      | 4          |
+
+use plonky2::fri::reduction_strategies::FriReductionStrategy;
+use plonky2::fri::FriConfig;
+use plonky2::gates::gate::Gate;
+use plonky2::hash::hash_types::RichField;
+use plonky2::plonk::circuit_data::CircuitConfig;
+use plonky2_field::extension::Extendable;
+use plonky2_field::goldilocks_field::GoldilocksField;
+use plonky2_field::types::Field;
+
+use crate::gate::poseidon2::Poseidon2Gate;
+use crate::gate::poseidon2_position::Poseidon2PositionGate;
+use crate::gate::poseidon2_sponge::{AbsorptionMode, Poseidon2SpongeGate};
+
+/// Number of wires of the [`Poseidon2Gate`], which does not depend on the
+/// field nor on the extension degree.
+pub fn poseidon2_gate_num_wires() -> usize {
+    Poseidon2Gate::<GoldilocksField, 2>::new().num_wires()
+}
+
+/// Number of wires needed by every Poseidon2 gate of this crate, including the
+/// [`Poseidon2SpongeGate`] in `Add` mode and the [`Poseidon2PositionGate`].
+pub fn poseidon2_wide_num_wires() -> usize {
+    [
+        poseidon2_gate_num_wires(),
+        Poseidon2SpongeGate::<GoldilocksField, 2>::new(AbsorptionMode::Add).num_wires(),
+        Poseidon2PositionGate::<GoldilocksField, 2>::new().num_wires(),
+    ]
+    .into_iter()
+    .max()
+    .unwrap()
+}
+
+/// The conjectured security of `config`, as checked by `CircuitBuilder`: the
+/// FRI query security, capped by the size of the extension field.
+pub fn conjectured_security_bits<F: RichField + Extendable<D>, const D: usize>(
+    config: &CircuitConfig,
+) -> usize {
+    let fri = &config.fri_config;
+    let query_bits = fri.num_query_rounds * fri.rate_bits + fri.proof_of_work_bits as usize;
+    let field_bits = F::Extension::order().bits() as usize;
+    query_bits.min(field_bits)
+}
+
+/// Number of routed wires of [`fast_prover_config`]: the inputs, outputs and
+/// swap flag of the [`Poseidon2Gate`], rounded up to a multiple of 4 so that
+/// arithmetic gates use all of them.
+pub const FAST_PROVER_NUM_ROUTED_WIRES: usize = 28;
+
+/// A config minimizing proving time. Only the wires the [`Poseidon2Gate`]
+/// connects are routed, which removes most sigma polynomials and partial
+/// products from the commitments. Its proofs can be verified recursively in a
+/// circuit of another config such as [`recursion_friendly_config`], but not
+/// of this one, since the recursive verifier gates need 80 routed wires.
+pub fn fast_prover_config() -> CircuitConfig {
+    CircuitConfig {
+        num_wires: poseidon2_gate_num_wires(),
+        num_routed_wires: FAST_PROVER_NUM_ROUTED_WIRES,
+        ..CircuitConfig::standard_recursion_config()
+    }
+}
+
+/// A config minimizing proof size, e.g. for the last layer of a recursion
+/// tree: a rate of 1/128 needs only 12 FRI queries, and a single Merkle root
+/// per commitment avoids sending caps. The low-degree extensions are 16 times
+/// larger than with [`recursion_friendly_config`], and so is proving time.
+pub fn small_proof_config() -> CircuitConfig {
+    CircuitConfig {
+        num_wires: poseidon2_gate_num_wires(),
+        fri_config: FriConfig {
+            rate_bits: 7,
+            cap_height: 0,
+            proof_of_work_bits: 16,
+            reduction_strategy: FriReductionStrategy::ConstantArityBits(4, 5),
+            num_query_rounds: 12,
+        },
+        ..CircuitConfig::standard_recursion_config()
+    }
+}
+
+/// The config of circuits meant to be verified recursively. It is equal to
+/// `CircuitConfig::standard_recursion_config()`, whose 135 wires are exactly
+/// those of the [`Poseidon2Gate`], and is only named here next to the other
+/// presets. Recursively verifying one of its proofs fits in `2^12` rows of
+/// the same config, so that recursion layers keep the same shape.
+pub fn recursion_friendly_config() -> CircuitConfig {
+    CircuitConfig {
+        num_wires: poseidon2_gate_num_wires(),
+        ..CircuitConfig::standard_recursion_config()
+    }
+}
+
+/// [`recursion_friendly_config`] with zero-knowledge enabled, i.e. blinded
+/// polynomials and salted Merkle leaves.
+pub fn zk_config() -> CircuitConfig {
+    CircuitConfig {
+        zero_knowledge: true,
+        ..recursion_friendly_config()
+    }
+}
+
+/// [`recursion_friendly_config`] widened to [`poseidon2_wide_num_wires`], for
+/// circuits using the sponge gate in `Add` mode or the position gate.
+pub fn wide_config() -> CircuitConfig {
+    CircuitConfig {
+        num_wires: poseidon2_wide_num_wires(),
+        ..recursion_friendly_config()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use anyhow::Result;
+    use plonky2::hash::hashing::PlonkyPermutation;
+    use plonky2::iop::witness::{PartialWitness, WitnessWrite};
+    use plonky2::plonk::circuit_builder::CircuitBuilder;
+    use plonky2::plonk::circuit_data::{CircuitConfig, CircuitData};
+    use plonky2::plonk::config::GenericConfig;
+    use plonky2::plonk::proof::ProofWithPublicInputs;
+    use plonky2_field::types::Field;
+
+    use crate::config::presets::{
+        conjectured_security_bits, fast_prover_config, poseidon2_wide_num_wires,
+        recursion_friendly_config, small_proof_config, wide_config, zk_config,
+    };
+    use crate::config::Poseidon2GoldilocksConfig;
+    use crate::gate::poseidon2_position::Poseidon2PositionGate;
+    use crate::poseidon2_hash::poseidon2::{Poseidon2Hash, Poseidon2Permutation, WIDTH};
+
+    const D: usize = 2;
+    type C = Poseidon2GoldilocksConfig;
+    type F = <C as GenericConfig<D>>::F;
+
+    /// Proves a chain of 256 permutations with `config`, and reports its
+    /// degree bits, proof size, security bits and proving time.
+    fn prove_chain(
+        name: &str,
+        config: CircuitConfig,
+    ) -> Result<(CircuitData<F, C, D>, ProofWithPublicInputs<F, C, D>)> {
+        let security_bits = conjectured_security_bits::<F, D>(&config);
+        let mut builder = CircuitBuilder::<F, D>::new(config);
+        let inputs = builder.add_virtual_targets(WIDTH);
+        let mut state = Poseidon2Permutation::new(inputs.clone());
+        for _ in 0..256 {
+            state = builder.permute::<Poseidon2Hash>(state);
+        }
+        builder.register_public_inputs(state.as_ref());
+        let data = builder.build::<C>();
+
+        let mut pw = PartialWitness::new();
+        for (i, &t) in inputs.iter().enumerate() {
+            pw.set_target(t, F::from_canonical_usize(i));
+        }
+        let start = Instant::now();
+        let proof = data.prove(pw)?;
+        println!(
+            "{}: degree bits {}, proof size {} bytes, security bits {}, proved in {:?}",
+            name,
+            data.common.degree_bits(),
+            proof.to_bytes().len(),
+            security_bits,
+            start.elapsed()
+        );
+        assert!(security_bits >= 100);
+        data.verify(proof.clone())?;
+        Ok((data, proof))
+    }
+
+    #[test]
+    fn fast_prover() -> Result<()> {
+        let (fast, fast_proof) = prove_chain("fast prover", fast_prover_config())?;
+        let (standard, _) = prove_chain("recursion friendly", recursion_friendly_config())?;
+        assert!(fast.common.num_partial_products < standard.common.num_partial_products);
+
+        // Recursion needs a layer of another config.
+        let mut builder = CircuitBuilder::<F, D>::new(recursion_friendly_config());
+        let proof_t = builder.add_virtual_proof_with_pis(&fast.common);
+        let verifier_t = builder.constant_verifier_data(&fast.verifier_only);
+        builder.verify_proof::<C>(&proof_t, &verifier_t, &fast.common);
+        let data = builder.build::<C>();
+        let mut pw = PartialWitness::new();
+        pw.set_proof_with_pis_target(&proof_t, &fast_proof);
+        data.verify(data.prove(pw)?)
+    }
+
+    #[test]
+    fn small_proof() -> Result<()> {
+        let (_, small) = prove_chain("small proof", small_proof_config())?;
+        let (_, standard) = prove_chain("recursion friendly", recursion_friendly_config())?;
+        assert!(small.to_bytes().len() < standard.to_bytes().len());
+        Ok(())
+    }
+
+    #[test]
+    fn recursion_friendly() -> Result<()> {
+        let config = recursion_friendly_config();
+        assert_eq!(config, CircuitConfig::standard_recursion_config());
+        let (inner_data, inner_proof) = prove_chain("recursion friendly", config.clone())?;
+
+        let mut builder = CircuitBuilder::<F, D>::new(config);
+        let proof_t = builder.add_virtual_proof_with_pis(&inner_data.common);
+        let verifier_t = builder.constant_verifier_data(&inner_data.verifier_only);
+        builder.verify_proof::<C>(&proof_t, &verifier_t, &inner_data.common);
+        let data = builder.build::<C>();
+        println!(
+            "recursive verifier: degree bits {}",
+            data.common.degree_bits()
+        );
+        assert!(data.common.degree_bits() <= 12);
+
+        let mut pw = PartialWitness::new();
+        pw.set_proof_with_pis_target(&proof_t, &inner_proof);
+        let proof = data.prove(pw)?;
+        data.verify(proof)
+    }
+
+    #[test]
+    fn zero_knowledge() -> Result<()> {
+        let (data, _) = prove_chain("zk", zk_config())?;
+        assert!(data.common.config.zero_knowledge);
+        Ok(())
+    }
+
+    #[test]
+    fn wide() {
+        let config = wide_config();
+        assert_eq!(config.num_wires, poseidon2_wide_num_wires());
+        let mut builder = CircuitBuilder::<F, D>::new(config);
+        builder.add_gate(Poseidon2PositionGate::<F, D>::new(), vec![]);
+    }
+}
@@ -0,0 +1 @@
+pub mod poseidon2;
@@ -0,0 +1,324 @@
+//! Poseidon2 hashing in circuits, independently of the hasher of the config
+//! the circuit is built with.
+
+use plonky2::hash::hash_types::{HashOutTarget, MerkleCapTarget, RichField, NUM_HASH_OUT_ELTS};
+use plonky2::hash::hashing::PlonkyPermutation;
+use plonky2::hash::merkle_proofs::MerkleProofTarget;
+use plonky2::iop::target::{BoolTarget, Target};
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+use plonky2_field::extension::Extendable;
+
+use crate::poseidon2_hash::merkle_4ary::{
+    verify_quaternary_merkle_proof_circuit, QuaternaryMerkleProofTarget,
+};
+use crate::poseidon2_hash::poseidon2::{Poseidon2, Poseidon2Hash, Poseidon2Permutation, WIDTH};
+use crate::poseidon2_hash::xof::pad;
+
+/// Poseidon2 operations on a [`CircuitBuilder`], matching the native
+/// [`Poseidon2Hash`] functions. They can be used in circuits built with any
+/// `GenericConfig`, e.g. `PoseidonGoldilocksConfig`.
+pub trait CircuitBuilderPoseidon2<F: RichField + Extendable<D> + Poseidon2, const D: usize> {
+    /// The Poseidon2 permutation.
+    fn poseidon2_permute(&mut self, inputs: [Target; WIDTH]) -> [Target; WIDTH];
+
+    /// Hashes `inputs` without padding, like `Poseidon2Hash::hash_no_pad`.
+    fn poseidon2_hash(&mut self, inputs: Vec<Target>) -> HashOutTarget;
+
+    /// Pads `inputs` with the `pad10*1` rule then hashes them, like
+    /// `Poseidon2Hash::hash_pad`.
+    fn poseidon2_hash_pad(&mut self, inputs: Vec<Target>) -> HashOutTarget;
+
+    /// Returns `inputs` as is if they fit in a digest, or hashes them, like
+    /// `Poseidon2Hash::hash_or_noop`.
+    fn poseidon2_hash_or_noop(&mut self, inputs: Vec<Target>) -> HashOutTarget;
+
+    /// Squeezes `num_outputs` outputs of the
+    /// [`Poseidon2Xof`](crate::poseidon2_hash::xof::Poseidon2Xof) seeded with
+    /// `seed`.
+    fn poseidon2_squeeze(&mut self, seed: Vec<Target>, num_outputs: usize) -> Vec<Target>;
+
+    /// Compresses two digests, like `Poseidon2Hash::two_to_one`.
+    fn poseidon2_two_to_one(&mut self, left: HashOutTarget, right: HashOutTarget) -> HashOutTarget;
+
+    /// Verifies a binary Merkle proof of a `MerkleTree<F, Poseidon2Hash>`.
+    fn poseidon2_verify_merkle_proof(
+        &mut self,
+        leaf_data: Vec<Target>,
+        leaf_index_bits: &[BoolTarget],
+        merkle_root: HashOutTarget,
+        proof: &MerkleProofTarget,
+    );
+
+    /// Verifies a binary Merkle proof of a `MerkleTree<F, Poseidon2Hash>`
+    /// against its cap. The bits of `leaf_index_bits` beyond the length of the
+    /// proof select the digest of the cap.
+    fn poseidon2_verify_merkle_proof_to_cap(
+        &mut self,
+        leaf_data: Vec<Target>,
+        leaf_index_bits: &[BoolTarget],
+        merkle_cap: &MerkleCapTarget,
+        proof: &MerkleProofTarget,
+    );
+
+    /// Verifies a proof of a
+    /// [`QuaternaryMerkleTree`](crate::poseidon2_hash::merkle_4ary::QuaternaryMerkleTree).
+    /// The circuit config needs at least 144 wires.
+    fn poseidon2_verify_quaternary_merkle_proof(
+        &mut self,
+        leaf_data: Vec<Target>,
+        leaf_index_bits: &[BoolTarget],
+        merkle_root: HashOutTarget,
+        proof: &QuaternaryMerkleProofTarget,
+    );
+}
+
+impl<F: RichField + Extendable<D> + Poseidon2, const D: usize> CircuitBuilderPoseidon2<F, D>
+    for CircuitBuilder<F, D>
+{
+    fn poseidon2_permute(&mut self, inputs: [Target; WIDTH]) -> [Target; WIDTH] {
+        let outputs = self.permute::<Poseidon2Hash>(Poseidon2Permutation::new(inputs));
+        outputs.as_ref().try_into().unwrap()
+    }
+
+    fn poseidon2_hash(&mut self, inputs: Vec<Target>) -> HashOutTarget {
+        self.hash_n_to_hash_no_pad::<Poseidon2Hash>(inputs)
+    }
+
+    fn poseidon2_hash_pad(&mut self, inputs: Vec<Target>) -> HashOutTarget {
+        let zero = self.zero();
+        let one = self.one();
+        self.poseidon2_hash(pad(&inputs, zero, one))
+    }
+
+    fn poseidon2_hash_or_noop(&mut self, inputs: Vec<Target>) -> HashOutTarget {
+        self.hash_or_noop::<Poseidon2Hash>(inputs)
+    }
+
+    fn poseidon2_squeeze(&mut self, seed: Vec<Target>, num_outputs: usize) -> Vec<Target> {
+        let zero = self.zero();
+        let one = self.one();
+        self.hash_n_to_m_no_pad::<Poseidon2Hash>(pad(&seed, zero, one), num_outputs)
+    }
+
+    fn poseidon2_two_to_one(&mut self, left: HashOutTarget, right: HashOutTarget) -> HashOutTarget {
+        let zero = self.zero();
+        let mut inputs = [zero; WIDTH];
+        inputs[..NUM_HASH_OUT_ELTS].copy_from_slice(&left.elements);
+        inputs[NUM_HASH_OUT_ELTS..2 * NUM_HASH_OUT_ELTS].copy_from_slice(&right.elements);
+        let outputs = self.poseidon2_permute(inputs);
+        HashOutTarget::from_vec(outputs[..NUM_HASH_OUT_ELTS].to_vec())
+    }
+
+    fn poseidon2_verify_merkle_proof(
+        &mut self,
+        leaf_data: Vec<Target>,
+        leaf_index_bits: &[BoolTarget],
+        merkle_root: HashOutTarget,
+        proof: &MerkleProofTarget,
+    ) {
+        self.verify_merkle_proof::<Poseidon2Hash>(leaf_data, leaf_index_bits, merkle_root, proof);
+    }
+
+    fn poseidon2_verify_merkle_proof_to_cap(
+        &mut self,
+        leaf_data: Vec<Target>,
+        leaf_index_bits: &[BoolTarget],
+        merkle_cap: &MerkleCapTarget,
+        proof: &MerkleProofTarget,
+    ) {
+        self.verify_merkle_proof_to_cap::<Poseidon2Hash>(
+            leaf_data,
+            leaf_index_bits,
+            merkle_cap,
+            proof,
+        );
+    }
+
+    fn poseidon2_verify_quaternary_merkle_proof(
+        &mut self,
+        leaf_data: Vec<Target>,
+        leaf_index_bits: &[BoolTarget],
+        merkle_root: HashOutTarget,
+        proof: &QuaternaryMerkleProofTarget,
+    ) {
+        verify_quaternary_merkle_proof_circuit(
+            self,
+            leaf_data,
+            leaf_index_bits,
+            merkle_root,
+            proof,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use plonky2::hash::hash_types::{HashOut, HashOutTarget};
+    use plonky2::hash::merkle_proofs::MerkleProofTarget;
+    use plonky2::hash::merkle_tree::MerkleTree;
+    use plonky2::iop::witness::{PartialWitness, WitnessWrite};
+    use plonky2::plonk::circuit_builder::CircuitBuilder;
+    use plonky2::plonk::circuit_data::CircuitConfig;
+    use plonky2::plonk::config::{GenericConfig, Hasher, PoseidonGoldilocksConfig};
+    use plonky2_field::types::{Field, Sample};
+
+    use crate::gadgets::poseidon2::CircuitBuilderPoseidon2;
+    use crate::poseidon2_hash::kat::KnownAnswerTests;
+    use crate::poseidon2_hash::poseidon2::{Poseidon2, Poseidon2Hash, WIDTH};
+    use crate::poseidon2_hash::xof::Poseidon2Xof;
+
+    const D: usize = 2;
+    // A config whose own hasher is Poseidon, not Poseidon2.
+    type C = PoseidonGoldilocksConfig;
+    type F = <C as GenericConfig<D>>::F;
+
+    #[test]
+    fn matches_native() -> Result<()> {
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let mut pw = PartialWitness::new();
+
+        let inputs = F::rand_vec(13);
+        let inputs_t = builder.add_virtual_targets(inputs.len());
+        pw.set_target_arr(&inputs_t, &inputs);
+        let left = HashOut::<F>::rand();
+        let right = HashOut::<F>::rand();
+        let left_t = builder.add_virtual_hash();
+        let right_t = builder.add_virtual_hash();
+        pw.set_hash_target(left_t, left);
+        pw.set_hash_target(right_t, right);
+
+        let permuted = builder.poseidon2_permute(inputs_t[..WIDTH].try_into().unwrap());
+        let hash = builder.poseidon2_hash(inputs_t.clone());
+        let hash_pad = builder.poseidon2_hash_pad(inputs_t.clone());
+        let noop = builder.poseidon2_hash_or_noop(inputs_t[..3].to_vec());
+        let compressed = builder.poseidon2_two_to_one(left_t, right_t);
+        let squeezed = builder.poseidon2_squeeze(inputs_t.clone(), 21);
+        builder.register_public_inputs(&permuted);
+        builder.register_public_inputs(&hash.elements);
+        builder.register_public_inputs(&hash_pad.elements);
+        builder.register_public_inputs(&noop.elements);
+        builder.register_public_inputs(&compressed.elements);
+        builder.register_public_inputs(&squeezed);
+
+        let mut expected = F::poseidon2(inputs[..WIDTH].try_into().unwrap()).to_vec();
+        expected.extend(Poseidon2Hash::hash_no_pad(&inputs).elements);
+        expected.extend(Poseidon2Hash::hash_pad(&inputs).elements);
+        expected.extend(Poseidon2Hash::hash_or_noop(&inputs[..3]).elements);
+        expected.extend(Poseidon2Hash::two_to_one(left, right).elements);
+        expected.extend(Poseidon2Xof::new(&inputs).squeeze_n(21));
+
+        let data = builder.build::<C>();
+        let proof = data.prove(pw)?;
+        assert_eq!(proof.public_inputs, expected);
+        data.verify(proof)
+    }
+
+    #[test]
+    fn merkle_proofs() -> Result<()> {
+        let log_n = 4;
+        let cap_height = 1;
+        let leaves = (0..1 << log_n).map(|_| F::rand_vec(5)).collect::<Vec<_>>();
+        let tree = MerkleTree::<F, Poseidon2Hash>::new(leaves.clone(), cap_height);
+
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let mut pw = PartialWitness::new();
+        let cap_t = builder.add_virtual_cap(cap_height);
+        pw.set_cap_target(&cap_t, &tree.cap);
+        let root = MerkleTree::<F, Poseidon2Hash>::new(leaves.clone(), 0).cap.0[0];
+        let root_t = builder.add_virtual_hash();
+        pw.set_hash_target(root_t, root);
+
+        for leaf_index in [0, 7, 12] {
+            let index_t = builder.constant(F::from_canonical_usize(leaf_index));
+            let bits = builder.split_le(index_t, log_n);
+            let leaf_t = builder.add_virtual_targets(leaves[leaf_index].len());
+            pw.set_target_arr(&leaf_t, &leaves[leaf_index]);
+
+            let proof = tree.prove(leaf_index);
+            let proof_t = MerkleProofTarget {
+                siblings: builder.add_virtual_hashes(proof.siblings.len()),
+            };
+            for (&t, &h) in proof_t.siblings.iter().zip(&proof.siblings) {
+                pw.set_hash_target(t, h);
+            }
+            builder.poseidon2_verify_merkle_proof_to_cap(leaf_t.clone(), &bits, &cap_t, &proof_t);
+
+            let full_proof =
+                MerkleTree::<F, Poseidon2Hash>::new(leaves.clone(), 0).prove(leaf_index);
+            let full_proof_t = MerkleProofTarget {
+                siblings: builder.add_virtual_hashes(full_proof.siblings.len()),
+            };
+            for (&t, &h) in full_proof_t.siblings.iter().zip(&full_proof.siblings) {
+                pw.set_hash_target(t, h);
+            }
+            builder.poseidon2_verify_merkle_proof(leaf_t, &bits, root_t, &full_proof_t);
+        }
+
+        let data = builder.build::<C>();
+        let proof = data.prove(pw)?;
+        data.verify(proof)
+    }
+
+    #[test]
+    fn known_answers() -> Result<()> {
+        let kats = KnownAnswerTests::load_default()?;
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let mut pw = PartialWitness::new();
+        let mut expected = Vec::new();
+        let mut add_inputs = |builder: &mut CircuitBuilder<F, D>, values: &[F]| {
+            let targets = builder.add_virtual_targets(values.len());
+            pw.set_target_arr(&targets, values);
+            targets
+        };
+
+        for v in &kats.permutation {
+            let inputs = add_inputs(&mut builder, &v.input);
+            let outputs = builder.poseidon2_permute(inputs.try_into().unwrap());
+            builder.register_public_inputs(&outputs);
+            expected.extend(&v.output);
+        }
+        for v in &kats.hash_no_pad {
+            let inputs = add_inputs(&mut builder, &v.input);
+            let hash = builder.poseidon2_hash(inputs);
+            builder.register_public_inputs(&hash.elements);
+            expected.extend(&v.output);
+        }
+        for v in &kats.two_to_one {
+            let left = add_inputs(&mut builder, &v.left);
+            let right = add_inputs(&mut builder, &v.right);
+            let hash = builder.poseidon2_two_to_one(
+                HashOutTarget::from_vec(left),
+                HashOutTarget::from_vec(right),
+            );
+            builder.register_public_inputs(&hash.elements);
+            expected.extend(&v.output);
+        }
+        for v in &kats.merkle_root {
+            // Hash the leaves, then compress the layers down to the cap.
+            let mut layer = v
+                .leaves
+                .iter()
+                .map(|leaf| {
+                    let leaf = add_inputs(&mut builder, leaf);
+                    builder.poseidon2_hash_or_noop(leaf)
+                })
+                .collect::<Vec<_>>();
+            while layer.len() > v.cap.len() {
+                layer = layer
+                    .chunks(2)
+                    .map(|pair| builder.poseidon2_two_to_one(pair[0], pair[1]))
+                    .collect();
+            }
+            for (hash, digest) in layer.iter().zip(&v.cap) {
+                builder.register_public_inputs(&hash.elements);
+                expected.extend(digest);
+            }
+        }
+
+        let data = builder.build::<C>();
+        let proof = data.prove(pw)?;
+        assert_eq!(proof.public_inputs, expected);
+        data.verify(proof)
+    }
+}
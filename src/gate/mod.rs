@@ -1 +1,7 @@
-pub mod poseidon2;
\ No newline at end of file
+pub mod poseidon2;
+pub mod poseidon2_batch;
+pub mod poseidon2_diagnostics;
+pub mod poseidon2_external;
+pub mod poseidon2_position;
+pub mod poseidon2_rounds;
+pub mod poseidon2_sponge;
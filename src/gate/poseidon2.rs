@@ -7,17 +7,18 @@ use plonky2::gates::gate::Gate;
 use plonky2::gates::util::StridedConstraintConsumer;
 use plonky2::hash::hash_types::RichField;
 // use crate::poseidon2_hash::RichField;
-use crate::poseidon2_hash::poseidon2;
-use crate::poseidon2_hash::poseidon2::{Poseidon2, ROUND_F_BEGIN, ROUND_F_END, ROUND_P, WIDTH};
+use crate::gate::poseidon2_external::matmul_external_circuit;
+use crate::gate::poseidon2_rounds::Poseidon2RoundWires;
+use crate::poseidon2_hash::poseidon2::{Poseidon2, ROUND_F_END, ROUND_P, WIDTH};
 use plonky2::iop::ext_target::ExtensionTarget;
 use plonky2::iop::generator::{GeneratedValues, SimpleGenerator, WitnessGeneratorRef};
 use plonky2::iop::target::Target;
 use plonky2::iop::wire::Wire;
-use plonky2::iop::witness::{PartitionWitness, Witness, WitnessWrite};
+use plonky2::iop::witness::{PartitionWitness, Witness};
 use plonky2::plonk::circuit_builder::CircuitBuilder;
 use plonky2::plonk::circuit_data::CommonCircuitData;
 use plonky2::plonk::vars::{EvaluationTargets, EvaluationVars, EvaluationVarsBase};
-use plonky2::util::serialization::{Buffer, IoResult, Read, Write};
+use plonky2::util::serialization::{Buffer, IoError, IoResult, Read, Write};
 
 /// Evaluates a full Poseidon2 permutation with 12 state elements.
 ///
@@ -55,48 +56,41 @@ impl<F: RichField + Extendable<D>, const D: usize> Poseidon2Gate<F, D> {
 
     /// A wire which stores `swap * (input[i + 4] - input[i])`; used to compute
     /// the swapped inputs.
-    fn wire_delta(i: usize) -> usize {
+    pub fn wire_delta(i: usize) -> usize {
         assert!(i < 4);
         Self::START_DELTA + i
     }
 
-    const START_ROUND_F_BEGIN: usize = Self::START_DELTA + 4;
+    /// The S-box input wires of the rounds, after the delta wires.
+    const ROUNDS: Poseidon2RoundWires = Poseidon2RoundWires::new(Self::START_DELTA + 4);
 
     /// A wire which stores the input of the `i`-th S-box of the `round`-th
     /// round of the first set of full rounds.
-    fn wire_full_round_begin(round: usize, i: usize) -> usize {
-        debug_assert!(
-            round != 0,
-            "First round S-box inputs are not stored as wires"
-        );
-        debug_assert!(round < poseidon2::ROUND_F_BEGIN);
-        debug_assert!(i < WIDTH);
-        Self::START_ROUND_F_BEGIN + WIDTH * (round - 1) + i
+    pub fn wire_full_round_begin(round: usize, i: usize) -> usize {
+        Self::ROUNDS.full_round_begin(round, i)
     }
 
-    const START_PARTIAL: usize = Self::START_ROUND_F_BEGIN + WIDTH * (poseidon2::ROUND_F_BEGIN - 1);
-
     /// A wire which stores the input of the S-box of the `round`-th round of
     /// the partial rounds.
-    fn wire_partial_round(round: usize) -> usize {
-        debug_assert!(round < poseidon2::ROUND_P);
-        Self::START_PARTIAL + round
+    pub fn wire_partial_round(round: usize) -> usize {
+        Self::ROUNDS.partial_round(round)
     }
 
-    const START_ROUND_F_END: usize = Self::START_PARTIAL + poseidon2::ROUND_P;
-
     /// A wire which stores the input of the `i`-th S-box of the `round`-th
     /// round of the second set of full rounds.
-    fn wire_full_round_end(round: usize, i: usize) -> usize {
-        debug_assert!(round < poseidon2::ROUND_F_BEGIN);
-        debug_assert!(i < WIDTH);
-        Self::START_ROUND_F_END + WIDTH * round + i
+    pub fn wire_full_round_end(round: usize, i: usize) -> usize {
+        Self::ROUNDS.full_round_end(round, i)
     }
 
     /// End of wire indices, exclusive.
     fn end() -> usize {
-        Self::START_ROUND_F_END + WIDTH * poseidon2::ROUND_F_BEGIN
+        Self::ROUNDS.end()
     }
+
+    /// Version of the serialized gate, written together with the permutation
+    /// parameters. It must be bumped whenever the wire layout or the
+    /// constraints change, so that stale circuit data fails to load.
+    pub const SERIALIZATION_VERSION: u32 = 1;
 }
 
 impl<F: RichField + Extendable<D> + Poseidon2, const D: usize> Gate<F, D> for Poseidon2Gate<F, D> {
@@ -106,13 +100,27 @@ impl<F: RichField + Extendable<D> + Poseidon2, const D: usize> Gate<F, D> for Po
 
     fn serialize(
         &self,
-        _dst: &mut Vec<u8>,
+        dst: &mut Vec<u8>,
         _common_data: &CommonCircuitData<F, D>,
     ) -> IoResult<()> {
-        Ok(())
+        dst.write_u32(Self::SERIALIZATION_VERSION)?;
+        dst.write_usize(WIDTH)?;
+        dst.write_usize(ROUND_F_END)?;
+        dst.write_usize(ROUND_P)
     }
 
-    fn deserialize(_src: &mut Buffer, _common_data: &CommonCircuitData<F, D>) -> IoResult<Self> {
+    fn deserialize(src: &mut Buffer, _common_data: &CommonCircuitData<F, D>) -> IoResult<Self> {
+        let version = src.read_u32()?;
+        let width = src.read_usize()?;
+        let rounds_f = src.read_usize()?;
+        let rounds_p = src.read_usize()?;
+        if version != Self::SERIALIZATION_VERSION
+            || width != WIDTH
+            || rounds_f != ROUND_F_END
+            || rounds_p != ROUND_P
+        {
+            return Err(IoError);
+        }
         Ok(Poseidon2Gate::new())
     }
 
@@ -149,47 +157,7 @@ impl<F: RichField + Extendable<D> + Poseidon2, const D: usize> Gate<F, D> for Po
         // M_E * X
         <F as Poseidon2>::matmul_external_field(&mut state);
 
-        // External_i, i in {0 - R_F/2 -1}
-        for r in 0..poseidon2::ROUND_F_BEGIN {
-            <F as Poseidon2>::constant_layer_field(&mut state, r);
-            //12 * 3 = 36 constraints
-            if r != 0 {
-                for i in 0..WIDTH {
-                    let sbox_in = vars.local_wires[Self::wire_full_round_begin(r, i)];
-                    constraints.push(state[i] - sbox_in);
-                    state[i] = sbox_in;
-                }
-            }
-            <F as Poseidon2>::sbox_layer_field(&mut state);
-            <F as Poseidon2>::matmul_external_field(&mut state);
-        }
-
-        // Internal_i
-        for r in 0..poseidon2::ROUND_P {
-            state[0] += F::Extension::from_canonical_u64(<F as Poseidon2>::RC12_MID[r]);
-
-            //22 constraints
-            let sbox_in = vars.local_wires[Self::wire_partial_round(r)];
-            constraints.push(state[0] - sbox_in);
-            //state[0] = sbox_in;
-            state[0] = <F as Poseidon2>::sbox_monomial(sbox_in);
-            <F as Poseidon2>::matmul_internal_field(&mut state, &<F as Poseidon2>::MAT_DIAG12_M_1);
-        }
-
-        // External_i, i in {R_F/2 = R/F - 1}.
-        for r in poseidon2::ROUND_F_BEGIN..poseidon2::ROUND_F_END {
-            <F as Poseidon2>::constant_layer_field(&mut state, r);
-
-            //12 * 4 = 48 constraints
-            for i in 0..WIDTH {
-                let sbox_in = vars.local_wires[Self::wire_full_round_end(r - ROUND_F_BEGIN, i)];
-                constraints.push(state[i] - sbox_in);
-                state[i] = sbox_in;
-            }
-
-            <F as Poseidon2>::sbox_layer_field(&mut state);
-            <F as Poseidon2>::matmul_external_field(&mut state);
-        }
+        let state = Self::ROUNDS.eval::<F, D>(vars.local_wires, state, &mut constraints);
 
         //12 constraints
         for i in 0..WIDTH {
@@ -232,46 +200,7 @@ impl<F: RichField + Extendable<D> + Poseidon2, const D: usize> Gate<F, D> for Po
         // M_E * X
         <F as Poseidon2>::matmul_external(&mut state);
 
-        // External_i, i in {0 - R_F/2 -1}
-        for r in 0..ROUND_F_BEGIN {
-            <F as Poseidon2>::constant_layer(&mut state, r);
-            if r != 0 {
-                for i in 0..WIDTH {
-                    let sbox_in = vars.local_wires[Self::wire_full_round_begin(r, i)];
-                    yield_constr.one(state[i] - sbox_in);
-                    state[i] = sbox_in;
-                }
-            }
-            <F as Poseidon2>::sbox_layer(&mut state);
-            <F as Poseidon2>::matmul_external(&mut state);
-        }
-
-        // Internal_i
-        for r in 0..ROUND_P {
-            // t_0 = x_0 + c_0^i
-            state[0] += F::from_canonical_u64(<F as Poseidon2>::RC12_MID[r]);
-            let sbox_in = vars.local_wires[Self::wire_partial_round(r)];
-            yield_constr.one(state[0] - sbox_in);
-            // t_1 = t_0^7
-            state[0] = sbox_in;
-            state[0] = <F as Poseidon2>::sbox_monomial(state[0]);
-            // M_I * t_1
-            <F as Poseidon2>::matmul_internal(&mut state, &<F as Poseidon2>::MAT_DIAG12_M_1);
-        }
-
-        // External_i, i in {R_F/2 = R/F - 1}
-        for r in ROUND_F_BEGIN..ROUND_F_END {
-            <F as Poseidon2>::constant_layer(&mut state, r);
-
-            for i in 0..WIDTH {
-                let sbox_in = vars.local_wires[Self::wire_full_round_end(r - ROUND_F_BEGIN, i)];
-                yield_constr.one(state[i] - sbox_in);
-                state[i] = sbox_in;
-            }
-
-            <F as Poseidon2>::sbox_layer(&mut state);
-            <F as Poseidon2>::matmul_external(&mut state);
-        }
+        let state = Self::ROUNDS.eval_base(&vars.local_wires, state, |c| yield_constr.one(c));
 
         for i in 0..WIDTH {
             yield_constr.one(state[i] - vars.local_wires[Self::wire_output(i)]);
@@ -283,8 +212,6 @@ impl<F: RichField + Extendable<D> + Poseidon2, const D: usize> Gate<F, D> for Po
         builder: &mut CircuitBuilder<F, D>,
         vars: EvaluationTargets<D>,
     ) -> Vec<ExtensionTarget<D>> {
-        // The naive method is more efficient if we have enough routed wires for
-
         let mut constraints = Vec::with_capacity(self.num_constraints());
 
         // Assert that `swap` is binary.
@@ -314,48 +241,10 @@ impl<F: RichField + Extendable<D> + Poseidon2, const D: usize> Gate<F, D> for Po
         }
 
         // M_E * X
-        state = <F as Poseidon2>::matmul_external_circuit(builder, &mut state);
-
-        // External_i, i in {0 - R_F/2 -1}
-        for r in 0..poseidon2::ROUND_F_BEGIN {
-            <F as Poseidon2>::constant_layer_circuit(builder, &mut state, r);
-            if r != 0 {
-                for i in 0..WIDTH {
-                    let sbox_in = vars.local_wires[Self::wire_full_round_begin(r, i)];
-                    constraints.push(builder.sub_extension(state[i], sbox_in));
-                    state[i] = sbox_in;
-                }
-            }
-            <F as Poseidon2>::sbox_layer_circuit(builder, &mut state);
-            state = <F as Poseidon2>::matmul_external_circuit(builder, &mut state);
-        }
-
-        // Internal_i
-        for r in 0..poseidon2::ROUND_P {
-            let round_constant = F::Extension::from_canonical_u64(<F as Poseidon2>::RC12_MID[r]);
-            let round_constant = builder.constant_extension(round_constant);
-            state[0] = builder.add_extension(state[0], round_constant);
-
-            let sbox_in = vars.local_wires[Self::wire_partial_round(r)];
-            constraints.push(builder.sub_extension(state[0], sbox_in));
-            //state[0] = sbox_in;
-            state[0] = <F as Poseidon2>::sbox_monomial_circuit(builder, sbox_in);
-            <F as Poseidon2>::matmul_internal_circuit(builder, &mut state);
-        }
-
-        // External_i, i in {R_F/2 = R/F - 1}.
-        for r in poseidon2::ROUND_F_BEGIN..poseidon2::ROUND_F_END {
-            <F as Poseidon2>::constant_layer_circuit(builder, &mut state, r);
-
-            for i in 0..WIDTH {
-                let sbox_in = vars.local_wires[Self::wire_full_round_end(r - ROUND_F_BEGIN, i)];
-                constraints.push(builder.sub_extension(state[i], sbox_in));
-                state[i] = sbox_in;
-            }
+        state = matmul_external_circuit(builder, state);
 
-            <F as Poseidon2>::sbox_layer_circuit(builder, &mut state);
-            state = <F as Poseidon2>::matmul_external_circuit(builder, &mut state);
-        }
+        let state =
+            Self::ROUNDS.eval_circuit(builder, vars.local_wires, state, &mut constraints);
 
         for i in 0..WIDTH {
             constraints
@@ -386,7 +275,7 @@ impl<F: RichField + Extendable<D> + Poseidon2, const D: usize> Gate<F, D> for Po
     }
 
     fn num_constraints(&self) -> usize {
-        WIDTH * (poseidon2::ROUND_F_END - 1) + poseidon2::ROUND_P + WIDTH + 1 + 4
+        Poseidon2RoundWires::NUM_CONSTRAINTS + WIDTH + 1 + 4
     }
 }
 
@@ -429,16 +318,47 @@ impl<F: RichField + Extendable<D> + Poseidon2, const D: usize> SimpleGenerator<F
             column,
         };
 
-        let mut state = (0..WIDTH)
-            .map(|i| witness.get_wire(local_wire(Poseidon2Gate::<F, D>::wire_input(i))))
-            .collect::<Vec<_>>();
-
+        let inputs = core::array::from_fn(|i| {
+            witness.get_wire(local_wire(Poseidon2Gate::<F, D>::wire_input(i)))
+        });
         let swap_value = witness.get_wire(local_wire(Poseidon2Gate::<F, D>::WIRE_SWAP));
+
+        Self::generate_row(self.row, inputs, swap_value, out_buffer);
+    }
+}
+
+impl<F: RichField + Extendable<D> + Poseidon2, const D: usize> Poseidon2Generator<F, D> {
+    /// Number of wires of a row computed from its inputs and swap flag.
+    pub(crate) const NUM_GENERATED_WIRES: usize = 4 + Poseidon2RoundWires::NUM_WIRES + WIDTH;
+
+    /// Whether the wires of the `Poseidon2Gate` in `row` have already been
+    /// generated. This checks a round wire rather than an output, since the
+    /// outputs may be copied from targets set by other generators or by the
+    /// partial witness.
+    pub(crate) fn is_generated(witness: &PartitionWitness<F>, row: usize) -> bool {
+        let column = Poseidon2Gate::<F, D>::wire_full_round_begin(1, 0);
+        witness.try_get_wire(Wire { row, column }).is_some()
+    }
+
+    /// Computes the delta, round and output wires of a `Poseidon2Gate` in
+    /// `row` from its inputs and swap flag.
+    pub(crate) fn generate_row(
+        row: usize,
+        inputs: [F; WIDTH],
+        swap_value: F,
+        out_buffer: &mut GeneratedValues<F>,
+    ) {
+        let local_target = |column| Target::wire(row, column);
+        out_buffer.target_values.reserve(Self::NUM_GENERATED_WIRES);
+
+        let mut state = inputs;
         debug_assert!(swap_value == F::ZERO || swap_value == F::ONE);
 
         for i in 0..4 {
             let delta_i = swap_value * (state[i + 4] - state[i]);
-            out_buffer.set_wire(local_wire(Poseidon2Gate::<F, D>::wire_delta(i)), delta_i);
+            out_buffer
+                .target_values
+                .push((local_target(Poseidon2Gate::<F, D>::wire_delta(i)), delta_i));
         }
 
         if swap_value == F::ONE {
@@ -447,59 +367,14 @@ impl<F: RichField + Extendable<D> + Poseidon2, const D: usize> SimpleGenerator<F
             }
         }
 
-        let mut state: [F; WIDTH] = state.try_into().unwrap();
-
-        // M_E * X
-        <F as Poseidon2>::matmul_external_field(&mut state);
+        let state = Poseidon2Gate::<F, D>::ROUNDS.generate(row, state, out_buffer);
 
-        // External_i, i in {0 - R_F/2 -1}
-        for r in 0..poseidon2::ROUND_F_BEGIN {
-            <F as Poseidon2>::constant_layer_field(&mut state, r);
-            if r != 0 {
-                for i in 0..WIDTH {
-                    out_buffer.set_wire(
-                        local_wire(Poseidon2Gate::<F, D>::wire_full_round_begin(r, i)),
-                        state[i],
-                    );
-                }
-            }
-            <F as Poseidon2>::sbox_layer_field(&mut state);
-            <F as Poseidon2>::matmul_external_field(&mut state);
-        }
-
-        // Internal_i
-        for r in 0..poseidon2::ROUND_P {
-            state[0] += F::from_canonical_u64(<F as Poseidon2>::RC12_MID[r]);
-            out_buffer.set_wire(
-                local_wire(Poseidon2Gate::<F, D>::wire_partial_round(r)),
-                state[0],
-            );
-            state[0] = <F as Poseidon2>::sbox_monomial(state[0]);
-            <F as Poseidon2>::matmul_internal_field(&mut state, &<F as Poseidon2>::MAT_DIAG12_M_1);
-        }
-
-        // External_i, i in {R_F/2 = R/F - 1}.
-        for r in poseidon2::ROUND_F_BEGIN..poseidon2::ROUND_F_END {
-            <F as Poseidon2>::constant_layer_field(&mut state, r);
-
-            for i in 0..WIDTH {
-                out_buffer.set_wire(
-                    local_wire(Poseidon2Gate::<F, D>::wire_full_round_end(
-                        r - ROUND_F_BEGIN,
-                        i,
-                    )),
-                    state[i],
-                );
-            }
-
-            <F as Poseidon2>::sbox_layer_field(&mut state);
-            <F as Poseidon2>::matmul_external_field(&mut state);
-        }
-
-        for i in 0..WIDTH {
-            out_buffer.set_wire(local_wire(Poseidon2Gate::<F, D>::wire_output(i)), state[i]);
-        }
-        // Ok(())
+        out_buffer.target_values.extend(
+            state
+                .into_iter()
+                .enumerate()
+                .map(|(i, out)| (local_target(Poseidon2Gate::<F, D>::wire_output(i)), out)),
+        );
     }
 }
 
@@ -518,7 +393,7 @@ mod tests {
     use plonky2::iop::witness::{PartialWitness, Witness, WitnessWrite};
     use plonky2::plonk::circuit_builder::CircuitBuilder;
     use plonky2::plonk::circuit_data::CircuitConfig;
-    use plonky2::plonk::config::GenericConfig;
+    use plonky2::plonk::config::{AlgebraicHasher, GenericConfig};
     use crate::config::Poseidon2GoldilocksConfig;
 
     #[test]
@@ -590,6 +465,42 @@ mod tests {
         }
     }
 
+    #[test]
+    fn known_answers() {
+        use crate::poseidon2_hash::kat::KnownAnswerTests;
+
+        const D: usize = 2;
+        type C = Poseidon2GoldilocksConfig;
+        type F = <C as GenericConfig<D>>::F;
+        type Gate = Poseidon2Gate<F, D>;
+
+        // One gate per permutation vector, filled by the gate generator.
+        let kats = KnownAnswerTests::load_default().unwrap();
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let rows = kats
+            .permutation
+            .iter()
+            .map(|_| builder.add_gate(Gate::new(), vec![]))
+            .collect::<Vec<_>>();
+        let circuit = builder.build_prover::<C>();
+
+        let mut inputs = PartialWitness::new();
+        for (&row, v) in rows.iter().zip(&kats.permutation) {
+            inputs.set_wire(Wire { row, column: Gate::WIRE_SWAP }, F::ZERO);
+            for i in 0..WIDTH {
+                inputs.set_wire(Wire { row, column: Gate::wire_input(i) }, v.input[i]);
+            }
+        }
+        let witness = generate_partial_witness(inputs, &circuit.prover_only, &circuit.common);
+
+        for (&row, v) in rows.iter().zip(&kats.permutation) {
+            let outputs = (0..WIDTH)
+                .map(|i| witness.get_wire(Wire { row, column: Gate::wire_output(i) }))
+                .collect::<Vec<_>>();
+            assert_eq!(outputs, v.output, "{} vector", v.source);
+        }
+    }
+
     #[test]
     fn low_degree() {
         type F = GoldilocksField;
@@ -606,6 +517,34 @@ mod tests {
         test_eval_fns::<F, C, _, D>(gate)
     }
 
+    #[test]
+    fn output_known_from_witness() -> Result<()> {
+        use plonky2::hash::hashing::PlonkyPermutation;
+        use crate::poseidon2_hash::poseidon2::{Poseidon2Hash, Poseidon2Permutation};
+
+        const D: usize = 2;
+        type C = Poseidon2GoldilocksConfig;
+        type F = <C as GenericConfig<D>>::F;
+
+        // The outputs are copied from targets set in the partial witness, as
+        // when checking a Merkle root; the row must still be generated.
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let inputs = builder.add_virtual_targets(WIDTH);
+        let expected = builder.add_virtual_targets(WIDTH);
+        let outputs = builder.permute::<Poseidon2Hash>(Poseidon2Permutation::new(inputs.clone()));
+        for (&o, &e) in outputs.as_ref().iter().zip(&expected) {
+            builder.connect(o, e);
+        }
+        let data = builder.build::<C>();
+
+        let input_values = core::array::from_fn(F::from_canonical_usize);
+        let mut pw = PartialWitness::new();
+        pw.set_target_arr(&inputs, &input_values);
+        pw.set_target_arr(&expected, &F::poseidon2(input_values));
+        let proof = data.prove(pw)?;
+        data.verify(proof)
+    }
+
     #[test]
     fn test_proof() {
         use plonky2_field::types::Sample;
@@ -650,4 +589,271 @@ mod tests {
         let proof = data.prove(pw);
         assert!(proof.is_ok());
     }
+
+    /// Builds the recursive verifier of a proof of a single hash, under config
+    /// `C` with `H` hashing in the inner circuit. Returns its number of gates,
+    /// after proving and verifying it.
+    fn recursive_verifier_num_gates<C, H>() -> Result<usize>
+    where
+        C: GenericConfig<2, F = GoldilocksField>,
+        C::Hasher: AlgebraicHasher<GoldilocksField>,
+        H: AlgebraicHasher<GoldilocksField>,
+    {
+        use plonky2::iop::witness::WitnessWrite;
+
+        const D: usize = 2;
+        type F = GoldilocksField;
+        let config = CircuitConfig::standard_recursion_config();
+
+        // Inner circuit: a single hash.
+        let mut builder = CircuitBuilder::<F, D>::new(config.clone());
+        let inputs = builder.add_virtual_targets(WIDTH);
+        let hash = builder.hash_n_to_hash_no_pad::<H>(inputs.clone());
+        builder.register_public_inputs(&hash.elements);
+        let inner_data = builder.build::<C>();
+
+        let mut pw = PartialWitness::new();
+        for (i, &t) in inputs.iter().enumerate() {
+            pw.set_target(t, F::from_canonical_usize(i));
+        }
+        let inner_proof = inner_data.prove(pw)?;
+
+        // Outer circuit: recursively verify the inner proof.
+        let mut builder = CircuitBuilder::<F, D>::new(config);
+        let proof_t = builder.add_virtual_proof_with_pis(&inner_data.common);
+        let verifier_t = builder.constant_verifier_data(&inner_data.verifier_only);
+        builder.verify_proof::<C>(&proof_t, &verifier_t, &inner_data.common);
+        let num_gates = builder.num_gates();
+        let outer_data = builder.build::<C>();
+
+        let mut pw = PartialWitness::new();
+        pw.set_proof_with_pis_target(&proof_t, &inner_proof);
+        let proof = outer_data.prove(pw)?;
+        outer_data.verify(proof)?;
+        Ok(num_gates)
+    }
+
+    #[test]
+    fn recursive_verifier_gate_count() -> Result<()> {
+        use plonky2::hash::poseidon::PoseidonHash;
+        use plonky2::plonk::config::PoseidonGoldilocksConfig;
+
+        use crate::poseidon2_hash::poseidon2::Poseidon2Hash;
+
+        let poseidon2 = recursive_verifier_num_gates::<Poseidon2GoldilocksConfig, Poseidon2Hash>()?;
+        let poseidon = recursive_verifier_num_gates::<PoseidonGoldilocksConfig, PoseidonHash>()?;
+        println!("recursive verifier gates: Poseidon2 {poseidon2}, Poseidon {poseidon}");
+        assert!(
+            poseidon2 <= poseidon,
+            "Poseidon2 verifier has {poseidon2} gates, more than the {poseidon} of Poseidon"
+        );
+        Ok(())
+    }
+}
+
+/// Differential tests of the native permutation, the `_field` and `_circuit`
+/// helpers, and the `Poseidon2Gate` constraints and generator, on random
+/// states including non-canonical representations.
+#[cfg(test)]
+mod proptests {
+    use plonky2::gates::gate::Gate as _;
+    use plonky2::hash::hash_types::HashOut;
+    use plonky2::hash::hashing::PlonkyPermutation;
+    use plonky2::iop::generator::generate_partial_witness;
+    use plonky2::iop::target::Target;
+    use plonky2::iop::wire::Wire;
+    use plonky2::iop::witness::{PartialWitness, Witness, WitnessWrite};
+    use plonky2::plonk::circuit_builder::CircuitBuilder;
+    use plonky2::plonk::circuit_data::CircuitConfig;
+    use plonky2::plonk::prover::prove_with_partition_witness;
+    use plonky2::plonk::vars::{EvaluationVars, EvaluationVarsBaseBatch};
+    use plonky2::util::timing::TimingTree;
+    use plonky2_field::extension::{Extendable, FieldExtension};
+    use plonky2_field::goldilocks_field::GoldilocksField;
+    use plonky2_field::types::{Field, Field64, PrimeField64};
+    use proptest::prelude::*;
+    use proptest::test_runner::{Config, TestRunner};
+
+    use crate::config::Poseidon2GoldilocksConfig;
+    use crate::gate::poseidon2::Poseidon2Gate;
+    use crate::poseidon2_hash::poseidon2::{
+        Poseidon2, Poseidon2Hash, Poseidon2Permutation, ROUND_F_BEGIN, ROUND_F_END, ROUND_P, WIDTH,
+    };
+
+    const D: usize = 2;
+    type C = Poseidon2GoldilocksConfig;
+    type F = GoldilocksField;
+    type FE = <F as Extendable<D>>::Extension;
+    type P2Gate = Poseidon2Gate<F, D>;
+
+    /// Field elements as stored, i.e. possibly non-canonical: any `u64`,
+    /// values next to 0 and to the modulus, and values in `[ORDER, 2^64)`.
+    fn element() -> impl Strategy<Value = F> {
+        prop_oneof![
+            any::<u64>().prop_map(F::from_noncanonical_u64),
+            (0..16u64).prop_map(F::from_canonical_u64),
+            (0..16u64).prop_map(|d| F::from_canonical_u64(F::ORDER - 1 - d)),
+            (F::ORDER..=u64::MAX).prop_map(F::from_noncanonical_u64),
+        ]
+    }
+
+    fn state() -> impl Strategy<Value = [F; WIDTH]> {
+        prop::array::uniform12(element())
+    }
+
+    fn canonical(state: [F; WIDTH]) -> [F; WIDTH] {
+        state.map(|x| F::from_canonical_u64(x.to_canonical_u64()))
+    }
+
+    fn row_of(target: Target) -> usize {
+        match target {
+            Target::Wire(Wire { row, .. }) => row,
+            Target::VirtualTarget { .. } => unreachable!("Gate outputs are wires"),
+        }
+    }
+
+    /// The permutation written with the `_field` helpers, over the extension.
+    fn poseidon2_field(input: [F; WIDTH]) -> [FE; WIDTH] {
+        let mut state = input.map(<FE as FieldExtension<D>>::from_basefield);
+        F::matmul_external_field::<FE, D>(&mut state);
+        for round_ctr in 0..ROUND_F_BEGIN {
+            F::constant_layer_field::<FE, D>(&mut state, round_ctr);
+            F::sbox_layer_field::<FE, D>(&mut state);
+            F::matmul_external_field::<FE, D>(&mut state);
+        }
+        for r in 0..ROUND_P {
+            state[0] += FE::from_canonical_u64(F::RC12_MID[r]);
+            state[0] = F::sbox_monomial::<FE, D>(state[0]);
+            F::matmul_internal_field::<FE, D>(&mut state, &F::MAT_DIAG12_M_1);
+        }
+        for round_ctr in ROUND_F_BEGIN..ROUND_F_END {
+            F::constant_layer_field::<FE, D>(&mut state, round_ctr);
+            F::sbox_layer_field::<FE, D>(&mut state);
+            F::matmul_external_field::<FE, D>(&mut state);
+        }
+        state
+    }
+
+    #[test]
+    fn all_implementations_agree() {
+        let config = CircuitConfig::standard_recursion_config();
+
+        // The gate and its generator, and the `_circuit` helpers.
+        let mut builder = CircuitBuilder::<F, D>::new(config.clone());
+        let inputs = builder.add_virtual_targets(WIDTH);
+        let gate_outputs =
+            builder.permute::<Poseidon2Hash>(Poseidon2Permutation::new(inputs.clone()));
+        let gate_outputs = gate_outputs.as_ref().to_vec();
+        let ext_inputs = inputs
+            .iter()
+            .map(|&t| builder.convert_to_ext(t))
+            .collect::<Vec<_>>();
+        let arith_outputs =
+            F::poseidon2_circuit_arith(&mut builder, ext_inputs.try_into().unwrap());
+        let data = builder.build::<C>();
+        let row = row_of(gate_outputs[0]);
+
+        // `eval_unfiltered_circuit` on the wires of a gate.
+        let gate = P2Gate::new();
+        let mut builder = CircuitBuilder::<F, D>::new(config);
+        let wires_t = builder.add_virtual_extension_targets(gate.num_wires());
+        let vars_t = plonky2::plonk::vars::EvaluationTargets {
+            local_constants: &[],
+            local_wires: &wires_t,
+            public_inputs_hash: &builder.add_virtual_hash(),
+        };
+        let constraints_t = gate.eval_unfiltered_circuit(&mut builder, vars_t);
+        let eval_data = builder.build::<C>();
+
+        let mut runner = TestRunner::new(Config::with_cases(64));
+        runner
+            .run(&state(), |input| {
+                let native = F::poseidon2(input);
+                prop_assert_eq!(native, F::poseidon2(canonical(input)));
+                prop_assert_eq!(
+                    poseidon2_field(input),
+                    native.map(<FE as FieldExtension<D>>::from_basefield)
+                );
+
+                let mut pw = PartialWitness::new();
+                pw.set_target_arr(&inputs, &input);
+                let witness = generate_partial_witness(pw, &data.prover_only, &data.common);
+                prop_assert_eq!(witness.get_targets(&gate_outputs), native.to_vec());
+                let arith = arith_outputs.map(|t| witness.get_extension_target(t));
+                prop_assert_eq!(arith, native.map(<FE as FieldExtension<D>>::from_basefield));
+
+                let wires = (0..gate.num_wires())
+                    .map(|column| witness.get_wire(Wire { row, column }))
+                    .collect::<Vec<_>>();
+                let ext_wires = wires
+                    .iter()
+                    .map(|&w| <FE as FieldExtension<D>>::from_basefield(w))
+                    .collect::<Vec<_>>();
+                let public_inputs_hash = HashOut::ZERO;
+                let vars = EvaluationVars {
+                    local_constants: &[],
+                    local_wires: &ext_wires,
+                    public_inputs_hash: &public_inputs_hash,
+                };
+                prop_assert!(gate.eval_unfiltered(vars).iter().all(|c| c.is_zero()));
+                let vars_base = EvaluationVarsBaseBatch::new(1, &[], &wires, &public_inputs_hash);
+                prop_assert!(gate
+                    .eval_unfiltered_base_batch(vars_base)
+                    .iter()
+                    .all(|c| c.is_zero()));
+
+                let mut pw = PartialWitness::new();
+                pw.set_extension_targets(&wires_t, &ext_wires);
+                let witness =
+                    generate_partial_witness(pw, &eval_data.prover_only, &eval_data.common);
+                prop_assert!(constraints_t
+                    .iter()
+                    .all(|&c| witness.get_extension_target(c).is_zero()));
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn tampered_wire_fails() {
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let inputs = builder.add_virtual_targets(WIDTH);
+        let outputs = builder.permute::<Poseidon2Hash>(Poseidon2Permutation::new(inputs.clone()));
+        builder.register_public_inputs(outputs.as_ref());
+        let row = row_of(outputs.as_ref()[0]);
+        let data = builder.build::<C>();
+        let num_wires = P2Gate::new().num_wires();
+
+        let mut runner = TestRunner::new(Config::with_cases(24));
+        runner
+            .run(
+                &(state(), 0..num_wires, 1..F::ORDER),
+                |(input, column, delta)| {
+                    let mut pw = PartialWitness::new();
+                    pw.set_target_arr(&inputs, &input);
+                    let mut witness = generate_partial_witness(pw, &data.prover_only, &data.common);
+
+                    let target = Target::wire(row, column);
+                    let index =
+                        witness.representative_map[target.index(witness.num_wires, witness.degree)];
+                    let value = witness.values[index].unwrap();
+                    witness.values[index] = Some(value + F::from_canonical_u64(delta));
+
+                    let result = prove_with_partition_witness::<F, C, D>(
+                        &data.prover_only,
+                        &data.common,
+                        witness,
+                        &mut TimingTree::default(),
+                    )
+                    .and_then(|proof| data.verify(proof));
+                    prop_assert!(
+                        result.is_err(),
+                        "Tampering wire {} was not detected",
+                        column
+                    );
+                    Ok(())
+                },
+            )
+            .unwrap();
+    }
 }
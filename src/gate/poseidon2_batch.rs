@@ -0,0 +1,328 @@
+//! Witness generation for many [`Poseidon2Gate`] rows at once.
+//!
+//! Every `Poseidon2Gate` row comes with its own [`Poseidon2Generator`], which
+//! the witness generation loop dispatches separately. A
+//! [`Poseidon2BatchGenerator`] instead watches the inputs of a whole set of
+//! rows, and each time it runs computes all the rows which are ready, in
+//! parallel when the `parallel` feature is enabled. It replaces the per-row
+//! generators of its rows.
+//!
+//! The loop does not tell a generator which of its inputs were just set, so a
+//! run has to check every row of its batch. [`add_poseidon2_batch_generators`]
+//! therefore batches rows by their depth in the chains of permutations, where
+//! the outputs of a row are copied into the inputs of the next ones: the rows
+//! of a batch become ready together, and a batch typically runs once before
+//! and once after they do, rather than once per newly set input.
+
+use core::marker::PhantomData;
+use std::collections::BTreeMap;
+
+use plonky2::hash::hash_types::RichField;
+use plonky2::iop::generator::{
+    GeneratedValues, SimpleGenerator, WitnessGenerator, WitnessGeneratorRef,
+};
+use plonky2::iop::target::Target;
+use plonky2::iop::wire::Wire;
+use plonky2::iop::witness::{PartitionWitness, Witness};
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+use plonky2::plonk::circuit_data::{CircuitData, CommonCircuitData, ProverOnlyCircuitData};
+use plonky2::plonk::config::GenericConfig;
+use plonky2::util::serialization::{Buffer, IoResult, Read, Write};
+use plonky2_field::extension::Extendable;
+use plonky2_maybe_rayon::*;
+
+use crate::gate::poseidon2::{Poseidon2Gate, Poseidon2Generator};
+use crate::poseidon2_hash::poseidon2::{Poseidon2, WIDTH};
+
+/// Default number of rows handled by one batch generator.
+pub const DEFAULT_BATCH_SIZE: usize = 256;
+
+/// Generates the witness of a set of `Poseidon2Gate` rows.
+#[derive(Debug, Default)]
+pub struct Poseidon2BatchGenerator<F: RichField + Extendable<D> + Poseidon2, const D: usize> {
+    rows: Vec<usize>,
+    _phantom: PhantomData<F>,
+}
+
+impl<F: RichField + Extendable<D> + Poseidon2, const D: usize> Poseidon2BatchGenerator<F, D> {
+    /// Creates a generator for `rows`, which must all hold a `Poseidon2Gate`.
+    pub fn new(rows: Vec<usize>) -> Self {
+        Self {
+            rows,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// The input and swap flag wires of `row`, which its other wires are
+    /// computed from.
+    fn input_targets(row: usize) -> impl Iterator<Item = Target> {
+        (0..WIDTH)
+            .map(Poseidon2Gate::<F, D>::wire_input)
+            .chain(Some(Poseidon2Gate::<F, D>::WIRE_SWAP))
+            .map(move |column| Target::wire(row, column))
+    }
+
+    /// The inputs and swap flag of `row`, if they are all set.
+    fn ready_inputs(witness: &PartitionWitness<F>, row: usize) -> Option<([F; WIDTH], F)> {
+        let get = |column| witness.try_get_wire(Wire { row, column });
+        let mut inputs = [F::ZERO; WIDTH];
+        for (i, input) in inputs.iter_mut().enumerate() {
+            *input = get(Poseidon2Gate::<F, D>::wire_input(i))?;
+        }
+        let swap = get(Poseidon2Gate::<F, D>::WIRE_SWAP)?;
+        Some((inputs, swap))
+    }
+}
+
+impl<F: RichField + Extendable<D> + Poseidon2, const D: usize> WitnessGenerator<F, D>
+    for Poseidon2BatchGenerator<F, D>
+{
+    fn id(&self) -> String {
+        "Poseidon2BatchGenerator".to_string()
+    }
+
+    fn watch_list(&self) -> Vec<Target> {
+        self.rows
+            .iter()
+            .flat_map(|&row| Self::input_targets(row))
+            .collect()
+    }
+
+    fn run(&self, witness: &PartitionWitness<F>, out_buffer: &mut GeneratedValues<F>) -> bool {
+        let pending = self
+            .rows
+            .iter()
+            .copied()
+            .filter(|&row| !Poseidon2Generator::<F, D>::is_generated(witness, row))
+            .collect::<Vec<_>>();
+        let ready = pending
+            .iter()
+            .filter_map(|&row| Self::ready_inputs(witness, row).map(|inputs| (row, inputs)))
+            .collect::<Vec<_>>();
+
+        // A single row, e.g. in a chain of permutations, is generated in place.
+        if let [(row, (inputs, swap))] = ready[..] {
+            Poseidon2Generator::<F, D>::generate_row(row, inputs, swap, out_buffer);
+            return ready.len() == pending.len();
+        }
+
+        let generated = ready
+            .par_iter()
+            .map(|&(row, (inputs, swap))| {
+                let mut values =
+                    GeneratedValues::with_capacity(Poseidon2Generator::<F, D>::NUM_GENERATED_WIRES);
+                Poseidon2Generator::<F, D>::generate_row(row, inputs, swap, &mut values);
+                values.target_values
+            })
+            .collect::<Vec<_>>();
+        for values in generated {
+            out_buffer.target_values.extend(values);
+        }
+
+        ready.len() == pending.len()
+    }
+
+    fn serialize(&self, dst: &mut Vec<u8>, _common_data: &CommonCircuitData<F, D>) -> IoResult<()> {
+        dst.write_usize_vec(&self.rows)
+    }
+
+    fn deserialize(src: &mut Buffer, _common_data: &CommonCircuitData<F, D>) -> IoResult<Self> {
+        let rows = src.read_usize_vec()?;
+        Ok(Self::new(rows))
+    }
+}
+
+/// Builds the circuit of `builder`, with batch generators covering all of its
+/// `Poseidon2Gate` rows in batches of `batch_size` rows.
+pub fn build_with_poseidon2_batching<F, C, const D: usize>(
+    builder: CircuitBuilder<F, D>,
+    batch_size: usize,
+) -> CircuitData<F, C, D>
+where
+    F: RichField + Extendable<D> + Poseidon2,
+    C: GenericConfig<D, F = F>,
+{
+    let mut data = builder.build::<C>();
+    add_poseidon2_batch_generators(&mut data.prover_only, &data.common, batch_size);
+    data
+}
+
+/// Registers batch generators covering all the `Poseidon2Gate` rows of a
+/// built circuit, in place of their per-row generators. Rows at the same depth
+/// in the chains of permutations are batched together, `batch_size` rows at a
+/// time.
+pub fn add_poseidon2_batch_generators<F, C, const D: usize>(
+    prover_only: &mut ProverOnlyCircuitData<F, C, D>,
+    common: &CommonCircuitData<F, D>,
+    batch_size: usize,
+) where
+    F: RichField + Extendable<D> + Poseidon2,
+    C: GenericConfig<D, F = F>,
+{
+    assert!(batch_size > 0, "Batch size must be positive");
+    let rep_index = |target: Target| {
+        prover_only.representative_map[target.index(common.config.num_wires, common.degree())]
+    };
+
+    let row_generator_id = Poseidon2Generator::<F, D>::default().id();
+    let mut rows = Vec::new();
+    prover_only.generators.retain(|generator| {
+        if generator.0.id() != row_generator_id {
+            return true;
+        }
+        match generator.0.watch_list().first() {
+            Some(&Target::Wire(Wire { row, .. })) => rows.push(row),
+            _ => unreachable!("Poseidon2Generator watches the wires of its row"),
+        }
+        false
+    });
+
+    // The depth of a row is one more than the deepest row whose outputs are
+    // copied into its inputs. Visiting the rows in order settles the depths in
+    // one pass when rows only copy the outputs of earlier rows, as built by
+    // `CircuitBuilder::permute`. Copies added later may need more passes.
+    let mut producers = BTreeMap::new();
+    for (position, &row) in rows.iter().enumerate() {
+        for i in 0..WIDTH {
+            let output = Target::wire(row, Poseidon2Gate::<F, D>::wire_output(i));
+            producers.insert(rep_index(output), position);
+        }
+    }
+    let dependencies = rows
+        .iter()
+        .map(|&row| {
+            let mut dependencies = Poseidon2BatchGenerator::<F, D>::input_targets(row)
+                .filter_map(|input| producers.get(&rep_index(input)).copied())
+                .collect::<Vec<_>>();
+            dependencies.sort_unstable();
+            dependencies.dedup();
+            dependencies
+        })
+        .collect::<Vec<_>>();
+    let mut depths = vec![0; rows.len()];
+    // A cycle of copies, which no witness satisfies, would never settle,
+    // hence the bound on the number of passes.
+    for _ in 0..rows.len() {
+        let mut changed = false;
+        for (position, dependencies) in dependencies.iter().enumerate() {
+            for &dependency in dependencies {
+                if depths[dependency] + 1 > depths[position] {
+                    depths[position] = depths[dependency] + 1;
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    let mut rows_by_depth = BTreeMap::<usize, Vec<usize>>::new();
+    for (&row, depth) in rows.iter().zip(depths) {
+        rows_by_depth.entry(depth).or_default().push(row);
+    }
+    prover_only
+        .generators
+        .extend(rows_by_depth.values().flat_map(|rows| {
+            rows.chunks(batch_size).map(|batch| {
+                WitnessGeneratorRef::new(Poseidon2BatchGenerator::<F, D>::new(batch.to_vec()))
+            })
+        }));
+
+    // Index the generators by their watched targets, the same way
+    // `CircuitBuilder::build` does.
+    let mut indices_by_watches = BTreeMap::<usize, Vec<usize>>::new();
+    for (i, generator) in prover_only.generators.iter().enumerate() {
+        for watch in generator.0.watch_list() {
+            indices_by_watches
+                .entry(rep_index(watch))
+                .or_default()
+                .push(i);
+        }
+    }
+    for indices in indices_by_watches.values_mut() {
+        indices.dedup();
+    }
+    prover_only.generator_indices_by_watches = indices_by_watches;
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use plonky2::hash::hashing::PlonkyPermutation;
+    use plonky2::iop::witness::{PartialWitness, WitnessWrite};
+    use plonky2::plonk::circuit_builder::CircuitBuilder;
+    use plonky2::plonk::circuit_data::{CircuitConfig, CircuitData};
+    use plonky2::plonk::config::GenericConfig;
+    use plonky2_field::types::Field;
+
+    use crate::config::Poseidon2GoldilocksConfig;
+    use crate::gate::poseidon2_batch::build_with_poseidon2_batching;
+    use crate::poseidon2_hash::poseidon2::{Poseidon2, Poseidon2Hash, Poseidon2Permutation, WIDTH};
+    use crate::serialization::{Poseidon2GateSerializer, Poseidon2GeneratorSerializer};
+
+    #[test]
+    fn batch_matches_native() -> Result<()> {
+        const D: usize = 2;
+        type C = Poseidon2GoldilocksConfig;
+        type F = <C as GenericConfig<D>>::F;
+
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let mut pw = PartialWitness::new();
+        let mut expected = Vec::new();
+
+        // Independent permutations, and a chain whose rows only become ready
+        // one after the other.
+        for k in 0..8 {
+            let inputs = (0..WIDTH)
+                .map(|i| F::from_canonical_usize(k * WIDTH + i))
+                .collect::<Vec<_>>();
+            let inputs_t = builder.add_virtual_targets(WIDTH);
+            pw.set_target_arr(&inputs_t, &inputs);
+            let state = builder.permute::<Poseidon2Hash>(Poseidon2Permutation::new(inputs_t));
+            builder.register_public_inputs(state.as_ref());
+            expected.extend(F::poseidon2(inputs.try_into().unwrap()));
+        }
+        let inputs = [F::TWO; WIDTH];
+        let mut state = Poseidon2Permutation::new(builder.constants(&inputs));
+        let mut native = inputs;
+        for _ in 0..5 {
+            state = builder.permute::<Poseidon2Hash>(state);
+            native = F::poseidon2(native);
+        }
+        builder.register_public_inputs(state.as_ref());
+        expected.extend(native);
+
+        let data = build_with_poseidon2_batching::<F, C, D>(builder, 3);
+        // The batches replace the per-row generators, including those of the
+        // rows hashing the public inputs, which are added by `build`.
+        let batches = data
+            .prover_only
+            .generators
+            .iter()
+            .filter(|generator| generator.0.id() == "Poseidon2BatchGenerator")
+            .map(|generator| generator.0.watch_list().len() / (WIDTH + 1))
+            .collect::<Vec<_>>();
+        assert!(data
+            .prover_only
+            .generators
+            .iter()
+            .all(|generator| generator.0.id() != "Poseidon2Generator"));
+        // The 8 independent rows and the first row of the chain, at depth 0.
+        // The chain and the hash of the public inputs, which absorbs the
+        // outputs of the independent rows first, then share depths 1 to 4,
+        // and the rest of the 14 rows of the hash go one per depth.
+        assert_eq!(batches, [&[3, 3, 3][..], &[2; 4], &[1; 10]].concat());
+        // The batch generators survive serialization.
+        let gate_serializer = Poseidon2GateSerializer;
+        let generator_serializer = Poseidon2GeneratorSerializer::<C, D>::default();
+        let bytes = data
+            .to_bytes(&gate_serializer, &generator_serializer)
+            .map_err(|_| anyhow::anyhow!("failed to serialize circuit data"))?;
+        let data =
+            CircuitData::<F, C, D>::from_bytes(&bytes, &gate_serializer, &generator_serializer)
+                .map_err(|_| anyhow::anyhow!("failed to deserialize circuit data"))?;
+        let proof = data.prove(pw)?;
+        assert_eq!(proof.public_inputs, expected);
+        data.verify(proof)
+    }
+}
@@ -0,0 +1,406 @@
+//! Debugging helpers for rows of a [`Poseidon2Gate`] whose witness does not
+//! satisfy the gate constraints.
+//!
+//! The prover only reports that some constraint is not satisfied. Given the
+//! witness and the row of the gate, [`diagnose_poseidon2_row`] evaluates every
+//! constraint of the gate, maps the failing ones back to a (phase, round, lane)
+//! of the permutation, and dumps the wires of the row next to the values they
+//! should hold for its inputs.
+
+use core::fmt;
+
+use anyhow::{anyhow, Result};
+use plonky2::gates::gate::Gate;
+use plonky2::hash::hash_types::{HashOut, RichField};
+use plonky2::iop::generator::GeneratedValues;
+use plonky2::iop::target::Target;
+use plonky2::iop::wire::Wire;
+use plonky2::iop::witness::Witness;
+use plonky2::plonk::vars::EvaluationVars;
+use plonky2_field::extension::{Extendable, FieldExtension};
+use plonky2_field::types::Field;
+
+use crate::gate::poseidon2::{Poseidon2Gate, Poseidon2Generator};
+use crate::poseidon2_hash::poseidon2::{Poseidon2, ROUND_F_BEGIN, ROUND_P, WIDTH};
+
+/// The part of the permutation a constraint of `Poseidon2Gate` belongs to.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Poseidon2Phase {
+    /// The swap flag is binary.
+    Swap,
+    /// The delta wires of the swapped inputs.
+    Delta,
+    /// The S-box inputs of the first set of full rounds.
+    FullRoundBegin,
+    /// The S-box inputs of the partial rounds.
+    PartialRound,
+    /// The S-box inputs of the second set of full rounds.
+    FullRoundEnd,
+    /// The outputs of the permutation.
+    Output,
+}
+
+/// Where a constraint of `Poseidon2Gate` sits in the permutation. `round` is
+/// the index used by the corresponding wire function of the gate, e.g.
+/// `Poseidon2Gate::wire_full_round_end(round, lane)`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ConstraintLocation {
+    pub phase: Poseidon2Phase,
+    pub round: usize,
+    pub lane: usize,
+}
+
+impl ConstraintLocation {
+    /// The location of the `index`-th constraint, in the order they are
+    /// returned by `eval_unfiltered`.
+    pub fn of_constraint(index: usize) -> Self {
+        let location = |phase, round, lane| Self { phase, round, lane };
+        let full_round_begin = WIDTH * (ROUND_F_BEGIN - 1);
+        let full_round_end = WIDTH * ROUND_F_BEGIN;
+
+        let mut i = index;
+        if i == 0 {
+            return location(Poseidon2Phase::Swap, 0, 0);
+        }
+        i -= 1;
+        if i < 4 {
+            return location(Poseidon2Phase::Delta, 0, i);
+        }
+        i -= 4;
+        if i < full_round_begin {
+            return location(Poseidon2Phase::FullRoundBegin, 1 + i / WIDTH, i % WIDTH);
+        }
+        i -= full_round_begin;
+        if i < ROUND_P {
+            return location(Poseidon2Phase::PartialRound, i, 0);
+        }
+        i -= ROUND_P;
+        if i < full_round_end {
+            return location(Poseidon2Phase::FullRoundEnd, i / WIDTH, i % WIDTH);
+        }
+        i -= full_round_end;
+        assert!(i < WIDTH, "Constraint index {} out of range", index);
+        location(Poseidon2Phase::Output, 0, i)
+    }
+
+    /// The wire checked by the constraint at this location.
+    pub fn wire<F: RichField + Extendable<D>, const D: usize>(&self) -> usize {
+        type G<F, const D: usize> = Poseidon2Gate<F, D>;
+        match self.phase {
+            Poseidon2Phase::Swap => G::<F, D>::WIRE_SWAP,
+            Poseidon2Phase::Delta => G::<F, D>::wire_delta(self.lane),
+            Poseidon2Phase::FullRoundBegin => {
+                G::<F, D>::wire_full_round_begin(self.round, self.lane)
+            }
+            Poseidon2Phase::PartialRound => G::<F, D>::wire_partial_round(self.round),
+            Poseidon2Phase::FullRoundEnd => G::<F, D>::wire_full_round_end(self.round, self.lane),
+            Poseidon2Phase::Output => G::<F, D>::wire_output(self.lane),
+        }
+    }
+}
+
+impl fmt::Display for ConstraintLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} (round {}, lane {})",
+            self.phase, self.round, self.lane
+        )
+    }
+}
+
+/// A constraint of the row which does not evaluate to zero.
+#[derive(Clone, Debug)]
+pub struct ConstraintFailure<F: Field> {
+    pub index: usize,
+    pub location: ConstraintLocation,
+    pub residual: F,
+}
+
+/// A wire of the row, with the value computed from the inputs of the row and
+/// the value found in the witness, if any.
+#[derive(Clone, Debug)]
+pub struct WireState<F: Field> {
+    pub location: ConstraintLocation,
+    pub column: usize,
+    pub expected: F,
+    pub actual: Option<F>,
+}
+
+impl<F: Field> WireState<F> {
+    pub fn matches(&self) -> bool {
+        self.actual == Some(self.expected)
+    }
+}
+
+/// The result of [`diagnose_poseidon2_row`].
+#[derive(Clone, Debug)]
+pub struct Poseidon2RowReport<F: Field> {
+    pub row: usize,
+    pub inputs: [F; WIDTH],
+    pub swap: F,
+    /// The constraints which are not satisfied, in evaluation order.
+    pub failures: Vec<ConstraintFailure<F>>,
+    /// Every wire constrained by the gate, except the swap flag. Empty when
+    /// the swap flag is not binary, since the expected values are then
+    /// undefined.
+    pub wires: Vec<WireState<F>>,
+}
+
+impl<F: Field> Poseidon2RowReport<F> {
+    /// Whether every constraint of the row is satisfied.
+    pub fn is_satisfied(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// The first failing constraint, which usually points at the mis-wiring;
+    /// later ones tend to be consequences of it.
+    pub fn first_failure(&self) -> Option<&ConstraintFailure<F>> {
+        self.failures.first()
+    }
+
+    /// The wires whose value differs from the one computed from the inputs.
+    pub fn mismatched_wires(&self) -> impl Iterator<Item = &WireState<F>> {
+        self.wires.iter().filter(|w| !w.matches())
+    }
+}
+
+impl<F: Field> fmt::Display for Poseidon2RowReport<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Poseidon2Gate at row {}", self.row)?;
+        writeln!(f, "  inputs: {:?}, swap: {}", self.inputs, self.swap)?;
+        if self.is_satisfied() {
+            return writeln!(f, "  all constraints are satisfied");
+        }
+        writeln!(f, "  {} failing constraints:", self.failures.len())?;
+        for failure in &self.failures {
+            writeln!(
+                f,
+                "    #{} {}: residual {}",
+                failure.index, failure.location, failure.residual
+            )?;
+        }
+        if self.wires.is_empty() {
+            return writeln!(f, "  the swap flag is not binary, no expected state");
+        }
+        writeln!(f, "  wires differing from the expected state:")?;
+        for wire in self.mismatched_wires() {
+            let actual = wire
+                .actual
+                .map_or_else(|| "unset".to_string(), |v| v.to_string());
+            writeln!(
+                f,
+                "    {} (column {}): expected {}, actual {}",
+                wire.location, wire.column, wire.expected, actual
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Evaluates the constraints of the `Poseidon2Gate` at `row` of `witness`, and
+/// compares its wires against the values computed from its inputs and swap
+/// flag, if it is binary. Unset wires are evaluated as zero.
+pub fn diagnose_poseidon2_row<F, W, const D: usize>(
+    witness: &W,
+    row: usize,
+) -> Result<Poseidon2RowReport<F>>
+where
+    F: RichField + Extendable<D> + Poseidon2,
+    W: Witness<F>,
+{
+    type G<F, const D: usize> = Poseidon2Gate<F, D>;
+    let gate = G::<F, D>::new();
+    let get = |column| witness.try_get_wire(Wire { row, column });
+
+    let required =
+        |column| get(column).ok_or_else(|| anyhow!("Wire {} of row {} is not set", column, row));
+    let mut inputs = [F::ZERO; WIDTH];
+    for (i, input) in inputs.iter_mut().enumerate() {
+        *input = required(G::<F, D>::wire_input(i))?;
+    }
+    let swap = required(G::<F, D>::WIRE_SWAP)?;
+
+    // Evaluate every constraint on the witness.
+    let local_wires = (0..gate.num_wires())
+        .map(|column| get(column).unwrap_or(F::ZERO).into())
+        .collect::<Vec<F::Extension>>();
+    let vars = EvaluationVars {
+        local_constants: &[],
+        local_wires: &local_wires,
+        public_inputs_hash: &HashOut::ZERO,
+    };
+    let failures = gate
+        .eval_unfiltered(vars)
+        .into_iter()
+        .enumerate()
+        .filter(|(_, c)| *c != F::Extension::ZERO)
+        .map(|(index, c)| ConstraintFailure {
+            index,
+            location: ConstraintLocation::of_constraint(index),
+            residual: c.to_basefield_array()[0],
+        })
+        .collect();
+
+    if swap != F::ZERO && swap != F::ONE {
+        return Ok(Poseidon2RowReport {
+            row,
+            inputs,
+            swap,
+            failures,
+            wires: Vec::new(),
+        });
+    }
+
+    // Recompute the wires from the inputs.
+    let mut expected = GeneratedValues::empty();
+    Poseidon2Generator::<F, D>::generate_row(row, inputs, swap, &mut expected);
+    let expected_value = |column| {
+        expected
+            .target_values
+            .iter()
+            .find(|(t, _)| *t == Target::wire(row, column))
+            .map(|&(_, v)| v)
+            .unwrap()
+    };
+    let wires = (1..gate.num_constraints())
+        .map(|index| {
+            let location = ConstraintLocation::of_constraint(index);
+            let column = location.wire::<F, D>();
+            WireState {
+                location,
+                column,
+                expected: expected_value(column),
+                actual: get(column),
+            }
+        })
+        .collect();
+
+    Ok(Poseidon2RowReport {
+        row,
+        inputs,
+        swap,
+        failures,
+        wires,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use plonky2::gates::gate::Gate;
+    use plonky2::iop::generator::generate_partial_witness;
+    use plonky2::iop::wire::Wire;
+    use plonky2::iop::witness::{PartialWitness, Witness, WitnessWrite};
+    use plonky2::plonk::circuit_builder::CircuitBuilder;
+    use plonky2::plonk::circuit_data::CircuitConfig;
+    use plonky2::plonk::config::GenericConfig;
+    use plonky2_field::types::Field;
+
+    use crate::config::Poseidon2GoldilocksConfig;
+    use crate::gate::poseidon2::Poseidon2Gate;
+    use crate::gate::poseidon2_diagnostics::{
+        diagnose_poseidon2_row, ConstraintLocation, Poseidon2Phase,
+    };
+    use crate::poseidon2_hash::poseidon2::WIDTH;
+
+    const D: usize = 2;
+    type C = Poseidon2GoldilocksConfig;
+    type F = <C as GenericConfig<D>>::F;
+    type G = Poseidon2Gate<F, D>;
+
+    #[test]
+    fn constraint_locations_cover_all_wires() {
+        let gate = G::new();
+        let mut columns = (1..Gate::<F, D>::num_constraints(&gate))
+            .map(|i| ConstraintLocation::of_constraint(i).wire::<F, D>())
+            .collect::<Vec<_>>();
+        columns.sort();
+        let expected = (WIDTH..Gate::<F, D>::num_wires(&gate))
+            .filter(|&c| c != G::WIRE_SWAP)
+            .collect::<Vec<_>>();
+        assert_eq!(columns, expected);
+        assert_eq!(
+            ConstraintLocation::of_constraint(0).phase,
+            Poseidon2Phase::Swap
+        );
+    }
+
+    #[test]
+    fn reports_tampered_wire() -> Result<()> {
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let row = builder.add_gate(G::new(), vec![]);
+        let circuit = builder.build_prover::<C>();
+
+        let mut inputs = PartialWitness::new();
+        inputs.set_wire(
+            Wire {
+                row,
+                column: G::WIRE_SWAP,
+            },
+            F::ONE,
+        );
+        for i in 0..WIDTH {
+            inputs.set_wire(
+                Wire {
+                    row,
+                    column: G::wire_input(i),
+                },
+                F::from_canonical_usize(i),
+            );
+        }
+        let witness = generate_partial_witness(inputs, &circuit.prover_only, &circuit.common);
+        assert!(diagnose_poseidon2_row::<F, _, D>(&witness, row)?.is_satisfied());
+
+        // Copy the row, corrupting the S-box input of the 5th partial round.
+        let tampered_column = G::wire_partial_round(5);
+        let mut tampered = PartialWitness::new();
+        for column in 0..G::new().num_wires() {
+            let wire = Wire { row, column };
+            let mut value = witness.get_wire(wire);
+            if column == tampered_column {
+                value += F::ONE;
+            }
+            tampered.set_wire(wire, value);
+        }
+
+        let report = diagnose_poseidon2_row::<F, _, D>(&tampered, row)?;
+        println!("{}", report);
+        let first = report.first_failure().unwrap();
+        assert_eq!(
+            first.location,
+            ConstraintLocation {
+                phase: Poseidon2Phase::PartialRound,
+                round: 5,
+                lane: 0,
+            }
+        );
+        let mismatched = report.mismatched_wires().collect::<Vec<_>>();
+        assert_eq!(mismatched.len(), 1);
+        assert_eq!(mismatched[0].column, tampered_column);
+        Ok(())
+    }
+
+    #[test]
+    fn reports_non_binary_swap() -> Result<()> {
+        let row = 0;
+        let mut witness = PartialWitness::<F>::new();
+        for column in 0..G::new().num_wires() {
+            let value = if column == G::WIRE_SWAP {
+                F::TWO
+            } else {
+                F::ZERO
+            };
+            witness.set_wire(Wire { row, column }, value);
+        }
+
+        let report = diagnose_poseidon2_row::<F, _, D>(&witness, row)?;
+        println!("{}", report);
+        assert_eq!(
+            report.first_failure().unwrap().location.phase,
+            Poseidon2Phase::Swap
+        );
+        assert!(report.wires.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,387 @@
+//! A gate applying the external linear layer M_E of Poseidon2 to 12 extension
+//! field elements, the Poseidon2 counterpart of plonky2's `PoseidonMdsGate`.
+//!
+//! The constraints of the Poseidon2 gates are evaluated in recursive circuits
+//! over the extension field, where an M_E layer takes about 44 arithmetic
+//! operations, and comparing its result to the S-box inputs of the next round
+//! 12 more. With enough routed wires, [`matmul_external_sub_circuit`] does both
+//! in a single row of this gate instead.
+
+use core::marker::PhantomData;
+use core::ops::Range;
+
+use plonky2::gates::gate::Gate;
+use plonky2::gates::util::StridedConstraintConsumer;
+use plonky2::hash::hash_types::RichField;
+use plonky2::iop::ext_target::{ExtensionAlgebraTarget, ExtensionTarget};
+use plonky2::iop::generator::{GeneratedValues, SimpleGenerator, WitnessGeneratorRef};
+use plonky2::iop::target::Target;
+use plonky2::iop::witness::{PartitionWitness, Witness, WitnessWrite};
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+use plonky2::plonk::circuit_data::CommonCircuitData;
+use plonky2::plonk::vars::{EvaluationTargets, EvaluationVars, EvaluationVarsBase};
+use plonky2::util::serialization::{Buffer, IoError, IoResult, Read, Write};
+use plonky2_field::extension::{Extendable, FieldExtension};
+
+use crate::poseidon2_hash::poseidon2::{Poseidon2, WIDTH};
+
+/// Applies M_E to `state` with additions only, as in `Poseidon2::matmul_external`.
+fn matmul_external_generic<T: Copy>(state: &mut [T; WIDTH], mut add: impl FnMut(T, T) -> T) {
+    for part in state.chunks_exact_mut(4) {
+        let t_0 = add(part[0], part[1]);
+        let t_1 = add(part[2], part[3]);
+        let double_1 = add(part[1], part[1]);
+        let t_2 = add(double_1, t_1);
+        let double_3 = add(part[3], part[3]);
+        let t_3 = add(double_3, t_0);
+        let double_t_1 = add(t_1, t_1);
+        let quadruple_t_1 = add(double_t_1, double_t_1);
+        let t_4 = add(quadruple_t_1, t_3);
+        let double_t_0 = add(t_0, t_0);
+        let quadruple_t_0 = add(double_t_0, double_t_0);
+        let t_5 = add(quadruple_t_0, t_2);
+        let t_6 = add(t_3, t_5);
+        let t_7 = add(t_2, t_4);
+        part.copy_from_slice(&[t_6, t_5, t_7, t_4]);
+    }
+
+    let mut stored = [state[0], state[1], state[2], state[3]];
+    for (i, &x) in state.iter().enumerate().skip(4) {
+        stored[i % 4] = add(stored[i % 4], x);
+    }
+    for (i, x) in state.iter_mut().enumerate() {
+        *x = add(*x, stored[i % 4]);
+    }
+}
+
+/// Computes `M_E * state - subtrahend` in a circuit, with a
+/// [`Poseidon2ExternalMatmulGate`] if the config has enough routed wires, or
+/// arithmetic operations otherwise.
+pub fn matmul_external_sub_circuit<F: RichField + Extendable<D> + Poseidon2, const D: usize>(
+    builder: &mut CircuitBuilder<F, D>,
+    mut state: [ExtensionTarget<D>; WIDTH],
+    subtrahend: [ExtensionTarget<D>; WIDTH],
+) -> [ExtensionTarget<D>; WIDTH] {
+    let gate = Poseidon2ExternalMatmulGate::<F, D>::new();
+    if builder.config.num_routed_wires < gate.num_wires() {
+        let state = <F as Poseidon2>::matmul_external_circuit(builder, &mut state);
+        return core::array::from_fn(|i| builder.sub_extension(state[i], subtrahend[i]));
+    }
+
+    let row = builder.add_gate(gate, vec![]);
+    for i in 0..WIDTH {
+        let input_wires = Poseidon2ExternalMatmulGate::<F, D>::wires_input(i);
+        builder.connect_extension(state[i], ExtensionTarget::from_range(row, input_wires));
+        let subtrahend_wires = Poseidon2ExternalMatmulGate::<F, D>::wires_subtrahend(i);
+        builder.connect_extension(
+            subtrahend[i],
+            ExtensionTarget::from_range(row, subtrahend_wires),
+        );
+    }
+    core::array::from_fn(|i| {
+        ExtensionTarget::from_range(row, Poseidon2ExternalMatmulGate::<F, D>::wires_output(i))
+    })
+}
+
+/// Computes `M_E * state` in a circuit, see [`matmul_external_sub_circuit`].
+pub fn matmul_external_circuit<F: RichField + Extendable<D> + Poseidon2, const D: usize>(
+    builder: &mut CircuitBuilder<F, D>,
+    state: [ExtensionTarget<D>; WIDTH],
+) -> [ExtensionTarget<D>; WIDTH] {
+    let zero = builder.zero_extension();
+    matmul_external_sub_circuit(builder, state, [zero; WIDTH])
+}
+
+/// Constrains its outputs to be M_E applied to its inputs, minus its
+/// subtrahends, all of them extension field elements.
+#[derive(Debug, Default)]
+pub struct Poseidon2ExternalMatmulGate<F: RichField + Extendable<D>, const D: usize>(
+    PhantomData<F>,
+);
+
+impl<F: RichField + Extendable<D>, const D: usize> Poseidon2ExternalMatmulGate<F, D> {
+    pub const fn new() -> Self {
+        Self(PhantomData)
+    }
+
+    /// The wires of the `i`th input.
+    pub const fn wires_input(i: usize) -> Range<usize> {
+        assert!(i < WIDTH);
+        i * D..(i + 1) * D
+    }
+
+    /// The wires of the `i`th subtrahend.
+    pub const fn wires_subtrahend(i: usize) -> Range<usize> {
+        assert!(i < WIDTH);
+        (WIDTH + i) * D..(WIDTH + i + 1) * D
+    }
+
+    /// The wires of the `i`th output.
+    pub const fn wires_output(i: usize) -> Range<usize> {
+        assert!(i < WIDTH);
+        (2 * WIDTH + i) * D..(2 * WIDTH + i + 1) * D
+    }
+
+    /// Version of the serialized gate, see `Poseidon2Gate::SERIALIZATION_VERSION`.
+    pub const SERIALIZATION_VERSION: u32 = 1;
+}
+
+impl<F: RichField + Extendable<D> + Poseidon2, const D: usize> Gate<F, D>
+    for Poseidon2ExternalMatmulGate<F, D>
+{
+    fn id(&self) -> String {
+        format!("{:?}<WIDTH={}>", self, WIDTH)
+    }
+
+    fn serialize(&self, dst: &mut Vec<u8>, _common_data: &CommonCircuitData<F, D>) -> IoResult<()> {
+        dst.write_u32(Self::SERIALIZATION_VERSION)?;
+        dst.write_usize(WIDTH)
+    }
+
+    fn deserialize(src: &mut Buffer, _common_data: &CommonCircuitData<F, D>) -> IoResult<Self> {
+        let version = src.read_u32()?;
+        let width = src.read_usize()?;
+        if version != Self::SERIALIZATION_VERSION || width != WIDTH {
+            return Err(IoError);
+        }
+        Ok(Self::new())
+    }
+
+    fn eval_unfiltered(&self, vars: EvaluationVars<F, D>) -> Vec<F::Extension> {
+        let mut state = core::array::from_fn(|i| vars.get_local_ext_algebra(Self::wires_input(i)));
+        matmul_external_generic(&mut state, |a, b| a + b);
+
+        state
+            .into_iter()
+            .enumerate()
+            .flat_map(|(i, computed)| {
+                let output = vars.get_local_ext_algebra(Self::wires_output(i));
+                let subtrahend = vars.get_local_ext_algebra(Self::wires_subtrahend(i));
+                (output + subtrahend - computed).to_basefield_array()
+            })
+            .collect()
+    }
+
+    fn eval_unfiltered_base_one(
+        &self,
+        vars: EvaluationVarsBase<F>,
+        mut yield_constr: StridedConstraintConsumer<F>,
+    ) {
+        let mut state: [F::Extension; WIDTH] =
+            core::array::from_fn(|i| vars.get_local_ext(Self::wires_input(i)));
+        matmul_external_generic(&mut state, |a, b| a + b);
+
+        yield_constr.many(state.into_iter().enumerate().flat_map(|(i, computed)| {
+            let output = vars.get_local_ext(Self::wires_output(i));
+            let subtrahend = vars.get_local_ext(Self::wires_subtrahend(i));
+            (output + subtrahend - computed).to_basefield_array()
+        }));
+    }
+
+    fn eval_unfiltered_circuit(
+        &self,
+        builder: &mut CircuitBuilder<F, D>,
+        vars: EvaluationTargets<D>,
+    ) -> Vec<ExtensionTarget<D>> {
+        let mut state: [ExtensionAlgebraTarget<D>; WIDTH] =
+            core::array::from_fn(|i| vars.get_local_ext_algebra(Self::wires_input(i)));
+        matmul_external_generic(&mut state, |a, b| builder.add_ext_algebra(a, b));
+
+        state
+            .into_iter()
+            .enumerate()
+            .flat_map(|(i, computed)| {
+                let output = vars.get_local_ext_algebra(Self::wires_output(i));
+                let subtrahend = vars.get_local_ext_algebra(Self::wires_subtrahend(i));
+                let sum = builder.add_ext_algebra(output, subtrahend);
+                builder.sub_ext_algebra(sum, computed).to_ext_target_array()
+            })
+            .collect()
+    }
+
+    fn generators(&self, row: usize, _local_constants: &[F]) -> Vec<WitnessGeneratorRef<F, D>> {
+        let gen = Poseidon2ExternalMatmulGenerator::<F, D> {
+            row,
+            _phantom: PhantomData,
+        };
+        vec![WitnessGeneratorRef::new(gen.adapter())]
+    }
+
+    fn num_wires(&self) -> usize {
+        3 * D * WIDTH
+    }
+
+    fn num_constants(&self) -> usize {
+        0
+    }
+
+    fn degree(&self) -> usize {
+        1
+    }
+
+    fn num_constraints(&self) -> usize {
+        D * WIDTH
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Poseidon2ExternalMatmulGenerator<F: RichField + Extendable<D>, const D: usize> {
+    row: usize,
+    _phantom: PhantomData<F>,
+}
+
+impl<F: RichField + Extendable<D> + Poseidon2, const D: usize> SimpleGenerator<F, D>
+    for Poseidon2ExternalMatmulGenerator<F, D>
+{
+    fn id(&self) -> String {
+        "Poseidon2ExternalMatmulGenerator".to_string()
+    }
+
+    fn dependencies(&self) -> Vec<Target> {
+        // The inputs, then the subtrahends.
+        let first = Poseidon2ExternalMatmulGate::<F, D>::wires_input(0);
+        let last = Poseidon2ExternalMatmulGate::<F, D>::wires_subtrahend(WIDTH - 1);
+        Target::wires_from_range(self.row, first.start..last.end)
+    }
+
+    fn run_once(&self, witness: &PartitionWitness<F>, out_buffer: &mut GeneratedValues<F>) {
+        let get_local_ext =
+            |range| witness.get_extension_target(ExtensionTarget::from_range(self.row, range));
+        let mut state: [F::Extension; WIDTH] = core::array::from_fn(|i| {
+            get_local_ext(Poseidon2ExternalMatmulGate::<F, D>::wires_input(i))
+        });
+        <F as Poseidon2>::matmul_external_field(&mut state);
+
+        for (i, output) in state.into_iter().enumerate() {
+            let subtrahend =
+                get_local_ext(Poseidon2ExternalMatmulGate::<F, D>::wires_subtrahend(i));
+            out_buffer.set_extension_target(
+                ExtensionTarget::from_range(
+                    self.row,
+                    Poseidon2ExternalMatmulGate::<F, D>::wires_output(i),
+                ),
+                output - subtrahend,
+            );
+        }
+    }
+
+    fn serialize(&self, dst: &mut Vec<u8>, _common_data: &CommonCircuitData<F, D>) -> IoResult<()> {
+        dst.write_usize(self.row)
+    }
+
+    fn deserialize(src: &mut Buffer, _common_data: &CommonCircuitData<F, D>) -> IoResult<Self> {
+        let row = src.read_usize()?;
+        Ok(Self {
+            row,
+            _phantom: PhantomData,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use plonky2::gates::gate::Gate;
+    use plonky2::gates::gate_testing::{test_eval_fns, test_low_degree};
+    use plonky2::hash::hash_types::HashOut;
+    use plonky2::iop::witness::{PartialWitness, WitnessWrite};
+    use plonky2::plonk::circuit_builder::CircuitBuilder;
+    use plonky2::plonk::circuit_data::CircuitConfig;
+    use plonky2::plonk::config::{GenericConfig, PoseidonGoldilocksConfig};
+    use plonky2::plonk::vars::EvaluationVars;
+    use plonky2_field::types::{Field, Sample};
+
+    use crate::gate::poseidon2_external::{matmul_external_circuit, Poseidon2ExternalMatmulGate};
+    use crate::poseidon2_hash::poseidon2::{Poseidon2, WIDTH};
+
+    const D: usize = 2;
+    type C = PoseidonGoldilocksConfig;
+    type F = <C as GenericConfig<D>>::F;
+    type FE = <C as GenericConfig<D>>::FE;
+
+    #[test]
+    fn low_degree() {
+        test_low_degree::<F, _, D>(Poseidon2ExternalMatmulGate::<F, D>::new())
+    }
+
+    #[test]
+    fn eval_fns() -> Result<()> {
+        test_eval_fns::<F, C, _, D>(Poseidon2ExternalMatmulGate::<F, D>::new())
+    }
+
+    #[test]
+    fn matches_native() -> Result<()> {
+        let inputs = FE::rand_array::<WIDTH>();
+        let mut expected = inputs;
+        <F as Poseidon2>::matmul_external_field::<FE, D>(&mut expected);
+
+        for num_routed_wires in [80, 40] {
+            let config = CircuitConfig {
+                num_routed_wires,
+                ..CircuitConfig::standard_recursion_config()
+            };
+            let mut builder = CircuitBuilder::<F, D>::new(config);
+            let inputs_t = builder.add_virtual_extension_targets(WIDTH);
+            let outputs_t =
+                matmul_external_circuit(&mut builder, inputs_t.clone().try_into().unwrap());
+            for (&output_t, &output) in outputs_t.iter().zip(&expected) {
+                let output = builder.constant_extension(output);
+                builder.connect_extension(output_t, output);
+            }
+
+            let mut pw = PartialWitness::new();
+            for (&t, &x) in inputs_t.iter().zip(&inputs) {
+                pw.set_extension_target(t, x);
+            }
+            let data = builder.build::<C>();
+            data.verify(data.prove(pw)?)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the wires of a row satisfying the gate, for random inputs and
+    /// subtrahends.
+    fn valid_row() -> Vec<FE> {
+        type G = Poseidon2ExternalMatmulGate<F, D>;
+        let mut wires = FE::rand_vec(G::new().num_wires());
+        // The gate acts on each limb of the extension algebra separately.
+        for limb in 0..D {
+            let mut state: [FE; WIDTH] =
+                core::array::from_fn(|i| wires[G::wires_input(i).start + limb]);
+            <F as Poseidon2>::matmul_external_field::<FE, D>(&mut state);
+            for (i, x) in state.into_iter().enumerate() {
+                let subtrahend = wires[G::wires_subtrahend(i).start + limb];
+                wires[G::wires_output(i).start + limb] = x - subtrahend;
+            }
+        }
+        wires
+    }
+
+    #[test]
+    fn tampered_wires() {
+        let gate = Poseidon2ExternalMatmulGate::<F, D>::new();
+        let public_inputs_hash = HashOut::rand();
+        let constraints = |wires: &[FE]| {
+            gate.eval_unfiltered(EvaluationVars {
+                local_constants: &[],
+                local_wires: wires,
+                public_inputs_hash: &public_inputs_hash,
+            })
+        };
+
+        let wires = valid_row();
+        assert!(constraints(&wires).iter().all(|c| c.is_zero()));
+
+        // M_E is invertible, so changing any input, subtrahend or output wire
+        // breaks a constraint.
+        for column in 0..gate.num_wires() {
+            let mut tampered = wires.clone();
+            tampered[column] += FE::ONE;
+            assert!(
+                constraints(&tampered).iter().any(|c| !c.is_zero()),
+                "Tampering wire {} was not detected",
+                column
+            );
+        }
+    }
+}
@@ -0,0 +1,495 @@
+//! A Poseidon2 gate for 4-ary Merkle trees, where the current node can be any
+//! of the four children of its parent.
+//!
+//! The node is inserted among its three siblings at the position given by two
+//! index bits, and the first half of the resulting 16 elements is absorbed into
+//! a fresh sponge. The second half is exposed as wires so that a
+//! [`Poseidon2SpongeGate`](crate::gate::poseidon2_sponge::Poseidon2SpongeGate)
+//! can absorb it, which gives `Poseidon2Hash::hash_no_pad` of the children.
+
+use core::marker::PhantomData;
+use core::ops::{Add, Mul, Sub};
+
+use plonky2::gates::gate::Gate;
+use plonky2::gates::util::StridedConstraintConsumer;
+use plonky2::hash::hash_types::{RichField, NUM_HASH_OUT_ELTS};
+use plonky2::hash::poseidon::{SPONGE_CAPACITY, SPONGE_RATE};
+use plonky2::iop::ext_target::ExtensionTarget;
+use plonky2::iop::generator::{GeneratedValues, SimpleGenerator, WitnessGeneratorRef};
+use plonky2::iop::target::Target;
+use plonky2::iop::wire::Wire;
+use plonky2::iop::witness::{PartitionWitness, Witness, WitnessWrite};
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+use plonky2::plonk::circuit_data::CommonCircuitData;
+use plonky2::plonk::vars::{EvaluationTargets, EvaluationVars, EvaluationVarsBase};
+use plonky2::util::serialization::{Buffer, IoError, IoResult, Read, Write};
+use plonky2_field::extension::Extendable;
+use plonky2_field::types::Field;
+
+use crate::gate::poseidon2_external::matmul_external_circuit;
+use crate::gate::poseidon2_rounds::Poseidon2RoundWires;
+use crate::poseidon2_hash::poseidon2::{Poseidon2, ROUND_F_END, ROUND_P, WIDTH};
+
+/// Number of children of a node.
+pub const ARITY: usize = 4;
+
+/// Number of index bits selecting the position of a node among its siblings.
+pub const POSITION_BITS: usize = 2;
+
+/// Returns the child at `position` when `node` is inserted at `node_position`,
+/// the siblings filling the other positions in order.
+pub fn select_child<T: Copy>(
+    node: T,
+    siblings: &[T; ARITY - 1],
+    node_position: usize,
+    position: usize,
+) -> T {
+    use core::cmp::Ordering;
+    match node_position.cmp(&position) {
+        Ordering::Equal => node,
+        Ordering::Less => siblings[position - 1],
+        Ordering::Greater => siblings[position],
+    }
+}
+
+/// Evaluates `select_child` as a polynomial in the position bits, given the
+/// indicator of each node position.
+fn select_child_poly<T: Copy + Add<Output = T> + Mul<Output = T>>(
+    node: T,
+    siblings: &[T; ARITY - 1],
+    indicators: &[T; ARITY],
+    position: usize,
+) -> T {
+    (0..ARITY)
+        .map(|p| indicators[p] * select_child(node, siblings, p, position))
+        .reduce(|acc, x| acc + x)
+        .unwrap()
+}
+
+/// The indicators `[p == 0, p == 1, p == 2, p == 3]` of `p = b0 + 2 * b1`.
+fn position_indicators<T: Copy + Add<Output = T> + Sub<Output = T> + Mul<Output = T>>(
+    one: T,
+    b0: T,
+    b1: T,
+) -> [T; ARITY] {
+    let b0_b1 = b0 * b1;
+    let not_b0 = one - b0;
+    [not_b0 - b1 + b0_b1, b0 - b0_b1, b1 - b0_b1, b0_b1]
+}
+
+/// Inserts a node among its three siblings at the position given by two bits,
+/// and evaluates the Poseidon2 permutation on the first two children with a
+/// zero capacity.
+///
+/// The last two children and the capacity of the output are exposed for a
+/// second absorption. The gate uses 144 wires, so it requires a config with
+/// more wires than `CircuitConfig::standard_recursion_config`.
+#[derive(Debug, Default)]
+pub struct Poseidon2PositionGate<F: RichField + Extendable<D>, const D: usize> {
+    _phantom: PhantomData<F>,
+}
+
+impl<F: RichField + Extendable<D>, const D: usize> Poseidon2PositionGate<F, D> {
+    pub fn new() -> Self {
+        Poseidon2PositionGate {
+            _phantom: PhantomData,
+        }
+    }
+
+    /// The wire index for the `i`th element of the current node.
+    pub fn wire_node(i: usize) -> usize {
+        debug_assert!(i < NUM_HASH_OUT_ELTS);
+        i
+    }
+
+    /// The wire index for the `i`th element of the `sibling`th sibling.
+    pub fn wire_sibling(sibling: usize, i: usize) -> usize {
+        debug_assert!(sibling < ARITY - 1);
+        debug_assert!(i < NUM_HASH_OUT_ELTS);
+        NUM_HASH_OUT_ELTS * (1 + sibling) + i
+    }
+
+    /// The wire index for the `i`th bit of the position of the node.
+    pub fn wire_position_bit(i: usize) -> usize {
+        debug_assert!(i < POSITION_BITS);
+        NUM_HASH_OUT_ELTS * ARITY + i
+    }
+
+    const START_CHILDREN: usize = NUM_HASH_OUT_ELTS * ARITY + POSITION_BITS;
+
+    /// The wire index for the `i`th element of the `child`th child, once the
+    /// node has been inserted.
+    pub fn wire_child(child: usize, i: usize) -> usize {
+        debug_assert!(child < ARITY);
+        debug_assert!(i < NUM_HASH_OUT_ELTS);
+        Self::START_CHILDREN + NUM_HASH_OUT_ELTS * child + i
+    }
+
+    const START_OUTPUT: usize = Self::START_CHILDREN + NUM_HASH_OUT_ELTS * ARITY;
+
+    /// The wire index for the `i`th capacity element of the output of the
+    /// permutation.
+    pub fn wire_output_capacity(i: usize) -> usize {
+        debug_assert!(i < SPONGE_CAPACITY);
+        Self::START_OUTPUT + i
+    }
+
+    /// The S-box input wires of the rounds, after the outputs.
+    const ROUNDS: Poseidon2RoundWires =
+        Poseidon2RoundWires::new(Self::START_OUTPUT + SPONGE_CAPACITY);
+
+    /// Version of the serialized gate, see `Poseidon2Gate::SERIALIZATION_VERSION`.
+    pub const SERIALIZATION_VERSION: u32 = 1;
+}
+
+impl<F: RichField + Extendable<D> + Poseidon2, const D: usize> Gate<F, D>
+    for Poseidon2PositionGate<F, D>
+{
+    fn id(&self) -> String {
+        format!("{:?}<WIDTH={}>", self, WIDTH)
+    }
+
+    fn serialize(&self, dst: &mut Vec<u8>, _common_data: &CommonCircuitData<F, D>) -> IoResult<()> {
+        dst.write_u32(Self::SERIALIZATION_VERSION)?;
+        dst.write_usize(WIDTH)?;
+        dst.write_usize(ROUND_F_END)?;
+        dst.write_usize(ROUND_P)
+    }
+
+    fn deserialize(src: &mut Buffer, _common_data: &CommonCircuitData<F, D>) -> IoResult<Self> {
+        let version = src.read_u32()?;
+        let width = src.read_usize()?;
+        let rounds_f = src.read_usize()?;
+        let rounds_p = src.read_usize()?;
+        if version != Self::SERIALIZATION_VERSION
+            || width != WIDTH
+            || rounds_f != ROUND_F_END
+            || rounds_p != ROUND_P
+        {
+            return Err(IoError);
+        }
+        Ok(Poseidon2PositionGate::new())
+    }
+
+    fn eval_unfiltered(&self, vars: EvaluationVars<F, D>) -> Vec<F::Extension> {
+        let mut constraints = Vec::with_capacity(self.num_constraints());
+
+        // Assert that the position bits are binary.
+        let b0 = vars.local_wires[Self::wire_position_bit(0)];
+        let b1 = vars.local_wires[Self::wire_position_bit(1)];
+        constraints.push(b0 * (b0 - F::Extension::ONE));
+        constraints.push(b1 * (b1 - F::Extension::ONE));
+
+        // Assert that the children are the node and its siblings, in order.
+        let indicators = position_indicators(F::Extension::ONE, b0, b1);
+        for i in 0..NUM_HASH_OUT_ELTS {
+            let node = vars.local_wires[Self::wire_node(i)];
+            let siblings = core::array::from_fn(|s| vars.local_wires[Self::wire_sibling(s, i)]);
+            for child in 0..ARITY {
+                let expected = select_child_poly(node, &siblings, &indicators, child);
+                constraints.push(expected - vars.local_wires[Self::wire_child(child, i)]);
+            }
+        }
+
+        // Absorb the first two children.
+        let mut state = [F::Extension::ZERO; WIDTH];
+        state[..SPONGE_RATE].copy_from_slice(
+            &vars.local_wires[Self::START_CHILDREN..Self::START_CHILDREN + SPONGE_RATE],
+        );
+
+        // M_E * X
+        <F as Poseidon2>::matmul_external_field(&mut state);
+
+        let state = Self::ROUNDS.eval::<F, D>(vars.local_wires, state, &mut constraints);
+
+        for i in 0..SPONGE_CAPACITY {
+            constraints
+                .push(state[SPONGE_RATE + i] - vars.local_wires[Self::wire_output_capacity(i)]);
+        }
+
+        constraints
+    }
+
+    fn eval_unfiltered_base_one(
+        &self,
+        vars: EvaluationVarsBase<F>,
+        mut yield_constr: StridedConstraintConsumer<F>,
+    ) {
+        // Assert that the position bits are binary.
+        let b0 = vars.local_wires[Self::wire_position_bit(0)];
+        let b1 = vars.local_wires[Self::wire_position_bit(1)];
+        yield_constr.one(b0 * b0.sub_one());
+        yield_constr.one(b1 * b1.sub_one());
+
+        // Assert that the children are the node and its siblings, in order.
+        let indicators = position_indicators(F::ONE, b0, b1);
+        for i in 0..NUM_HASH_OUT_ELTS {
+            let node = vars.local_wires[Self::wire_node(i)];
+            let siblings = core::array::from_fn(|s| vars.local_wires[Self::wire_sibling(s, i)]);
+            for child in 0..ARITY {
+                let expected = select_child_poly(node, &siblings, &indicators, child);
+                yield_constr.one(expected - vars.local_wires[Self::wire_child(child, i)]);
+            }
+        }
+
+        // Absorb the first two children.
+        let mut state = [F::ZERO; WIDTH];
+        for (i, s) in state[..SPONGE_RATE].iter_mut().enumerate() {
+            *s = vars.local_wires[Self::START_CHILDREN + i];
+        }
+
+        // M_E * X
+        <F as Poseidon2>::matmul_external(&mut state);
+
+        let state = Self::ROUNDS.eval_base(&vars.local_wires, state, |c| yield_constr.one(c));
+
+        for i in 0..SPONGE_CAPACITY {
+            yield_constr
+                .one(state[SPONGE_RATE + i] - vars.local_wires[Self::wire_output_capacity(i)]);
+        }
+    }
+
+    fn eval_unfiltered_circuit(
+        &self,
+        builder: &mut CircuitBuilder<F, D>,
+        vars: EvaluationTargets<D>,
+    ) -> Vec<ExtensionTarget<D>> {
+        let mut constraints = Vec::with_capacity(self.num_constraints());
+
+        // Assert that the position bits are binary.
+        let b0 = vars.local_wires[Self::wire_position_bit(0)];
+        let b1 = vars.local_wires[Self::wire_position_bit(1)];
+        constraints.push(builder.mul_sub_extension(b0, b0, b0));
+        constraints.push(builder.mul_sub_extension(b1, b1, b1));
+
+        // Assert that the children are the node and its siblings, in order.
+        // With `p = b0 + 2 * b1`, child `j` is the node if `p == j`, the
+        // sibling `j - 1` if `p < j` and the sibling `j` if `p > j`.
+        let one = builder.one_extension();
+        let b0_b1 = builder.mul_extension(b0, b1);
+        let not_b0 = builder.sub_extension(one, b0);
+        let e0 = builder.sub_extension(not_b0, b1);
+        let indicators = [
+            builder.add_extension(e0, b0_b1),
+            builder.sub_extension(b0, b0_b1),
+            builder.sub_extension(b1, b0_b1),
+            b0_b1,
+        ];
+        for i in 0..NUM_HASH_OUT_ELTS {
+            let node = vars.local_wires[Self::wire_node(i)];
+            let siblings: [ExtensionTarget<D>; ARITY - 1] =
+                core::array::from_fn(|s| vars.local_wires[Self::wire_sibling(s, i)]);
+            for child in 0..ARITY {
+                let mut expected = builder.zero_extension();
+                for (p, &indicator) in indicators.iter().enumerate() {
+                    let term = select_child(node, &siblings, p, child);
+                    expected = builder.mul_add_extension(indicator, term, expected);
+                }
+                constraints.push(
+                    builder.sub_extension(expected, vars.local_wires[Self::wire_child(child, i)]),
+                );
+            }
+        }
+
+        // Absorb the first two children.
+        let zero = builder.zero_extension();
+        let mut state = [zero; WIDTH];
+        state[..SPONGE_RATE].copy_from_slice(
+            &vars.local_wires[Self::START_CHILDREN..Self::START_CHILDREN + SPONGE_RATE],
+        );
+
+        // M_E * X
+        state = matmul_external_circuit(builder, state);
+
+        let state = Self::ROUNDS.eval_circuit(builder, vars.local_wires, state, &mut constraints);
+
+        for i in 0..SPONGE_CAPACITY {
+            constraints.push(builder.sub_extension(
+                state[SPONGE_RATE + i],
+                vars.local_wires[Self::wire_output_capacity(i)],
+            ));
+        }
+
+        constraints
+    }
+
+    fn generators(&self, row: usize, _local_constants: &[F]) -> Vec<WitnessGeneratorRef<F, D>> {
+        let gen = Poseidon2PositionGenerator::<F, D> {
+            row,
+            _phantom: PhantomData,
+        };
+        vec![WitnessGeneratorRef::new(gen.adapter())]
+    }
+
+    fn num_wires(&self) -> usize {
+        Self::ROUNDS.end()
+    }
+
+    fn num_constants(&self) -> usize {
+        0
+    }
+
+    fn degree(&self) -> usize {
+        7
+    }
+
+    fn num_constraints(&self) -> usize {
+        POSITION_BITS
+            + NUM_HASH_OUT_ELTS * ARITY
+            + Poseidon2RoundWires::NUM_CONSTRAINTS
+            + SPONGE_CAPACITY
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Poseidon2PositionGenerator<F: RichField + Extendable<D> + Poseidon2, const D: usize> {
+    row: usize,
+    _phantom: PhantomData<F>,
+}
+
+impl<F: RichField + Extendable<D> + Poseidon2, const D: usize> SimpleGenerator<F, D>
+    for Poseidon2PositionGenerator<F, D>
+{
+    fn id(&self) -> String {
+        "Poseidon2PositionGenerator".to_string()
+    }
+
+    fn serialize(&self, dst: &mut Vec<u8>, _common_data: &CommonCircuitData<F, D>) -> IoResult<()> {
+        dst.write_usize(self.row)
+    }
+
+    fn deserialize(src: &mut Buffer, _common_data: &CommonCircuitData<F, D>) -> IoResult<Self> {
+        let row = src.read_usize()?;
+        Ok(Self {
+            row,
+            _phantom: PhantomData,
+        })
+    }
+
+    fn dependencies(&self) -> Vec<Target> {
+        (0..Poseidon2PositionGate::<F, D>::START_CHILDREN)
+            .map(|column| Target::wire(self.row, column))
+            .collect()
+    }
+
+    fn run_once(&self, witness: &PartitionWitness<F>, out_buffer: &mut GeneratedValues<F>) {
+        type Gate<F, const D: usize> = Poseidon2PositionGate<F, D>;
+        let local_wire = |column| Wire {
+            row: self.row,
+            column,
+        };
+
+        // Non-binary bits fail the constraints of the gate. Only their low bit
+        // is used here so that the position stays below `ARITY`.
+        let position = (0..POSITION_BITS).rev().fold(0, |acc, i| {
+            let bit = witness.get_wire(local_wire(Gate::<F, D>::wire_position_bit(i)));
+            2 * acc + (bit.to_canonical_u64() & 1) as usize
+        });
+
+        let mut state = [F::ZERO; WIDTH];
+        for i in 0..NUM_HASH_OUT_ELTS {
+            let node = witness.get_wire(local_wire(Gate::<F, D>::wire_node(i)));
+            let siblings = core::array::from_fn(|s| {
+                witness.get_wire(local_wire(Gate::<F, D>::wire_sibling(s, i)))
+            });
+            for child in 0..ARITY {
+                let value = select_child(node, &siblings, position, child);
+                out_buffer.set_wire(local_wire(Gate::<F, D>::wire_child(child, i)), value);
+                if child < SPONGE_RATE / NUM_HASH_OUT_ELTS {
+                    state[NUM_HASH_OUT_ELTS * child + i] = value;
+                }
+            }
+        }
+
+        let state = Gate::<F, D>::ROUNDS.generate(self.row, state, out_buffer);
+
+        for i in 0..SPONGE_CAPACITY {
+            out_buffer.set_wire(
+                local_wire(Gate::<F, D>::wire_output_capacity(i)),
+                state[SPONGE_RATE + i],
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use plonky2::gates::gate::Gate;
+    use plonky2::gates::gate_testing::{test_eval_fns, test_low_degree};
+    use plonky2::iop::target::Target;
+    use plonky2::iop::witness::{PartialWitness, WitnessWrite};
+    use plonky2::plonk::circuit_builder::CircuitBuilder;
+    use plonky2::plonk::config::GenericConfig;
+    use plonky2_field::goldilocks_field::GoldilocksField;
+    use plonky2_field::types::Field;
+
+    use crate::config::presets::wide_config;
+    use crate::config::Poseidon2GoldilocksConfig;
+    use crate::gate::poseidon2_position::{select_child, Poseidon2PositionGate};
+
+    #[test]
+    fn wire_indices() {
+        type G = Poseidon2PositionGate<GoldilocksField, 2>;
+        assert_eq!(G::wire_sibling(2, 3), 15);
+        assert_eq!(G::wire_position_bit(1), 17);
+        assert_eq!(G::wire_child(0, 0), 18);
+        assert_eq!(G::wire_output_capacity(0), 34);
+        assert_eq!(G::new().num_wires(), 144);
+    }
+
+    #[test]
+    fn children_order() {
+        let siblings = ['a', 'b', 'c'];
+        let children = |p| {
+            (0..4)
+                .map(|j| select_child('n', &siblings, p, j))
+                .collect::<String>()
+        };
+        assert_eq!(children(0), "nabc");
+        assert_eq!(children(1), "anbc");
+        assert_eq!(children(2), "abnc");
+        assert_eq!(children(3), "abcn");
+    }
+
+    #[test]
+    fn low_degree() {
+        type F = GoldilocksField;
+        test_low_degree(Poseidon2PositionGate::<F, 4>::new())
+    }
+
+    #[test]
+    fn non_binary_position_bit() {
+        const D: usize = 2;
+        type C = Poseidon2GoldilocksConfig;
+        type F = <C as GenericConfig<D>>::F;
+
+        let mut builder = CircuitBuilder::<F, D>::new(wide_config());
+        let row = builder.add_gate(Poseidon2PositionGate::<F, D>::new(), vec![]);
+        let mut pw = PartialWitness::new();
+        for column in 0..Poseidon2PositionGate::<F, D>::wire_position_bit(0) {
+            pw.set_target(Target::wire(row, column), F::from_canonical_usize(column));
+        }
+        pw.set_target(
+            Target::wire(row, Poseidon2PositionGate::<F, D>::wire_position_bit(0)),
+            F::TWO,
+        );
+        pw.set_target(
+            Target::wire(row, Poseidon2PositionGate::<F, D>::wire_position_bit(1)),
+            F::ONE,
+        );
+
+        // Witness generation goes through, and the proof is rejected.
+        let data = builder.build::<C>();
+        let result = data.prove(pw).and_then(|proof| data.verify(proof));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn eval_fns() -> Result<()> {
+        const D: usize = 2;
+        type C = Poseidon2GoldilocksConfig;
+        type F = <C as GenericConfig<D>>::F;
+        test_eval_fns::<F, C, _, D>(Poseidon2PositionGate::<F, D>::new())
+    }
+}
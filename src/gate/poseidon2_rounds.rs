@@ -0,0 +1,342 @@
+//! Wire layout and constraints of the Poseidon2 rounds, shared by the gates
+//! which evaluate a full permutation in a single row.
+//!
+//! Starting from the state right after the initial M_E layer, the S-box inputs
+//! of every round except the first full round are stored as wires, which keeps
+//! the constraint degree at 7.
+
+use core::ops::Index;
+
+use plonky2::hash::hash_types::RichField;
+use plonky2::iop::ext_target::ExtensionTarget;
+use plonky2::iop::generator::GeneratedValues;
+use plonky2::iop::target::Target;
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+use plonky2_field::extension::{Extendable, FieldExtension};
+use plonky2_field::packed::PackedField;
+use plonky2_field::types::Field;
+
+use crate::gate::poseidon2_external::{matmul_external_circuit, matmul_external_sub_circuit};
+use crate::poseidon2_hash::poseidon2::{Poseidon2, ROUND_F_BEGIN, ROUND_F_END, ROUND_P, WIDTH};
+use crate::poseidon2_hash::trace::{Poseidon2RoundPhase, Poseidon2Trace};
+
+/// The round wires of a Poseidon2 gate, laid out contiguously from `start`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Poseidon2RoundWires {
+    pub start: usize,
+}
+
+impl Poseidon2RoundWires {
+    /// Number of wires used by the rounds.
+    pub const NUM_WIRES: usize = WIDTH * (ROUND_F_BEGIN - 1) + ROUND_P + WIDTH * ROUND_F_BEGIN;
+
+    /// Number of constraints generated by the rounds.
+    pub const NUM_CONSTRAINTS: usize = Self::NUM_WIRES;
+
+    pub const fn new(start: usize) -> Self {
+        Self { start }
+    }
+
+    /// A wire which stores the input of the `i`-th S-box of the `round`-th
+    /// round of the first set of full rounds.
+    pub fn full_round_begin(&self, round: usize, i: usize) -> usize {
+        debug_assert!(
+            round != 0,
+            "First round S-box inputs are not stored as wires"
+        );
+        debug_assert!(round < ROUND_F_BEGIN);
+        debug_assert!(i < WIDTH);
+        self.start + WIDTH * (round - 1) + i
+    }
+
+    fn start_partial(&self) -> usize {
+        self.start + WIDTH * (ROUND_F_BEGIN - 1)
+    }
+
+    /// A wire which stores the input of the S-box of the `round`-th round of
+    /// the partial rounds.
+    pub fn partial_round(&self, round: usize) -> usize {
+        debug_assert!(round < ROUND_P);
+        self.start_partial() + round
+    }
+
+    fn start_full_round_end(&self) -> usize {
+        self.start_partial() + ROUND_P
+    }
+
+    /// A wire which stores the input of the `i`-th S-box of the `round`-th
+    /// round of the second set of full rounds.
+    pub fn full_round_end(&self, round: usize, i: usize) -> usize {
+        debug_assert!(round < ROUND_F_BEGIN);
+        debug_assert!(i < WIDTH);
+        self.start_full_round_end() + WIDTH * round + i
+    }
+
+    /// End of the round wires, exclusive.
+    pub fn end(&self) -> usize {
+        self.start + Self::NUM_WIRES
+    }
+
+    /// Applies the rounds to `state`, which must already have gone through the
+    /// initial M_E layer, pushing one constraint per round wire. Returns the
+    /// output of the permutation.
+    pub fn eval<F: RichField + Extendable<D> + Poseidon2, const D: usize>(
+        &self,
+        local_wires: &[F::Extension],
+        mut state: [F::Extension; WIDTH],
+        constraints: &mut Vec<F::Extension>,
+    ) -> [F::Extension; WIDTH] {
+        // External_i, i in {0 - R_F/2 -1}
+        for r in 0..ROUND_F_BEGIN {
+            <F as Poseidon2>::constant_layer_field(&mut state, r);
+            if r != 0 {
+                for i in 0..WIDTH {
+                    let sbox_in = local_wires[self.full_round_begin(r, i)];
+                    constraints.push(state[i] - sbox_in);
+                    state[i] = sbox_in;
+                }
+            }
+            <F as Poseidon2>::sbox_layer_field(&mut state);
+            <F as Poseidon2>::matmul_external_field(&mut state);
+        }
+
+        // Internal_i
+        for r in 0..ROUND_P {
+            state[0] += F::Extension::from_canonical_u64(<F as Poseidon2>::RC12_MID[r]);
+            let sbox_in = local_wires[self.partial_round(r)];
+            constraints.push(state[0] - sbox_in);
+            state[0] = <F as Poseidon2>::sbox_monomial(sbox_in);
+            <F as Poseidon2>::matmul_internal_field(&mut state, &<F as Poseidon2>::MAT_DIAG12_M_1);
+        }
+
+        // External_i, i in {R_F/2 = R/F - 1}.
+        for r in ROUND_F_BEGIN..ROUND_F_END {
+            <F as Poseidon2>::constant_layer_field(&mut state, r);
+            for i in 0..WIDTH {
+                let sbox_in = local_wires[self.full_round_end(r - ROUND_F_BEGIN, i)];
+                constraints.push(state[i] - sbox_in);
+                state[i] = sbox_in;
+            }
+            <F as Poseidon2>::sbox_layer_field(&mut state);
+            <F as Poseidon2>::matmul_external_field(&mut state);
+        }
+
+        state
+    }
+
+    /// Same as `eval`, over the base field.
+    pub fn eval_base<F: RichField + Poseidon2, W: Index<usize, Output = F> + ?Sized>(
+        &self,
+        local_wires: &W,
+        mut state: [F; WIDTH],
+        mut yield_constr: impl FnMut(F),
+    ) -> [F; WIDTH] {
+        // External_i, i in {0 - R_F/2 -1}
+        for r in 0..ROUND_F_BEGIN {
+            <F as Poseidon2>::constant_layer(&mut state, r);
+            if r != 0 {
+                for i in 0..WIDTH {
+                    let sbox_in = local_wires[self.full_round_begin(r, i)];
+                    yield_constr(state[i] - sbox_in);
+                    state[i] = sbox_in;
+                }
+            }
+            <F as Poseidon2>::sbox_layer(&mut state);
+            <F as Poseidon2>::matmul_external(&mut state);
+        }
+
+        // Internal_i
+        for r in 0..ROUND_P {
+            // t_0 = x_0 + c_0^i
+            state[0] += F::from_canonical_u64(<F as Poseidon2>::RC12_MID[r]);
+            let sbox_in = local_wires[self.partial_round(r)];
+            yield_constr(state[0] - sbox_in);
+            // t_1 = t_0^7
+            state[0] = <F as Poseidon2>::sbox_monomial(sbox_in);
+            // M_I * t_1
+            <F as Poseidon2>::matmul_internal(&mut state, &<F as Poseidon2>::MAT_DIAG12_M_1);
+        }
+
+        // External_i, i in {R_F/2 = R/F - 1}
+        for r in ROUND_F_BEGIN..ROUND_F_END {
+            <F as Poseidon2>::constant_layer(&mut state, r);
+            for i in 0..WIDTH {
+                let sbox_in = local_wires[self.full_round_end(r - ROUND_F_BEGIN, i)];
+                yield_constr(state[i] - sbox_in);
+                state[i] = sbox_in;
+            }
+            <F as Poseidon2>::sbox_layer(&mut state);
+            <F as Poseidon2>::matmul_external(&mut state);
+        }
+
+        state
+    }
+
+    /// Same as `eval`, over packed extension fields.
+    pub fn eval_packed<F, FE, P, const D2: usize>(
+        &self,
+        local_wires: &[P],
+        mut state: [P; WIDTH],
+        mut yield_constr: impl FnMut(P),
+    ) -> [P; WIDTH]
+    where
+        F: RichField + Poseidon2,
+        FE: FieldExtension<D2, BaseField = F>,
+        P: PackedField<Scalar = FE>,
+    {
+        // External_i, i in {0 - R_F/2 -1}
+        for r in 0..ROUND_F_BEGIN {
+            <F as Poseidon2>::constant_layer_packed(&mut state, r);
+            if r != 0 {
+                for i in 0..WIDTH {
+                    let sbox_in = local_wires[self.full_round_begin(r, i)];
+                    yield_constr(state[i] - sbox_in);
+                    state[i] = sbox_in;
+                }
+            }
+            <F as Poseidon2>::sbox_layer_packed(&mut state);
+            <F as Poseidon2>::matmul_external_packed(&mut state);
+        }
+
+        // Internal_i
+        for r in 0..ROUND_P {
+            state[0] += FE::from_canonical_u64(<F as Poseidon2>::RC12_MID[r]);
+            let sbox_in = local_wires[self.partial_round(r)];
+            yield_constr(state[0] - sbox_in);
+            state[0] = <F as Poseidon2>::sbox_monomial_packed(sbox_in);
+            <F as Poseidon2>::matmul_internal_packed(&mut state, &<F as Poseidon2>::MAT_DIAG12_M_1);
+        }
+
+        // External_i, i in {R_F/2 = R/F - 1}
+        for r in ROUND_F_BEGIN..ROUND_F_END {
+            <F as Poseidon2>::constant_layer_packed(&mut state, r);
+            for i in 0..WIDTH {
+                let sbox_in = local_wires[self.full_round_end(r - ROUND_F_BEGIN, i)];
+                yield_constr(state[i] - sbox_in);
+                state[i] = sbox_in;
+            }
+            <F as Poseidon2>::sbox_layer_packed(&mut state);
+            <F as Poseidon2>::matmul_external_packed(&mut state);
+        }
+
+        state
+    }
+
+    /// Same as `eval`, in a circuit.
+    pub fn eval_circuit<F: RichField + Extendable<D> + Poseidon2, const D: usize>(
+        &self,
+        builder: &mut CircuitBuilder<F, D>,
+        local_wires: &[ExtensionTarget<D>],
+        mut state: [ExtensionTarget<D>; WIDTH],
+        constraints: &mut Vec<ExtensionTarget<D>>,
+    ) -> [ExtensionTarget<D>; WIDTH] {
+        // The constants of full round `r + 1` are folded into the S-box layer of
+        // full round `r`, since M_E * (x^7 + M_E^{-1} * c) = M_E * x^7 + c.
+        let sbox_layer = |builder: &mut CircuitBuilder<F, D>,
+                          state: &mut [ExtensionTarget<D>; WIDTH],
+                          next_round: Option<usize>| {
+            let mut folded = [F::ZERO; WIDTH];
+            if let Some(r) = next_round {
+                let round_constants = &<F as Poseidon2>::RC12[r * WIDTH..(r + 1) * WIDTH];
+                for (f, &c) in folded.iter_mut().zip(round_constants) {
+                    *f = F::from_canonical_u64(c);
+                }
+                <F as Poseidon2>::matmul_external_inverse(&mut folded);
+            }
+            for (s, f) in state.iter_mut().zip(folded) {
+                let addend = builder.constant_extension(f.into());
+                *s = <F as Poseidon2>::sbox_monomial_add_circuit(builder, *s, addend);
+            }
+        };
+
+        // External_i, i in {0 - R_F/2 -1}
+        <F as Poseidon2>::constant_layer_circuit(builder, &mut state, 0);
+        // The constraints of full round `r + 1` are computed along with the
+        // M_E layer of full round `r`.
+        for r in 0..ROUND_F_BEGIN {
+            let next_round = Some(r + 1).filter(|&r| r < ROUND_F_BEGIN);
+            sbox_layer(builder, &mut state, next_round);
+            if let Some(next) = next_round {
+                let sbox_in = core::array::from_fn(|i| local_wires[self.full_round_begin(next, i)]);
+                constraints.extend(matmul_external_sub_circuit(builder, state, sbox_in));
+                state = sbox_in;
+            } else {
+                state = matmul_external_circuit(builder, state);
+            }
+        }
+
+        // Internal_i
+        for r in 0..ROUND_P {
+            let round_constant = F::Extension::from_canonical_u64(<F as Poseidon2>::RC12_MID[r]);
+            let round_constant = builder.constant_extension(round_constant);
+            state[0] = builder.add_extension(state[0], round_constant);
+
+            let sbox_in = local_wires[self.partial_round(r)];
+            constraints.push(builder.sub_extension(state[0], sbox_in));
+            state[0] = <F as Poseidon2>::sbox_monomial_circuit(builder, sbox_in);
+            <F as Poseidon2>::matmul_internal_circuit(builder, &mut state);
+        }
+
+        // External_i, i in {R_F/2 = R/F - 1}.
+        <F as Poseidon2>::constant_layer_circuit(builder, &mut state, ROUND_F_BEGIN);
+        for i in 0..WIDTH {
+            let sbox_in = local_wires[self.full_round_end(0, i)];
+            constraints.push(builder.sub_extension(state[i], sbox_in));
+            state[i] = sbox_in;
+        }
+        for r in ROUND_F_BEGIN..ROUND_F_END {
+            let next_round = Some(r + 1).filter(|&r| r < ROUND_F_END);
+            sbox_layer(builder, &mut state, next_round);
+            if let Some(next) = next_round {
+                let sbox_in = core::array::from_fn(|i| {
+                    local_wires[self.full_round_end(next - ROUND_F_BEGIN, i)]
+                });
+                constraints.extend(matmul_external_sub_circuit(builder, state, sbox_in));
+                state = sbox_in;
+            } else {
+                state = matmul_external_circuit(builder, state);
+            }
+        }
+
+        state
+    }
+
+    /// Computes the permutation of `input` and writes the round wires of
+    /// `row`. Returns the output of the permutation.
+    pub fn generate<F: RichField + Poseidon2>(
+        &self,
+        row: usize,
+        input: [F; WIDTH],
+        out_buffer: &mut GeneratedValues<F>,
+    ) -> [F; WIDTH] {
+        let trace = <F as Poseidon2>::poseidon2_trace(input);
+        out_buffer.target_values.extend(
+            Self::round_values(&trace)
+                .into_iter()
+                .enumerate()
+                .map(|(i, value)| (Target::wire(row, self.start + i), value)),
+        );
+        trace.output()
+    }
+
+    /// The values of the round wires of a permutation, in wire order, i.e. the
+    /// S-box inputs of its rounds except the first one.
+    pub fn round_values<F: RichField + Poseidon2>(
+        trace: &Poseidon2Trace<F>,
+    ) -> [F; Self::NUM_WIRES] {
+        let mut values = [F::ZERO; Self::NUM_WIRES];
+        let mut next = 0;
+        for round in &trace.rounds {
+            let sbox_inputs = match round.phase {
+                Poseidon2RoundPhase::FullRoundBegin if round.round == 0 => continue,
+                Poseidon2RoundPhase::PartialRound => &round.after_constants[..1],
+                _ => &round.after_constants[..],
+            };
+            values[next..next + sbox_inputs.len()].copy_from_slice(sbox_inputs);
+            next += sbox_inputs.len();
+        }
+
+        debug_assert_eq!(next, Self::NUM_WIRES);
+        values
+    }
+}
@@ -0,0 +1,499 @@
+//! A gate absorbing one block of inputs into a Poseidon2 sponge state and
+//! applying the permutation, all in a single row.
+//!
+//! Only the capacity of the previous state is passed along, plus its rate in
+//! [`AbsorptionMode::Add`], which saves the copy constraints of routing a whole
+//! state into a fresh [`Poseidon2Gate`](crate::gate::poseidon2::Poseidon2Gate)
+//! for every absorbed block.
+
+use core::marker::PhantomData;
+
+use plonky2::gates::gate::Gate;
+use plonky2::gates::util::StridedConstraintConsumer;
+use plonky2::hash::hash_types::{HashOut, HashOutTarget, RichField, NUM_HASH_OUT_ELTS};
+use plonky2::hash::poseidon::{SPONGE_CAPACITY, SPONGE_RATE};
+use plonky2::iop::ext_target::ExtensionTarget;
+use plonky2::iop::generator::{GeneratedValues, SimpleGenerator, WitnessGeneratorRef};
+use plonky2::iop::target::Target;
+use plonky2::iop::wire::Wire;
+use plonky2::iop::witness::{PartitionWitness, Witness, WitnessWrite};
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+use plonky2::plonk::circuit_data::CommonCircuitData;
+use plonky2::plonk::vars::{EvaluationTargets, EvaluationVars, EvaluationVarsBase};
+use plonky2::util::serialization::{Buffer, IoError, IoResult, Read, Write};
+use plonky2_field::extension::Extendable;
+use plonky2_field::types::Field;
+
+use crate::gate::poseidon2_external::matmul_external_circuit;
+use crate::gate::poseidon2_rounds::Poseidon2RoundWires;
+use crate::poseidon2_hash::poseidon2::{Poseidon2, Poseidon2Hash, ROUND_F_END, ROUND_P, WIDTH};
+
+/// How an input block is combined with the rate of the sponge state.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum AbsorptionMode {
+    /// The block replaces the rate, as in `hash_n_to_hash_no_pad`.
+    #[default]
+    Overwrite,
+    /// The block is added to the rate, as in a textbook sponge.
+    Add,
+}
+
+impl AbsorptionMode {
+    /// Native sponge hash using this absorption mode. In `Overwrite` mode this
+    /// is the same as `Poseidon2Hash::hash_no_pad`.
+    pub fn hash_no_pad<F: RichField + Poseidon2>(self, inputs: &[F]) -> HashOut<F> {
+        let mut state = [F::ZERO; WIDTH];
+        for chunk in inputs.chunks(SPONGE_RATE) {
+            for (s, &x) in state.iter_mut().zip(chunk) {
+                match self {
+                    AbsorptionMode::Overwrite => *s = x,
+                    AbsorptionMode::Add => *s += x,
+                }
+            }
+            state = F::poseidon2(state);
+        }
+        HashOut::from_partial(&state[..NUM_HASH_OUT_ELTS])
+    }
+}
+
+/// Absorbs a block of `SPONGE_RATE` inputs into a sponge state and evaluates
+/// the Poseidon2 permutation on the result.
+///
+/// In `Overwrite` mode the gate uses 130 wires; in `Add` mode it also takes
+/// the previous rate and uses 138 wires, which requires a config with more
+/// wires than `CircuitConfig::standard_recursion_config`.
+#[derive(Debug, Default, Clone)]
+pub struct Poseidon2SpongeGate<F: RichField + Extendable<D>, const D: usize> {
+    pub mode: AbsorptionMode,
+    _phantom: PhantomData<F>,
+}
+
+impl<F: RichField + Extendable<D>, const D: usize> Poseidon2SpongeGate<F, D> {
+    pub fn new(mode: AbsorptionMode) -> Self {
+        Poseidon2SpongeGate {
+            mode,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// The wire index for the `i`th capacity element of the previous state.
+    pub fn wire_capacity(i: usize) -> usize {
+        debug_assert!(i < SPONGE_CAPACITY);
+        i
+    }
+
+    /// The wire index for the `i`th rate element of the previous state. Only
+    /// present in `Add` mode.
+    pub fn wire_rate(&self, i: usize) -> usize {
+        debug_assert!(self.mode == AbsorptionMode::Add);
+        debug_assert!(i < SPONGE_RATE);
+        SPONGE_CAPACITY + i
+    }
+
+    fn start_block(&self) -> usize {
+        match self.mode {
+            AbsorptionMode::Overwrite => SPONGE_CAPACITY,
+            AbsorptionMode::Add => WIDTH,
+        }
+    }
+
+    /// The wire index for the `i`th element of the absorbed block.
+    pub fn wire_block(&self, i: usize) -> usize {
+        debug_assert!(i < SPONGE_RATE);
+        self.start_block() + i
+    }
+
+    /// The wire index for the `i`th output of the permutation.
+    pub fn wire_output(&self, i: usize) -> usize {
+        debug_assert!(i < WIDTH);
+        self.start_block() + SPONGE_RATE + i
+    }
+
+    /// The S-box input wires of the rounds, after the outputs.
+    fn rounds(&self) -> Poseidon2RoundWires {
+        Poseidon2RoundWires::new(self.wire_output(0) + WIDTH)
+    }
+
+    /// Version of the serialized gate, see `Poseidon2Gate::SERIALIZATION_VERSION`.
+    pub const SERIALIZATION_VERSION: u32 = 1;
+}
+
+impl<F: RichField + Extendable<D> + Poseidon2, const D: usize> Gate<F, D>
+    for Poseidon2SpongeGate<F, D>
+{
+    fn id(&self) -> String {
+        format!("{:?}<WIDTH={}>", self, WIDTH)
+    }
+
+    fn serialize(
+        &self,
+        dst: &mut Vec<u8>,
+        _common_data: &CommonCircuitData<F, D>,
+    ) -> IoResult<()> {
+        dst.write_u32(Self::SERIALIZATION_VERSION)?;
+        dst.write_usize(WIDTH)?;
+        dst.write_usize(ROUND_F_END)?;
+        dst.write_usize(ROUND_P)?;
+        dst.write_bool(self.mode == AbsorptionMode::Add)
+    }
+
+    fn deserialize(src: &mut Buffer, _common_data: &CommonCircuitData<F, D>) -> IoResult<Self> {
+        let version = src.read_u32()?;
+        let width = src.read_usize()?;
+        let rounds_f = src.read_usize()?;
+        let rounds_p = src.read_usize()?;
+        if version != Self::SERIALIZATION_VERSION
+            || width != WIDTH
+            || rounds_f != ROUND_F_END
+            || rounds_p != ROUND_P
+        {
+            return Err(IoError);
+        }
+        let mode = if src.read_bool()? {
+            AbsorptionMode::Add
+        } else {
+            AbsorptionMode::Overwrite
+        };
+        Ok(Poseidon2SpongeGate::new(mode))
+    }
+
+    fn eval_unfiltered(&self, vars: EvaluationVars<F, D>) -> Vec<F::Extension> {
+        let mut constraints = Vec::with_capacity(self.num_constraints());
+
+        // Absorb the block into the previous state.
+        let mut state = [F::Extension::ZERO; WIDTH];
+        for (i, s) in state[..SPONGE_RATE].iter_mut().enumerate() {
+            *s = vars.local_wires[self.wire_block(i)];
+            if self.mode == AbsorptionMode::Add {
+                *s += vars.local_wires[self.wire_rate(i)];
+            }
+        }
+        for (i, s) in state[SPONGE_RATE..].iter_mut().enumerate() {
+            *s = vars.local_wires[Self::wire_capacity(i)];
+        }
+
+        // M_E * X
+        <F as Poseidon2>::matmul_external_field(&mut state);
+
+        let state = self.rounds().eval::<F, D>(vars.local_wires, state, &mut constraints);
+
+        for (i, s) in state.into_iter().enumerate() {
+            constraints.push(s - vars.local_wires[self.wire_output(i)]);
+        }
+
+        constraints
+    }
+
+    fn eval_unfiltered_base_one(
+        &self,
+        vars: EvaluationVarsBase<F>,
+        mut yield_constr: StridedConstraintConsumer<F>,
+    ) {
+        // Absorb the block into the previous state.
+        let mut state = [F::ZERO; WIDTH];
+        for (i, s) in state[..SPONGE_RATE].iter_mut().enumerate() {
+            *s = vars.local_wires[self.wire_block(i)];
+            if self.mode == AbsorptionMode::Add {
+                *s += vars.local_wires[self.wire_rate(i)];
+            }
+        }
+        for (i, s) in state[SPONGE_RATE..].iter_mut().enumerate() {
+            *s = vars.local_wires[Self::wire_capacity(i)];
+        }
+
+        // M_E * X
+        <F as Poseidon2>::matmul_external(&mut state);
+
+        let state = self
+            .rounds()
+            .eval_base(&vars.local_wires, state, |c| yield_constr.one(c));
+
+        for (i, s) in state.into_iter().enumerate() {
+            yield_constr.one(s - vars.local_wires[self.wire_output(i)]);
+        }
+    }
+
+    fn eval_unfiltered_circuit(
+        &self,
+        builder: &mut CircuitBuilder<F, D>,
+        vars: EvaluationTargets<D>,
+    ) -> Vec<ExtensionTarget<D>> {
+        let mut constraints = Vec::with_capacity(self.num_constraints());
+
+        // Absorb the block into the previous state.
+        let mut state = [builder.zero_extension(); WIDTH];
+        for (i, s) in state[..SPONGE_RATE].iter_mut().enumerate() {
+            *s = vars.local_wires[self.wire_block(i)];
+            if self.mode == AbsorptionMode::Add {
+                *s = builder.add_extension(*s, vars.local_wires[self.wire_rate(i)]);
+            }
+        }
+        for (i, s) in state[SPONGE_RATE..].iter_mut().enumerate() {
+            *s = vars.local_wires[Self::wire_capacity(i)];
+        }
+
+        // M_E * X
+        state = matmul_external_circuit(builder, state);
+
+        let state = self
+            .rounds()
+            .eval_circuit(builder, vars.local_wires, state, &mut constraints);
+
+        for (i, s) in state.into_iter().enumerate() {
+            constraints.push(builder.sub_extension(s, vars.local_wires[self.wire_output(i)]));
+        }
+
+        constraints
+    }
+
+    fn generators(&self, row: usize, _local_constants: &[F]) -> Vec<WitnessGeneratorRef<F, D>> {
+        let gen = Poseidon2SpongeGenerator::<F, D> {
+            row,
+            mode: self.mode,
+            _phantom: PhantomData,
+        };
+        vec![WitnessGeneratorRef::new(gen.adapter())]
+    }
+
+    fn num_wires(&self) -> usize {
+        self.rounds().end()
+    }
+
+    fn num_constants(&self) -> usize {
+        0
+    }
+
+    fn degree(&self) -> usize {
+        7
+    }
+
+    fn num_constraints(&self) -> usize {
+        Poseidon2RoundWires::NUM_CONSTRAINTS + WIDTH
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Poseidon2SpongeGenerator<F: RichField + Extendable<D> + Poseidon2, const D: usize> {
+    row: usize,
+    mode: AbsorptionMode,
+    _phantom: PhantomData<F>,
+}
+
+impl<F: RichField + Extendable<D> + Poseidon2, const D: usize> SimpleGenerator<F, D>
+    for Poseidon2SpongeGenerator<F, D>
+{
+    fn id(&self) -> String {
+        "Poseidon2SpongeGenerator".to_string()
+    }
+
+    fn serialize(&self, dst: &mut Vec<u8>, _common_data: &CommonCircuitData<F, D>) -> IoResult<()> {
+        dst.write_usize(self.row)?;
+        dst.write_bool(self.mode == AbsorptionMode::Add)
+    }
+
+    fn deserialize(src: &mut Buffer, _common_data: &CommonCircuitData<F, D>) -> IoResult<Self> {
+        let row = src.read_usize()?;
+        let mode = if src.read_bool()? {
+            AbsorptionMode::Add
+        } else {
+            AbsorptionMode::Overwrite
+        };
+        Ok(Self {
+            row,
+            mode,
+            _phantom: PhantomData,
+        })
+    }
+
+    fn dependencies(&self) -> Vec<Target> {
+        let gate = Poseidon2SpongeGate::<F, D>::new(self.mode);
+        let mut deps = (0..SPONGE_CAPACITY)
+            .map(Poseidon2SpongeGate::<F, D>::wire_capacity)
+            .chain((0..SPONGE_RATE).map(|i| gate.wire_block(i)))
+            .collect::<Vec<_>>();
+        if self.mode == AbsorptionMode::Add {
+            deps.extend((0..SPONGE_RATE).map(|i| gate.wire_rate(i)));
+        }
+        deps.into_iter()
+            .map(|column| Target::wire(self.row, column))
+            .collect()
+    }
+
+    fn run_once(&self, witness: &PartitionWitness<F>, out_buffer: &mut GeneratedValues<F>) {
+        let gate = Poseidon2SpongeGate::<F, D>::new(self.mode);
+        let local_wire = |column| Wire {
+            row: self.row,
+            column,
+        };
+
+        let mut state = [F::ZERO; WIDTH];
+        for (i, s) in state[..SPONGE_RATE].iter_mut().enumerate() {
+            *s = witness.get_wire(local_wire(gate.wire_block(i)));
+            if self.mode == AbsorptionMode::Add {
+                *s += witness.get_wire(local_wire(gate.wire_rate(i)));
+            }
+        }
+        for (i, s) in state[SPONGE_RATE..].iter_mut().enumerate() {
+            *s = witness.get_wire(local_wire(Poseidon2SpongeGate::<F, D>::wire_capacity(i)));
+        }
+
+        let state = gate.rounds().generate(self.row, state, out_buffer);
+
+        for (i, s) in state.into_iter().enumerate() {
+            out_buffer.set_wire(local_wire(gate.wire_output(i)), s);
+        }
+    }
+}
+
+/// Hashes `inputs` in a circuit with a Poseidon2 sponge, using one
+/// [`Poseidon2SpongeGate`] per absorbed block. The result matches
+/// [`AbsorptionMode::hash_no_pad`].
+///
+/// Inputs fitting in a single block are hashed with `hash_n_to_hash_no_pad`
+/// instead, as both modes agree on them and there is no state to carry over.
+pub fn hash_n_to_hash_no_pad_sponge<F: RichField + Extendable<D> + Poseidon2, const D: usize>(
+    builder: &mut CircuitBuilder<F, D>,
+    mode: AbsorptionMode,
+    inputs: Vec<Target>,
+) -> HashOutTarget {
+    if inputs.len() <= SPONGE_RATE {
+        return builder.hash_n_to_hash_no_pad::<Poseidon2Hash>(inputs);
+    }
+
+    let gate = Poseidon2SpongeGate::<F, D>::new(mode);
+    let zero = builder.zero();
+    let mut state = [zero; WIDTH];
+    for chunk in inputs.chunks(SPONGE_RATE) {
+        let row = builder.add_gate(gate.clone(), vec![]);
+
+        for (i, &s) in state[SPONGE_RATE..].iter().enumerate() {
+            let wire = Target::wire(row, Poseidon2SpongeGate::<F, D>::wire_capacity(i));
+            builder.connect(s, wire);
+        }
+        if mode == AbsorptionMode::Add {
+            for (i, &s) in state[..SPONGE_RATE].iter().enumerate() {
+                builder.connect(s, Target::wire(row, gate.wire_rate(i)));
+            }
+        }
+        // A partial last block leaves the rest of the rate untouched.
+        for (i, &s) in state[..SPONGE_RATE].iter().enumerate() {
+            let input = match (chunk.get(i), mode) {
+                (Some(&input), _) => input,
+                (None, AbsorptionMode::Overwrite) => s,
+                (None, AbsorptionMode::Add) => zero,
+            };
+            builder.connect(input, Target::wire(row, gate.wire_block(i)));
+        }
+
+        state = core::array::from_fn(|i| Target::wire(row, gate.wire_output(i)));
+    }
+
+    HashOutTarget::from_partial(&state[..NUM_HASH_OUT_ELTS], zero)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use plonky2::gates::gate_testing::{test_eval_fns, test_low_degree};
+    use plonky2::iop::witness::{PartialWitness, WitnessWrite};
+    use plonky2::plonk::circuit_builder::CircuitBuilder;
+    use plonky2::plonk::circuit_data::CircuitConfig;
+    use plonky2::plonk::config::{GenericConfig, Hasher};
+    use plonky2_field::goldilocks_field::GoldilocksField;
+    use plonky2_field::types::Sample;
+
+    use crate::config::Poseidon2GoldilocksConfig;
+    use crate::gate::poseidon2_sponge::{
+        hash_n_to_hash_no_pad_sponge, AbsorptionMode, Poseidon2SpongeGate,
+    };
+    use crate::poseidon2_hash::poseidon2::Poseidon2Hash;
+
+    #[test]
+    fn wire_indices() {
+        type Gate = Poseidon2SpongeGate<GoldilocksField, 2>;
+        let overwrite = Gate::new(AbsorptionMode::Overwrite);
+        assert_eq!(overwrite.wire_block(0), 4);
+        assert_eq!(overwrite.wire_output(11), 23);
+        assert_eq!(plonky2::gates::gate::Gate::num_wires(&overwrite), 130);
+
+        let add = Gate::new(AbsorptionMode::Add);
+        assert_eq!(add.wire_rate(0), 4);
+        assert_eq!(add.wire_block(0), 12);
+        assert_eq!(add.wire_output(11), 31);
+        assert_eq!(plonky2::gates::gate::Gate::num_wires(&add), 138);
+    }
+
+    #[test]
+    fn low_degree() {
+        type F = GoldilocksField;
+        test_low_degree(Poseidon2SpongeGate::<F, 4>::new(AbsorptionMode::Overwrite));
+        test_low_degree(Poseidon2SpongeGate::<F, 4>::new(AbsorptionMode::Add));
+    }
+
+    #[test]
+    fn eval_fns() -> Result<()> {
+        const D: usize = 2;
+        type C = Poseidon2GoldilocksConfig;
+        type F = <C as GenericConfig<D>>::F;
+        test_eval_fns::<F, C, _, D>(Poseidon2SpongeGate::<F, D>::new(AbsorptionMode::Overwrite))?;
+        test_eval_fns::<F, C, _, D>(Poseidon2SpongeGate::<F, D>::new(AbsorptionMode::Add))
+    }
+
+    #[test]
+    fn overwrite_matches_hash_no_pad() {
+        type F = GoldilocksField;
+        for n in [0, 5, 8, 13, 24] {
+            let inputs = F::rand_vec(n);
+            assert_eq!(
+                AbsorptionMode::Overwrite.hash_no_pad(&inputs),
+                Poseidon2Hash::hash_no_pad(&inputs)
+            );
+        }
+    }
+
+    fn check_sponge_circuit(mode: AbsorptionMode, num_wires: usize) -> Result<()> {
+        const D: usize = 2;
+        type C = Poseidon2GoldilocksConfig;
+        type F = <C as GenericConfig<D>>::F;
+
+        let config = CircuitConfig {
+            num_wires,
+            ..CircuitConfig::standard_recursion_config()
+        };
+        let mut builder = CircuitBuilder::<F, D>::new(config);
+        let mut pw = PartialWitness::new();
+        let mut expected = Vec::new();
+        for n in [3, 8, 16, 21, 50] {
+            let inputs = F::rand_vec(n);
+            let inputs_t = builder.add_virtual_targets(n);
+            pw.set_target_arr(&inputs_t, &inputs);
+            let hash = hash_n_to_hash_no_pad_sponge(&mut builder, mode, inputs_t);
+            builder.register_public_inputs(&hash.elements);
+            expected.extend(mode.hash_no_pad(&inputs).elements);
+        }
+        let data = builder.build::<C>();
+        let proof = data.prove(pw)?;
+        assert_eq!(proof.public_inputs, expected);
+        data.verify(proof)
+    }
+
+    #[test]
+    fn sponge_overwrite() -> Result<()> {
+        check_sponge_circuit(AbsorptionMode::Overwrite, 135)
+    }
+
+    #[test]
+    fn sponge_add() -> Result<()> {
+        check_sponge_circuit(AbsorptionMode::Add, 143)
+    }
+
+    #[test]
+    fn sponge_add_differs_from_overwrite() {
+        type F = GoldilocksField;
+        let inputs = F::rand_vec(20);
+        assert_ne!(
+            AbsorptionMode::Add.hash_no_pad(&inputs),
+            AbsorptionMode::Overwrite.hash_no_pad(&inputs)
+        );
+    }
+}
@@ -1,3 +1,7 @@
+pub mod gadgets;
 pub mod gate;
 pub mod poseidon2_hash;
-pub mod config;
\ No newline at end of file
+pub mod config;
+pub mod recursion;
+pub mod serialization;
+pub mod stark;
\ No newline at end of file
@@ -0,0 +1,358 @@
+//! Arithmetic in the scalar field of the BN254 curve, as needed to run the
+//! Poseidon2 permutation over it. Elements are stored in Montgomery form as
+//! four little-endian 64-bit limbs.
+
+use core::fmt::{self, Debug, Display, Formatter};
+use core::ops::{Add, Mul, Sub};
+
+/// The order of the BN254 scalar field,
+/// `0x30644e72e131a029b85045b68181585d2833e84879b9709143e1f593f0000001`.
+pub const MODULUS: [u64; 4] = [
+    0x43e1f593f0000001,
+    0x2833e84879b97091,
+    0xb85045b68181585d,
+    0x30644e72e131a029,
+];
+
+/// `-MODULUS^-1 mod 2^64`.
+const INV: u64 = 0xc2e1f593efffffff;
+
+/// `2^512 mod MODULUS`, used to convert into Montgomery form.
+const R2: [u64; 4] = [
+    0x1bb8e645ae216da7,
+    0x53fe3ab1e35c59e3,
+    0x8c49833d53bb8085,
+    0x0216d0b17f4e44a5,
+];
+
+/// An element of the BN254 scalar field.
+#[derive(Copy, Clone, Default, Eq, PartialEq, Hash)]
+pub struct Bn254Fr([u64; 4]);
+
+impl Bn254Fr {
+    pub const ZERO: Self = Self([0; 4]);
+
+    /// Converts canonical little-endian limbs, which must be smaller than the
+    /// modulus, into a field element.
+    pub const fn from_limbs(limbs: [u64; 4]) -> Self {
+        assert!(lt(&limbs, &MODULUS), "Non-canonical BN254 element");
+        Self(mont_mul(&limbs, &R2))
+    }
+
+    /// Converts canonical little-endian limbs into a field element, or returns
+    /// `None` if they are not smaller than the modulus.
+    pub fn try_from_limbs(limbs: [u64; 4]) -> Option<Self> {
+        lt(&limbs, &MODULUS).then(|| Self::from_limbs(limbs))
+    }
+
+    /// The canonical little-endian limbs of this element.
+    pub const fn to_limbs(&self) -> [u64; 4] {
+        mont_mul(&self.0, &[1, 0, 0, 0])
+    }
+
+    pub fn from_u64(value: u64) -> Self {
+        Self::from_limbs([value, 0, 0, 0])
+    }
+
+    /// The canonical little-endian encoding of this element.
+    pub fn to_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0; 32];
+        for (chunk, limb) in bytes.chunks_exact_mut(8).zip(self.to_limbs()) {
+            chunk.copy_from_slice(&limb.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Parses a canonical little-endian encoding.
+    pub fn from_bytes(bytes: &[u8; 32]) -> Option<Self> {
+        let limbs = core::array::from_fn(|i| {
+            u64::from_le_bytes(bytes[8 * i..8 * (i + 1)].try_into().unwrap())
+        });
+        Self::try_from_limbs(limbs)
+    }
+
+    /// The canonical value in decimal, without leading zeros, e.g. `42`.
+    pub fn to_decimal_string(&self) -> String {
+        const TEN_19: u128 = 10_000_000_000_000_000_000;
+        let mut limbs = self.to_limbs();
+        // Base 10^19 digits, least significant first.
+        let mut chunks = Vec::new();
+        loop {
+            let mut rem = 0u128;
+            for limb in limbs.iter_mut().rev() {
+                let cur = (rem << 64) | *limb as u128;
+                *limb = (cur / TEN_19) as u64;
+                rem = cur % TEN_19;
+            }
+            chunks.push(rem as u64);
+            if limbs == [0; 4] {
+                break;
+            }
+        }
+        let mut s = chunks.pop().unwrap().to_string();
+        for chunk in chunks.iter().rev() {
+            s.push_str(&format!("{:019}", chunk));
+        }
+        s
+    }
+
+    /// Parses a canonical value in decimal, as written by `to_decimal_string`.
+    pub fn from_decimal_str(s: &str) -> Option<Self> {
+        if s.is_empty() || (s.len() > 1 && s.starts_with('0')) {
+            return None;
+        }
+        let mut limbs = [0u64; 4];
+        for byte in s.bytes() {
+            if !byte.is_ascii_digit() {
+                return None;
+            }
+            let mut carry = (byte - b'0') as u128;
+            for limb in limbs.iter_mut() {
+                let cur = *limb as u128 * 10 + carry;
+                *limb = cur as u64;
+                carry = cur >> 64;
+            }
+            if carry != 0 {
+                return None;
+            }
+        }
+        Self::try_from_limbs(limbs)
+    }
+
+    pub fn square(&self) -> Self {
+        *self * *self
+    }
+
+    /// The Poseidon2 S-box, `x^5`.
+    pub fn pow5(&self) -> Self {
+        self.square().square() * *self
+    }
+}
+
+impl Add for Bn254Fr {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        // Both operands are below `MODULUS < 2^254`, so the sum cannot overflow.
+        let (sum, _) = add_limbs(&self.0, &rhs.0);
+        Self(reduce_once(sum))
+    }
+}
+
+impl Sub for Bn254Fr {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        let (diff, borrow) = sub_limbs(&self.0, &rhs.0);
+        if borrow {
+            Self(add_limbs(&diff, &MODULUS).0)
+        } else {
+            Self(diff)
+        }
+    }
+}
+
+impl Mul for Bn254Fr {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self {
+        Self(mont_mul(&self.0, &rhs.0))
+    }
+}
+
+impl Debug for Bn254Fr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+/// Formats the canonical value as big-endian hexadecimal, e.g. `0x2a`.
+impl Display for Bn254Fr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let limbs = self.to_limbs();
+        match limbs.iter().rposition(|&limb| limb != 0) {
+            None => write!(f, "0x0"),
+            Some(top) => {
+                write!(f, "0x{:x}", limbs[top])?;
+                for limb in limbs[..top].iter().rev() {
+                    write!(f, "{:016x}", limb)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+const fn lt(a: &[u64; 4], b: &[u64; 4]) -> bool {
+    let mut i = 4;
+    while i > 0 {
+        i -= 1;
+        if a[i] != b[i] {
+            return a[i] < b[i];
+        }
+    }
+    false
+}
+
+const fn add_limbs(a: &[u64; 4], b: &[u64; 4]) -> ([u64; 4], bool) {
+    let mut res = [0; 4];
+    let mut carry = 0u64;
+    let mut i = 0;
+    while i < 4 {
+        let s = a[i] as u128 + b[i] as u128 + carry as u128;
+        res[i] = s as u64;
+        carry = (s >> 64) as u64;
+        i += 1;
+    }
+    (res, carry != 0)
+}
+
+const fn sub_limbs(a: &[u64; 4], b: &[u64; 4]) -> ([u64; 4], bool) {
+    let mut res = [0; 4];
+    let mut borrow = false;
+    let mut i = 0;
+    while i < 4 {
+        let (d, b1) = a[i].overflowing_sub(b[i]);
+        let (d, b2) = d.overflowing_sub(borrow as u64);
+        res[i] = d;
+        borrow = b1 || b2;
+        i += 1;
+    }
+    (res, borrow)
+}
+
+/// Maps `[0, 2 * MODULUS)` to `[0, MODULUS)`.
+const fn reduce_once(a: [u64; 4]) -> [u64; 4] {
+    if lt(&a, &MODULUS) {
+        a
+    } else {
+        sub_limbs(&a, &MODULUS).0
+    }
+}
+
+/// Montgomery multiplication, `a * b / 2^256 mod MODULUS`.
+const fn mont_mul(a: &[u64; 4], b: &[u64; 4]) -> [u64; 4] {
+    let mut t = [0u64; 5];
+    let mut i = 0;
+    while i < 4 {
+        // t += a * b[i]
+        let mut carry = 0u64;
+        let mut j = 0;
+        while j < 4 {
+            let s = t[j] as u128 + a[j] as u128 * b[i] as u128 + carry as u128;
+            t[j] = s as u64;
+            carry = (s >> 64) as u64;
+            j += 1;
+        }
+        let s = t[4] as u128 + carry as u128;
+        t[4] = s as u64;
+        let top = (s >> 64) as u64;
+
+        // t = (t + m * MODULUS) / 2^64, with m chosen to clear the lowest limb.
+        let m = t[0].wrapping_mul(INV);
+        let s = t[0] as u128 + m as u128 * MODULUS[0] as u128;
+        let mut carry = (s >> 64) as u64;
+        let mut j = 1;
+        while j < 4 {
+            let s = t[j] as u128 + m as u128 * MODULUS[j] as u128 + carry as u128;
+            t[j - 1] = s as u64;
+            carry = (s >> 64) as u64;
+            j += 1;
+        }
+        let s = t[4] as u128 + carry as u128;
+        t[3] = s as u64;
+        t[4] = top + (s >> 64) as u64;
+        i += 1;
+    }
+    // The result is below `2 * MODULUS < 2^256`, so `t[4]` is zero.
+    reduce_once([t[0], t[1], t[2], t[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigUint;
+    use proptest::prelude::*;
+    use proptest::test_runner::{Config, TestRunner};
+
+    use crate::poseidon2_hash::bn254::{Bn254Fr, MODULUS};
+
+    fn modulus() -> BigUint {
+        to_big(MODULUS)
+    }
+
+    fn to_big(limbs: [u64; 4]) -> BigUint {
+        BigUint::from_slice(&limbs.map(|l| [l as u32, (l >> 32) as u32]).concat())
+    }
+
+    fn to_limbs(x: &BigUint) -> [u64; 4] {
+        let mut limbs = [0; 4];
+        for (limb, digit) in limbs.iter_mut().zip(x.iter_u64_digits()) {
+            *limb = digit;
+        }
+        limbs
+    }
+
+    /// Canonical integers: uniform, next to 0 and next to the modulus.
+    fn element() -> impl Strategy<Value = BigUint> {
+        prop_oneof![
+            any::<[u64; 4]>().prop_map(|limbs| to_big(limbs) % modulus()),
+            (0..16u64).prop_map(BigUint::from),
+            (1..=16u64).prop_map(|d| modulus() - d),
+        ]
+    }
+
+    /// Addition, subtraction and Montgomery multiplication, and the decimal
+    /// encoding, against big integers reduced modulo the order.
+    #[test]
+    fn arithmetic_matches_big_integers() {
+        let p = modulus();
+        let mut runner = TestRunner::new(Config::with_cases(256));
+        runner
+            .run(&(element(), element()), |(a, b)| {
+                let x = Bn254Fr::from_limbs(to_limbs(&a));
+                let y = Bn254Fr::from_limbs(to_limbs(&b));
+                prop_assert_eq!(x.to_limbs(), to_limbs(&a));
+                prop_assert_eq!((x + y).to_limbs(), to_limbs(&((&a + &b) % &p)));
+                prop_assert_eq!((x - y).to_limbs(), to_limbs(&((&a + &p - &b) % &p)));
+                prop_assert_eq!((x * y).to_limbs(), to_limbs(&((&a * &b) % &p)));
+                prop_assert_eq!(x.to_decimal_string(), a.to_str_radix(10));
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn arithmetic() {
+        let minus_one = Bn254Fr::from_limbs([MODULUS[0] - 1, MODULUS[1], MODULUS[2], MODULUS[3]]);
+        let one = Bn254Fr::from_u64(1);
+        assert_eq!(minus_one + one, Bn254Fr::ZERO);
+        assert_eq!(Bn254Fr::ZERO - one, minus_one);
+        assert_eq!(minus_one * minus_one, one);
+        assert_eq!(minus_one.to_limbs()[0], MODULUS[0] - 1);
+
+        // (2^64)^2 = 2^128.
+        let x = Bn254Fr::from_limbs([0, 1, 0, 0]);
+        assert_eq!((x * x).to_limbs(), [0, 0, 1, 0]);
+        assert_eq!(Bn254Fr::from_u64(3).pow5(), Bn254Fr::from_u64(243));
+
+        let bytes = minus_one.to_bytes();
+        assert_eq!(Bn254Fr::from_bytes(&bytes), Some(minus_one));
+        assert_eq!(Bn254Fr::try_from_limbs(MODULUS), None);
+        assert_eq!(Bn254Fr::from_u64(42).to_string(), "0x2a");
+
+        assert_eq!(Bn254Fr::ZERO.to_decimal_string(), "0");
+        assert_eq!(x.to_decimal_string(), "18446744073709551616");
+        assert_eq!(
+            minus_one.to_decimal_string(),
+            "21888242871839275222246405745257275088548364400416034343698204186575808495616"
+        );
+        for y in [Bn254Fr::ZERO, x, minus_one, x * x * x] {
+            assert_eq!(Bn254Fr::from_decimal_str(&y.to_decimal_string()), Some(y));
+        }
+        let modulus =
+            "21888242871839275222246405745257275088548364400416034343698204186575808495617";
+        for invalid in ["", "01", "-1", "0x2a", modulus] {
+            assert_eq!(Bn254Fr::from_decimal_str(invalid), None);
+        }
+    }
+}
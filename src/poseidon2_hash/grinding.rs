@@ -0,0 +1,342 @@
+//! Proof-of-work grinding with Poseidon2.
+//!
+//! Grinding searches for a witness which, absorbed into a duplex sponge right
+//! before a squeeze, yields a challenge with `pow_bits` leading zeros, as in
+//! the FRI proof of work of plonky2. Only the witness changes between
+//! candidates, so [`Poseidon2PowGrinder`] precomputes what does not depend on
+//! it: the first linear layer and round constants are affine in the witness,
+//! and only the challenge element of the last linear layer is computed. The
+//! candidates are split in chunks searched in parallel when the `parallel`
+//! feature is enabled. When `F::Packing` is vectorised, i.e. with AVX2 or
+//! AVX-512 (e.g. `RUSTFLAGS="-C target-cpu=native"`), each chunk is searched
+//! `F::Packing::WIDTH` candidates at a time, which on one core takes ~0.8x
+//! (AVX2) and ~0.4x (AVX-512) of the time of a permutation per candidate.
+//! Otherwise the precomputation saves about as much as the affine first layer
+//! costs, so each candidate is checked with a full permutation, as plonky2
+//! does.
+//!
+//! plonky2 0.2 grinds the FRI proof of work inside its prover, which offers no
+//! way to plug in another routine, so `CircuitData::prove` is not made faster:
+//! it still uses plonky2's search. [`grind_challenger`] and
+//! [`verify_challenger_pow`] apply the grinder to a [`Challenger`] of other
+//! protocols instead.
+
+use core::ops::Range;
+
+use anyhow::{ensure, Result};
+use plonky2::hash::hash_types::RichField;
+use plonky2::hash::hashing::PlonkyPermutation;
+use plonky2::iop::challenger::Challenger;
+use plonky2_field::packable::Packable;
+use plonky2_field::packed::PackedField;
+use plonky2_maybe_rayon::*;
+
+use crate::poseidon2_hash::poseidon2::{
+    Poseidon2, Poseidon2Hash, Poseidon2Permutation, ROUND_F_BEGIN, ROUND_F_END, ROUND_P, WIDTH,
+};
+
+/// Number of candidates searched by a parallel task.
+const CHUNK_SIZE: u64 = 1 << 12;
+
+/// Searches proof-of-work witnesses for a fixed sponge state.
+#[derive(Copy, Clone, Debug)]
+pub struct Poseidon2PowGrinder<F: Poseidon2> {
+    /// The sponge state the witness is written into.
+    duplex_state: [F; WIDTH],
+    /// The position of the witness in `duplex_state`.
+    position: usize,
+    /// The state after the first M_E layer and round constants, for a zero
+    /// witness.
+    offset: [F; WIDTH],
+    /// The first M_E layer applied to the unit vector at the position of the
+    /// witness.
+    slope: [F; WIDTH],
+}
+
+impl<F: RichField + Poseidon2> Poseidon2PowGrinder<F> {
+    /// The index of the challenge in the state, which is the last element of
+    /// the rate since challengers squeeze outputs from the end.
+    pub const CHALLENGE_INDEX: usize = Poseidon2Permutation::<F>::RATE - 1;
+
+    /// A grinder for witnesses written at `position` of `duplex_state`, the
+    /// sponge state in which the pending inputs have already been written.
+    pub fn new(duplex_state: [F; WIDTH], position: usize) -> Self {
+        assert!(position < Poseidon2Permutation::<F>::RATE);
+        let mut offset = duplex_state;
+        offset[position] = F::ZERO;
+        <F as Poseidon2>::matmul_external(&mut offset);
+        <F as Poseidon2>::constant_layer(&mut offset, 0);
+
+        let mut slope = [F::ZERO; WIDTH];
+        slope[position] = F::ONE;
+        <F as Poseidon2>::matmul_external(&mut slope);
+        Self {
+            duplex_state,
+            position,
+            offset,
+            slope,
+        }
+    }
+
+    /// The challenge squeezed after absorbing `witness`.
+    pub fn challenge(&self, witness: F) -> F {
+        let mut state: [F; WIDTH] =
+            core::array::from_fn(|i| self.offset[i] + witness * self.slope[i]);
+        <F as Poseidon2>::sbox_layer(&mut state);
+        <F as Poseidon2>::matmul_external(&mut state);
+
+        for round_ctr in 1..ROUND_F_BEGIN {
+            <F as Poseidon2>::constant_layer(&mut state, round_ctr);
+            <F as Poseidon2>::sbox_layer(&mut state);
+            <F as Poseidon2>::matmul_external(&mut state);
+        }
+        for r in 0..ROUND_P {
+            state[0] += F::from_canonical_u64(<F as Poseidon2>::RC12_MID[r]);
+            state[0] = <F as Poseidon2>::sbox_monomial(state[0]);
+            <F as Poseidon2>::matmul_internal(&mut state, &<F as Poseidon2>::MAT_DIAG12_M_1);
+        }
+        for round_ctr in ROUND_F_BEGIN..ROUND_F_END - 1 {
+            <F as Poseidon2>::constant_layer(&mut state, round_ctr);
+            <F as Poseidon2>::sbox_layer(&mut state);
+            <F as Poseidon2>::matmul_external(&mut state);
+        }
+        <F as Poseidon2>::constant_layer(&mut state, ROUND_F_END - 1);
+        <F as Poseidon2>::sbox_layer(&mut state);
+
+        // Row 7 of M_E = circ(2 * M4, M4, M4): the last row [1, 1, 4, 6] of
+        // M4 applied to every 4-element part, the second part counted twice.
+        debug_assert_eq!(Self::CHALLENGE_INDEX, 7);
+        let m4_last_row = |part: &[F]| {
+            part[0]
+                + part[1]
+                + part[2] * F::from_canonical_u64(4)
+                + part[3] * F::from_canonical_u64(6)
+        };
+        let parts = [0, 1, 2].map(|j| m4_last_row(&state[4 * j..4 * j + 4]));
+        parts[0] + parts[1].double() + parts[2]
+    }
+
+    /// Same as [`Self::challenge`], for each lane of `witnesses`.
+    pub fn challenges<P: PackedField<Scalar = F>>(&self, witnesses: P) -> P {
+        let mut state: [P; WIDTH] =
+            core::array::from_fn(|i| witnesses * self.slope[i] + self.offset[i]);
+        <F as Poseidon2>::sbox_layer_packed(&mut state);
+        <F as Poseidon2>::matmul_external_packed(&mut state);
+
+        for round_ctr in 1..ROUND_F_BEGIN {
+            <F as Poseidon2>::constant_layer_packed(&mut state, round_ctr);
+            <F as Poseidon2>::sbox_layer_packed(&mut state);
+            <F as Poseidon2>::matmul_external_packed(&mut state);
+        }
+        for r in 0..ROUND_P {
+            state[0] += F::from_canonical_u64(<F as Poseidon2>::RC12_MID[r]);
+            state[0] = <F as Poseidon2>::sbox_monomial_packed(state[0]);
+            <F as Poseidon2>::matmul_internal_packed(&mut state, &<F as Poseidon2>::MAT_DIAG12_M_1);
+        }
+        for round_ctr in ROUND_F_BEGIN..ROUND_F_END - 1 {
+            <F as Poseidon2>::constant_layer_packed(&mut state, round_ctr);
+            <F as Poseidon2>::sbox_layer_packed(&mut state);
+            <F as Poseidon2>::matmul_external_packed(&mut state);
+        }
+        <F as Poseidon2>::constant_layer_packed(&mut state, ROUND_F_END - 1);
+        <F as Poseidon2>::sbox_layer_packed(&mut state);
+
+        // See `challenge`.
+        let m4_last_row = |part: &[P]| {
+            part[0]
+                + part[1]
+                + part[2] * F::from_canonical_u64(4)
+                + part[3] * F::from_canonical_u64(6)
+        };
+        let parts = [0, 1, 2].map(|j| m4_last_row(&state[4 * j..4 * j + 4]));
+        parts[0] + parts[1].doubles() + parts[2]
+    }
+
+    /// Whether the challenge of `witness` has `pow_bits` leading zeros, with
+    /// the convention of plonky2's FRI proof of work.
+    pub fn is_valid(&self, witness: F, pow_bits: u32) -> bool {
+        leading_zeros(self.challenge(witness)) >= min_leading_zeros::<F>(pow_bits)
+    }
+
+    /// Finds a witness whose challenge has `pow_bits` leading zeros. Not
+    /// necessarily the smallest one when searching in parallel.
+    pub fn grind(&self, pow_bits: u32) -> F {
+        let min_leading_zeros = min_leading_zeros::<F>(pow_bits);
+        let num_chunks = F::NEG_ONE.to_canonical_u64() / CHUNK_SIZE + 1;
+        (0..num_chunks)
+            .into_par_iter()
+            .map(|chunk| {
+                let start = chunk * CHUNK_SIZE;
+                let end = start.saturating_add(CHUNK_SIZE).min(F::ORDER);
+                if end - start == CHUNK_SIZE && <F as Packable>::Packing::WIDTH > 1 {
+                    self.grind_chunk_packed(start, min_leading_zeros)
+                } else {
+                    self.grind_chunk_permutation(start..end, min_leading_zeros)
+                }
+            })
+            .find_any(Option::is_some)
+            .flatten()
+            .expect("Proof of work failed. This is highly unlikely!")
+    }
+
+    /// Searches the candidates of `range` with a full permutation each, as
+    /// plonky2's FRI proof of work does.
+    fn grind_chunk_permutation(&self, range: Range<u64>, min_leading_zeros: u32) -> Option<F> {
+        range.map(F::from_canonical_u64).find(|&witness| {
+            let mut perm = Poseidon2Permutation::new(self.duplex_state);
+            perm.set_elt(witness, self.position);
+            perm.permute();
+            leading_zeros(perm.squeeze()[Self::CHALLENGE_INDEX]) >= min_leading_zeros
+        })
+    }
+
+    /// Searches the `CHUNK_SIZE` candidates from `start`, `F::Packing::WIDTH`
+    /// at a time.
+    fn grind_chunk_packed(&self, start: u64, min_leading_zeros: u32) -> Option<F> {
+        let width = <F as Packable>::Packing::WIDTH as u64;
+        debug_assert_eq!(CHUNK_SIZE % width, 0);
+        let lanes = (0..width)
+            .map(|lane| F::from_canonical_u64(start + lane))
+            .collect::<Vec<_>>();
+        let mut witnesses = *<F as Packable>::Packing::from_slice(&lanes);
+        let step = F::from_canonical_u64(width);
+        for batch in (start..start + CHUNK_SIZE).step_by(width as usize) {
+            let challenges = self.challenges(witnesses);
+            let lane = challenges
+                .as_slice()
+                .iter()
+                .position(|&c| leading_zeros(c) >= min_leading_zeros);
+            if let Some(lane) = lane {
+                return Some(F::from_canonical_u64(batch + lane as u64));
+            }
+            witnesses += step;
+        }
+        None
+    }
+}
+
+/// Compacts `challenger`, then finds and observes a witness whose challenge,
+/// which is drawn from `challenger`, has `pow_bits` leading zeros.
+pub fn grind_challenger<F: RichField + Poseidon2>(
+    challenger: &mut Challenger<F, Poseidon2Hash>,
+    pow_bits: u32,
+) -> F {
+    let state = challenger.compact();
+    let grinder = Poseidon2PowGrinder::new(state.as_ref().try_into().unwrap(), 0);
+    let witness = grinder.grind(pow_bits);
+    challenger.observe_element(witness);
+    let challenge = challenger.get_challenge();
+    debug_assert!(leading_zeros(challenge) >= min_leading_zeros::<F>(pow_bits));
+    witness
+}
+
+/// Checks a witness produced by [`grind_challenger`] on the same transcript.
+pub fn verify_challenger_pow<F: RichField + Poseidon2>(
+    challenger: &mut Challenger<F, Poseidon2Hash>,
+    witness: F,
+    pow_bits: u32,
+) -> Result<()> {
+    challenger.compact();
+    challenger.observe_element(witness);
+    let challenge = challenger.get_challenge();
+    ensure!(
+        leading_zeros(challenge) >= min_leading_zeros::<F>(pow_bits),
+        "Invalid proof-of-work witness"
+    );
+    Ok(())
+}
+
+fn min_leading_zeros<F: RichField>(pow_bits: u32) -> u32 {
+    pow_bits + F::ORDER.leading_zeros()
+}
+
+fn leading_zeros<F: RichField>(challenge: F) -> u32 {
+    challenge.to_canonical_u64().leading_zeros()
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use plonky2::hash::hashing::PlonkyPermutation;
+    use plonky2::iop::challenger::Challenger;
+    use plonky2_field::goldilocks_field::GoldilocksField;
+    use plonky2_field::packable::Packable;
+    use plonky2_field::packed::PackedField;
+    use plonky2_field::types::{Field, Field64, PrimeField64, Sample};
+
+    use crate::poseidon2_hash::grinding::{
+        grind_challenger, verify_challenger_pow, Poseidon2PowGrinder, CHUNK_SIZE,
+    };
+    use crate::poseidon2_hash::poseidon2::{Poseidon2Hash, Poseidon2Permutation, WIDTH};
+
+    type F = GoldilocksField;
+
+    #[test]
+    fn challenge_matches_permutation() {
+        let state = F::rand_array::<WIDTH>();
+        for position in 0..Poseidon2Permutation::<F>::RATE {
+            let grinder = Poseidon2PowGrinder::new(state, position);
+            for witness in [F::ZERO, F::ONE, F::NEG_ONE, F::rand()] {
+                let mut perm = Poseidon2Permutation::new(state);
+                perm.set_elt(witness, position);
+                perm.permute();
+                assert_eq!(grinder.challenge(witness), *perm.squeeze().last().unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn challenges_match_challenge() {
+        type P = <F as Packable>::Packing;
+        let grinder = Poseidon2PowGrinder::new(F::rand_array::<WIDTH>(), 2);
+        let witnesses = F::rand_vec(P::WIDTH);
+        let challenges = grinder.challenges(*P::from_slice(&witnesses));
+        for (&witness, &challenge) in witnesses.iter().zip(challenges.as_slice()) {
+            assert_eq!(challenge, grinder.challenge(witness));
+        }
+        assert_eq!(
+            grinder.challenges(witnesses[0]),
+            grinder.challenge(witnesses[0])
+        );
+    }
+
+    #[test]
+    fn grind() {
+        let grinder = Poseidon2PowGrinder::new(F::rand_array::<WIDTH>(), 3);
+        let witness = grinder.grind(12);
+        assert!(grinder.is_valid(witness, 12));
+        assert!(grinder.challenge(witness).to_canonical_u64() < 1 << 52);
+    }
+
+    #[test]
+    fn searches_match_challenge() {
+        let grinder = Poseidon2PowGrinder::new(F::rand_array::<WIDTH>(), 0);
+        let min_leading_zeros = 8 + F::ORDER.leading_zeros();
+        let scalar = (0..CHUNK_SIZE).map(F::from_canonical_u64).find(|&w| {
+            grinder.challenge(w).to_canonical_u64().leading_zeros() >= min_leading_zeros
+        });
+        assert_eq!(grinder.grind_chunk_packed(0, min_leading_zeros), scalar);
+        assert_eq!(
+            grinder.grind_chunk_permutation(0..CHUNK_SIZE, min_leading_zeros),
+            scalar
+        );
+    }
+
+    #[test]
+    fn challenger() -> Result<()> {
+        let mut prover = Challenger::<F, Poseidon2Hash>::new();
+        let mut verifier = Challenger::<F, Poseidon2Hash>::new();
+        let inputs = F::rand_vec(5);
+        prover.observe_elements(&inputs);
+        verifier.observe_elements(&inputs);
+
+        let witness = grind_challenger(&mut prover, 10);
+        verify_challenger_pow(&mut verifier.clone(), witness, 10)?;
+        assert!(verify_challenger_pow(&mut verifier.clone(), witness + F::ONE, 10).is_err());
+        verify_challenger_pow(&mut verifier, witness, 10)?;
+
+        // Both transcripts continue identically.
+        assert_eq!(prover.get_challenge(), verifier.get_challenge());
+        Ok(())
+    }
+}
@@ -0,0 +1,395 @@
+//! Known-answer tests of Poseidon2 over the Goldilocks field.
+//!
+//! The vectors are stored as JSON files in [`KAT_DIR`], one per primitive,
+//! with field elements as hexadecimal strings. Vectors whose source is
+//! [`HORIZEN_LABS`] come from the reference implementation at
+//! <https://github.com/HorizenLabs/poseidon2> and are never regenerated. The
+//! others pin the outputs of this crate, and are regenerated with
+//! `cargo run --release --bin regen_kat` when the parameters change.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, ensure, Result};
+use plonky2::hash::hash_types::HashOut;
+use plonky2::hash::merkle_tree::MerkleTree;
+use plonky2::plonk::config::Hasher;
+use plonky2_field::goldilocks_field::GoldilocksField;
+use plonky2_field::types::{Field, PrimeField64};
+use serde::{Deserialize, Serialize};
+
+use crate::poseidon2_hash::poseidon2::{Poseidon2, Poseidon2Hash, WIDTH};
+
+type F = GoldilocksField;
+
+/// Directory of the vector files of this crate.
+pub const KAT_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_vectors");
+
+pub const PERMUTATION_FILE: &str = "poseidon2_goldilocks_permutation.json";
+pub const HASH_NO_PAD_FILE: &str = "poseidon2_goldilocks_hash_no_pad.json";
+pub const TWO_TO_ONE_FILE: &str = "poseidon2_goldilocks_two_to_one.json";
+pub const MERKLE_ROOT_FILE: &str = "poseidon2_goldilocks_merkle_root.json";
+
+pub const HORIZEN_LABS: &str = "HorizenLabs/poseidon2";
+pub const GENERATED: &str = "generated";
+
+/// Permutation vectors of the HorizenLabs reference implementation, as
+/// `(input, output)`.
+const HORIZEN_LABS_PERMUTATION: [([u64; WIDTH], [u64; WIDTH]); 1] = [(
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+    [
+        0x01eaef96bdf1c0c1,
+        0x1f0d2cc525b2540c,
+        0x6282c1dfe1e0358d,
+        0xe780d721f698e1e6,
+        0x280c0b6f753d833b,
+        0x1b942dd5023156ab,
+        0x43f0df3fcccb8398,
+        0xe8e8190585489025,
+        0x56bdbf72f77ada22,
+        0x7911c32bf9dcd705,
+        0xec467926508fbe67,
+        0x6a50450ddf85a6ed,
+    ],
+)];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PermutationVector {
+    pub source: String,
+    #[serde(with = "hex")]
+    pub input: Vec<F>,
+    #[serde(with = "hex")]
+    pub output: Vec<F>,
+}
+
+/// A vector of `Poseidon2Hash::hash_no_pad`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HashVector {
+    pub source: String,
+    #[serde(with = "hex")]
+    pub input: Vec<F>,
+    #[serde(with = "hex")]
+    pub output: Vec<F>,
+}
+
+/// A vector of `Poseidon2Hash::two_to_one`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TwoToOneVector {
+    pub source: String,
+    #[serde(with = "hex")]
+    pub left: Vec<F>,
+    #[serde(with = "hex")]
+    pub right: Vec<F>,
+    #[serde(with = "hex")]
+    pub output: Vec<F>,
+}
+
+/// The cap of a `MerkleTree<F, Poseidon2Hash>`, i.e. its root when
+/// `cap_height` is 0.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleRootVector {
+    pub source: String,
+    #[serde(with = "hex_nested")]
+    pub leaves: Vec<Vec<F>>,
+    pub cap_height: usize,
+    #[serde(with = "hex_nested")]
+    pub cap: Vec<Vec<F>>,
+}
+
+/// All the known-answer tests, as stored in the vector files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KnownAnswerTests {
+    pub permutation: Vec<PermutationVector>,
+    pub hash_no_pad: Vec<HashVector>,
+    pub two_to_one: Vec<TwoToOneVector>,
+    pub merkle_root: Vec<MerkleRootVector>,
+}
+
+impl KnownAnswerTests {
+    /// Loads the vector files of `dir`.
+    pub fn load(dir: &Path) -> Result<Self> {
+        Ok(Self {
+            permutation: read(dir, PERMUTATION_FILE)?,
+            hash_no_pad: read(dir, HASH_NO_PAD_FILE)?,
+            two_to_one: read(dir, TWO_TO_ONE_FILE)?,
+            merkle_root: read(dir, MERKLE_ROOT_FILE)?,
+        })
+    }
+
+    /// Loads the vector files of this crate, from [`KAT_DIR`].
+    pub fn load_default() -> Result<Self> {
+        Self::load(Path::new(KAT_DIR))
+    }
+
+    /// Writes the vector files into `dir`, creating it if needed.
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        write(dir, PERMUTATION_FILE, &self.permutation)?;
+        write(dir, HASH_NO_PAD_FILE, &self.hash_no_pad)?;
+        write(dir, TWO_TO_ONE_FILE, &self.two_to_one)?;
+        write(dir, MERKLE_ROOT_FILE, &self.merkle_root)
+    }
+
+    /// The reference vectors, followed by vectors computed by this crate on
+    /// fixed inputs.
+    pub fn generate() -> Self {
+        let mut rng = SplitMix64(0x706f736569646f6e);
+
+        let reference = HORIZEN_LABS_PERMUTATION
+            .iter()
+            .map(|(input, output)| PermutationVector {
+                source: HORIZEN_LABS.to_string(),
+                input: input.map(F::from_canonical_u64).to_vec(),
+                output: output.map(F::from_canonical_u64).to_vec(),
+            });
+        let permutation_inputs = [
+            vec![F::ZERO; WIDTH],
+            vec![F::NEG_ONE; WIDTH],
+            rng.elements(WIDTH),
+            rng.elements(WIDTH),
+        ];
+        let permutation = reference
+            .chain(permutation_inputs.into_iter().map(|input| {
+                let output = F::poseidon2(input.clone().try_into().unwrap());
+                PermutationVector {
+                    source: GENERATED.to_string(),
+                    input,
+                    output: output.to_vec(),
+                }
+            }))
+            .collect();
+
+        let hash_no_pad = [0, 1, 4, 7, 8, 9, 16, 25]
+            .into_iter()
+            .map(|len| {
+                let input = rng.elements(len);
+                let output = Poseidon2Hash::hash_no_pad(&input).elements.to_vec();
+                HashVector {
+                    source: GENERATED.to_string(),
+                    input,
+                    output,
+                }
+            })
+            .collect();
+
+        let two_to_one = (0..3)
+            .map(|_| {
+                let left = rng.elements(4);
+                let right = rng.elements(4);
+                let output = Poseidon2Hash::two_to_one(hash_out(&left), hash_out(&right));
+                TwoToOneVector {
+                    source: GENERATED.to_string(),
+                    left,
+                    right,
+                    output: output.elements.to_vec(),
+                }
+            })
+            .collect();
+
+        let merkle_root = [(1, 4, 0), (2, 1, 0), (8, 7, 0), (16, 4, 0), (16, 9, 2)]
+            .into_iter()
+            .map(|(num_leaves, leaf_len, cap_height)| {
+                let leaves = (0..num_leaves)
+                    .map(|_| rng.elements(leaf_len))
+                    .collect::<Vec<_>>();
+                let tree = MerkleTree::<F, Poseidon2Hash>::new(leaves.clone(), cap_height);
+                MerkleRootVector {
+                    source: GENERATED.to_string(),
+                    leaves,
+                    cap_height,
+                    cap: tree.cap.0.iter().map(|h| h.elements.to_vec()).collect(),
+                }
+            })
+            .collect();
+
+        Self {
+            permutation,
+            hash_no_pad,
+            two_to_one,
+            merkle_root,
+        }
+    }
+
+    /// Checks every vector against the native implementation.
+    pub fn check_native(&self) -> Result<()> {
+        for (i, v) in self.permutation.iter().enumerate() {
+            let input: [F; WIDTH] = v
+                .input
+                .clone()
+                .try_into()
+                .map_err(|_| anyhow!("Permutation vector {} has a wrong width", i))?;
+            ensure!(
+                F::poseidon2(input).to_vec() == v.output,
+                "Permutation vector {} ({}) does not match",
+                i,
+                v.source
+            );
+        }
+        for (i, v) in self.hash_no_pad.iter().enumerate() {
+            ensure!(
+                Poseidon2Hash::hash_no_pad(&v.input).elements.to_vec() == v.output,
+                "hash_no_pad vector {} ({}) does not match",
+                i,
+                v.source
+            );
+        }
+        for (i, v) in self.two_to_one.iter().enumerate() {
+            let output = Poseidon2Hash::two_to_one(hash_out(&v.left), hash_out(&v.right));
+            ensure!(
+                output.elements.to_vec() == v.output,
+                "two_to_one vector {} ({}) does not match",
+                i,
+                v.source
+            );
+        }
+        for (i, v) in self.merkle_root.iter().enumerate() {
+            let tree = MerkleTree::<F, Poseidon2Hash>::new(v.leaves.clone(), v.cap_height);
+            let cap = tree
+                .cap
+                .0
+                .iter()
+                .map(|h| h.elements.to_vec())
+                .collect::<Vec<_>>();
+            ensure!(
+                cap == v.cap,
+                "Merkle root vector {} ({}) does not match",
+                i,
+                v.source
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Converts 4 elements into a digest.
+pub fn hash_out(elements: &[F]) -> HashOut<F> {
+    HashOut::from_vec(elements.to_vec())
+}
+
+fn read<T: for<'de> Deserialize<'de>>(dir: &Path, file: &str) -> Result<T> {
+    let path = dir.join(file);
+    let json =
+        fs::read_to_string(&path).map_err(|e| anyhow!("Cannot read {}: {}", path.display(), e))?;
+    Ok(serde_json::from_str(&json)?)
+}
+
+fn write<T: Serialize>(dir: &Path, file: &str, vectors: &T) -> Result<()> {
+    let mut json = serde_json::to_string_pretty(vectors)?;
+    json.push('\n');
+    fs::write(dir.join(file), json)?;
+    Ok(())
+}
+
+/// The SplitMix64 generator, to derive the inputs of the generated vectors
+/// deterministically.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn elements(&mut self, n: usize) -> Vec<F> {
+        (0..n)
+            .map(|_| F::from_noncanonical_u64(self.next_u64()))
+            .collect()
+    }
+}
+
+/// Serializes field elements as `0x`-prefixed hexadecimal strings, which,
+/// unlike JSON numbers above `2^53`, every JSON parser reads exactly.
+mod hex {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::{from_hex, to_hex, F};
+
+    pub fn serialize<S: Serializer>(elements: &[F], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(elements.iter().map(to_hex))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<F>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|s| from_hex(s).map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+/// [`hex`] for lists of lists of field elements.
+mod hex_nested {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::{from_hex, to_hex, F};
+
+    pub fn serialize<S: Serializer>(lists: &[Vec<F>], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            lists
+                .iter()
+                .map(|l| l.iter().map(to_hex).collect::<Vec<_>>()),
+        )
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Vec<F>>, D::Error> {
+        Vec::<Vec<String>>::deserialize(deserializer)?
+            .iter()
+            .map(|l| {
+                l.iter()
+                    .map(|s| from_hex(s).map_err(D::Error::custom))
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+pub(crate) fn to_hex<F: PrimeField64>(x: &F) -> String {
+    format!("{:#018x}", x.to_canonical_u64())
+}
+
+pub(crate) fn from_hex<F: PrimeField64>(s: &str) -> Result<F> {
+    let digits = s
+        .strip_prefix("0x")
+        .ok_or_else(|| anyhow!("Expected a 0x prefix in {}", s))?;
+    let value = u64::from_str_radix(digits, 16)?;
+    ensure!(value < F::ORDER, "Non-canonical field element {}", s);
+    Ok(F::from_canonical_u64(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use crate::poseidon2_hash::kat::{from_hex, KnownAnswerTests, F, HORIZEN_LABS};
+
+    #[test]
+    fn native() -> Result<()> {
+        let kats = KnownAnswerTests::load_default()?;
+        assert!(kats.permutation.iter().any(|v| v.source == HORIZEN_LABS));
+        kats.check_native()
+    }
+
+    #[test]
+    fn files_up_to_date() -> Result<()> {
+        // Fails when the generated vectors change, e.g. with the parameters;
+        // run `cargo run --release --bin regen_kat` and review the diff.
+        assert_eq!(
+            KnownAnswerTests::load_default()?,
+            KnownAnswerTests::generate()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(from_hex::<F>("0x2a").unwrap().0, 42);
+        assert!(from_hex::<F>("2a").is_err());
+        assert!(from_hex::<F>("0xffffffffffffffff").is_err());
+    }
+}
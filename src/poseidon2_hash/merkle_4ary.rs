@@ -0,0 +1,286 @@
+//! 4-ary Merkle trees hashed with Poseidon2, and their proof verification in
+//! circuits using the [`Poseidon2PositionGate`].
+//!
+//! Leaves are hashed with `hash_or_noop` and each internal node is the
+//! `hash_no_pad` of the concatenation of its four children, so that a tree of
+//! depth `d` holds `4^d` leaves and is addressed by `2 * d` index bits.
+
+use anyhow::{ensure, Result};
+use plonky2::hash::hash_types::{HashOut, HashOutTarget, RichField, NUM_HASH_OUT_ELTS};
+use plonky2::iop::target::{BoolTarget, Target};
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+use plonky2::plonk::config::Hasher;
+use plonky2_field::extension::Extendable;
+
+use crate::gate::poseidon2_position::{select_child, Poseidon2PositionGate, ARITY};
+use crate::gate::poseidon2_sponge::{AbsorptionMode, Poseidon2SpongeGate};
+use crate::poseidon2_hash::poseidon2::{Poseidon2, Poseidon2Hash};
+
+/// The digests of the three siblings of each node on the path from a leaf to
+/// the root, starting from the bottommost layer.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QuaternaryMerkleProof<F: RichField> {
+    pub siblings: Vec<[HashOut<F>; ARITY - 1]>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QuaternaryMerkleProofTarget {
+    pub siblings: Vec<[HashOutTarget; ARITY - 1]>,
+}
+
+/// Hashes the four children of a node.
+pub fn hash_children<F: RichField + Poseidon2>(children: &[HashOut<F>; ARITY]) -> HashOut<F> {
+    let inputs = children.iter().flat_map(|c| c.elements).collect::<Vec<_>>();
+    Poseidon2Hash::hash_no_pad(&inputs)
+}
+
+/// A 4-ary Merkle tree, storing every layer of digests.
+#[derive(Clone, Debug)]
+pub struct QuaternaryMerkleTree<F: RichField> {
+    /// The digests of each layer, from the leaves up to the root.
+    pub layers: Vec<Vec<HashOut<F>>>,
+}
+
+impl<F: RichField + Poseidon2> QuaternaryMerkleTree<F> {
+    /// Builds the tree of `leaves`, whose number must be a power of 4.
+    pub fn new(leaves: &[Vec<F>]) -> Self {
+        assert!(
+            leaves.len().is_power_of_two() && leaves.len().trailing_zeros().is_multiple_of(2),
+            "Number of leaves must be a power of 4"
+        );
+        let mut layers = vec![leaves
+            .iter()
+            .map(|leaf| Poseidon2Hash::hash_or_noop(leaf))
+            .collect::<Vec<_>>()];
+        while layers.last().unwrap().len() > 1 {
+            let layer = layers
+                .last()
+                .unwrap()
+                .chunks(ARITY)
+                .map(|children| hash_children(children.try_into().unwrap()))
+                .collect();
+            layers.push(layer);
+        }
+        Self { layers }
+    }
+
+    pub fn root(&self) -> HashOut<F> {
+        self.layers.last().unwrap()[0]
+    }
+
+    pub fn prove(&self, leaf_index: usize) -> QuaternaryMerkleProof<F> {
+        let mut index = leaf_index;
+        let siblings = self.layers[..self.layers.len() - 1]
+            .iter()
+            .map(|layer| {
+                let first = index - index % ARITY;
+                let position = index % ARITY;
+                index /= ARITY;
+                core::array::from_fn(|s| layer[first + if s < position { s } else { s + 1 }])
+            })
+            .collect();
+        QuaternaryMerkleProof { siblings }
+    }
+}
+
+/// Verifies that the given leaf data is present at the given index in the
+/// 4-ary Merkle tree with the given root.
+pub fn verify_quaternary_merkle_proof<F: RichField + Poseidon2>(
+    leaf_data: Vec<F>,
+    leaf_index: usize,
+    merkle_root: HashOut<F>,
+    proof: &QuaternaryMerkleProof<F>,
+) -> Result<()> {
+    let mut index = leaf_index;
+    let mut current_digest = Poseidon2Hash::hash_or_noop(&leaf_data);
+    for siblings in &proof.siblings {
+        let position = index % ARITY;
+        let children =
+            core::array::from_fn(|j| select_child(current_digest, siblings, position, j));
+        current_digest = hash_children(&children);
+        index /= ARITY;
+    }
+    ensure!(index == 0, "Leaf index out of range");
+    ensure!(current_digest == merkle_root, "Invalid Merkle proof.");
+    Ok(())
+}
+
+/// Verifies a 4-ary Merkle proof in a circuit. `leaf_index_bits` are the
+/// little-endian bits of the leaf index, e.g. from `split_le`, consumed two at
+/// a time by one [`Poseidon2PositionGate`] and one [`Poseidon2SpongeGate`] per
+/// layer.
+pub fn verify_quaternary_merkle_proof_circuit<
+    F: RichField + Extendable<D> + Poseidon2,
+    const D: usize,
+>(
+    builder: &mut CircuitBuilder<F, D>,
+    leaf_data: Vec<Target>,
+    leaf_index_bits: &[BoolTarget],
+    merkle_root: HashOutTarget,
+    proof: &QuaternaryMerkleProofTarget,
+) {
+    assert_eq!(
+        leaf_index_bits.len(),
+        2 * proof.siblings.len(),
+        "Expected two index bits per layer"
+    );
+    type PositionGate<F, const D: usize> = Poseidon2PositionGate<F, D>;
+    let sponge_gate = Poseidon2SpongeGate::<F, D>::new(AbsorptionMode::Overwrite);
+
+    let mut state = builder.hash_or_noop::<Poseidon2Hash>(leaf_data);
+    for (bits, siblings) in leaf_index_bits.chunks(2).zip(&proof.siblings) {
+        let row = builder.add_gate(PositionGate::<F, D>::new(), vec![]);
+        for i in 0..NUM_HASH_OUT_ELTS {
+            let wire = Target::wire(row, PositionGate::<F, D>::wire_node(i));
+            builder.connect(state.elements[i], wire);
+            for (s, sibling) in siblings.iter().enumerate() {
+                let wire = Target::wire(row, PositionGate::<F, D>::wire_sibling(s, i));
+                builder.connect(sibling.elements[i], wire);
+            }
+        }
+        for (i, bit) in bits.iter().enumerate() {
+            let wire = Target::wire(row, PositionGate::<F, D>::wire_position_bit(i));
+            builder.connect(bit.target, wire);
+        }
+
+        // Absorb the last two children.
+        let sponge_row = builder.add_gate(sponge_gate.clone(), vec![]);
+        for i in 0..NUM_HASH_OUT_ELTS {
+            builder.connect(
+                Target::wire(row, PositionGate::<F, D>::wire_output_capacity(i)),
+                Target::wire(sponge_row, Poseidon2SpongeGate::<F, D>::wire_capacity(i)),
+            );
+        }
+        for child in 0..2 {
+            for i in 0..NUM_HASH_OUT_ELTS {
+                builder.connect(
+                    Target::wire(row, PositionGate::<F, D>::wire_child(2 + child, i)),
+                    Target::wire(
+                        sponge_row,
+                        sponge_gate.wire_block(NUM_HASH_OUT_ELTS * child + i),
+                    ),
+                );
+            }
+        }
+
+        state = HashOutTarget::from_vec(
+            (0..NUM_HASH_OUT_ELTS)
+                .map(|i| Target::wire(sponge_row, sponge_gate.wire_output(i)))
+                .collect(),
+        );
+    }
+
+    builder.connect_hashes(state, merkle_root);
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use plonky2::hash::hash_types::HashOut;
+    use plonky2::iop::witness::{PartialWitness, WitnessWrite};
+    use plonky2::plonk::circuit_builder::CircuitBuilder;
+    use plonky2::plonk::circuit_data::CircuitConfig;
+    use plonky2::plonk::config::GenericConfig;
+    use plonky2_field::types::{Field, Sample};
+
+    use crate::config::Poseidon2GoldilocksConfig;
+    use crate::poseidon2_hash::merkle_4ary::{
+        verify_quaternary_merkle_proof, verify_quaternary_merkle_proof_circuit,
+        QuaternaryMerkleProof, QuaternaryMerkleProofTarget, QuaternaryMerkleTree,
+    };
+
+    const D: usize = 2;
+    type C = Poseidon2GoldilocksConfig;
+    type F = <C as GenericConfig<D>>::F;
+
+    fn random_tree(depth: usize) -> (Vec<Vec<F>>, QuaternaryMerkleTree<F>) {
+        let leaves = (0..1 << (2 * depth))
+            .map(|_| F::rand_vec(7))
+            .collect::<Vec<_>>();
+        let tree = QuaternaryMerkleTree::new(&leaves);
+        (leaves, tree)
+    }
+
+    #[test]
+    fn native_proofs() -> Result<()> {
+        let (leaves, tree) = random_tree(3);
+        for (i, leaf) in leaves.iter().enumerate() {
+            verify_quaternary_merkle_proof(leaf.clone(), i, tree.root(), &tree.prove(i))?;
+        }
+        let proof = tree.prove(5);
+        assert!(verify_quaternary_merkle_proof(leaves[6].clone(), 5, tree.root(), &proof).is_err());
+        Ok(())
+    }
+
+    /// Proves and verifies, in a circuit, that `leaf` is at `leaf_index` in a
+    /// tree of the given depth and `root`. The leaf index is a witness.
+    fn prove_circuit(
+        depth: usize,
+        leaf: &[F],
+        leaf_index: usize,
+        root: HashOut<F>,
+        proof: &QuaternaryMerkleProof<F>,
+    ) -> Result<()> {
+        let config = CircuitConfig {
+            num_wires: 144,
+            ..CircuitConfig::standard_recursion_config()
+        };
+        let mut builder = CircuitBuilder::<F, D>::new(config);
+        let mut pw = PartialWitness::new();
+
+        let root_t = builder.add_virtual_hash();
+        pw.set_hash_target(root_t, root);
+        let proof_t = QuaternaryMerkleProofTarget {
+            siblings: (0..depth)
+                .map(|_| core::array::from_fn(|_| builder.add_virtual_hash()))
+                .collect(),
+        };
+        for (s_t, s) in proof_t.siblings.iter().zip(&proof.siblings) {
+            for (&t, &h) in s_t.iter().zip(s) {
+                pw.set_hash_target(t, h);
+            }
+        }
+        let leaf_t = builder.add_virtual_targets(leaf.len());
+        pw.set_target_arr(&leaf_t, leaf);
+        let index_t = builder.add_virtual_target();
+        pw.set_target(index_t, F::from_canonical_usize(leaf_index));
+        let bits = builder.split_le(index_t, 2 * depth);
+        verify_quaternary_merkle_proof_circuit(&mut builder, leaf_t, &bits, root_t, &proof_t);
+
+        let data = builder.build::<C>();
+        let proof = data.prove(pw)?;
+        data.verify(proof)
+    }
+
+    #[test]
+    fn circuit_proofs() -> Result<()> {
+        let depth = 3;
+        let (leaves, tree) = random_tree(depth);
+        for leaf_index in [0, 17, 42, 63] {
+            let proof = tree.prove(leaf_index);
+            prove_circuit(depth, &leaves[leaf_index], leaf_index, tree.root(), &proof)?;
+        }
+        Ok(())
+    }
+
+    // The computed root differs from the given one, so witness generation
+    // stops at the copy constraint between them.
+    #[test]
+    #[should_panic(expected = "set twice with different values")]
+    fn circuit_wrong_index() {
+        let depth = 3;
+        let (leaves, tree) = random_tree(depth);
+        let proof = tree.prove(17);
+        let _ = prove_circuit(depth, &leaves[17], 18, tree.root(), &proof);
+    }
+
+    #[test]
+    #[should_panic(expected = "set twice with different values")]
+    fn circuit_wrong_sibling() {
+        let depth = 3;
+        let (leaves, tree) = random_tree(depth);
+        let mut proof = tree.prove(17);
+        proof.siblings[1][2].elements[0] += F::ONE;
+        let _ = prove_circuit(depth, &leaves[17], 17, tree.root(), &proof);
+    }
+}
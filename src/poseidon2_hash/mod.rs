@@ -1,5 +1,13 @@
+pub mod bn254;
+pub mod grinding;
+pub mod kat;
+pub mod merkle_4ary;
 pub mod poseidon2;
+pub mod poseidon2_bn254;
 pub mod poseidon2_goldilocks;
+pub mod trace;
+pub mod transcript;
+pub mod xof;
 
 use plonky2::field::types::{Field, PrimeField64, Sample};
 use plonky2::hash::poseidon::Poseidon;
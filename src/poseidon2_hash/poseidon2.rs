@@ -4,10 +4,12 @@
 
 use core::fmt::Debug;
 use plonky2_field::extension::{Extendable, FieldExtension};
+use plonky2_field::packed::PackedField;
 use plonky2_field::types::{Field, PrimeField64};
 use unroll::unroll_for_loops;
 
 use crate::gate::poseidon2::Poseidon2Gate;
+use crate::poseidon2_hash::trace::{Poseidon2RoundPhase, Poseidon2RoundTrace, Poseidon2Trace};
 use plonky2::hash::hash_types::{HashOut, RichField};
 use plonky2::hash::hashing::{compress, hash_n_to_hash_no_pad, PlonkyPermutation};
 use plonky2::iop::ext_target::ExtensionTarget;
@@ -208,6 +210,187 @@ pub trait Poseidon2: PrimeField64 {
         }
     }
 
+    // M4^{-1} * x
+    // M4^{-1} = 1/8 * [
+    //    [-1, 3,-6, 7]
+    //    [ 1,-1, 4,-5]
+    //    [-6, 7,-1, 3]
+    //    [ 4,-5, 1,-1]
+    //  ]
+    #[inline]
+    fn matmul_m4_inverse(input: &mut [Self]) {
+        const M4_INV_8: [[i64; 4]; 4] =
+            [[-1, 3, -6, 7], [1, -1, 4, -5], [-6, 7, -1, 3], [4, -5, 1, -1]];
+        let inv_8 = Self::inverse_2exp(3);
+        let t4 = WIDTH / 4;
+        for i in 0..t4 {
+            let start_index = i * 4;
+            let mut block = [Self::ZERO; 4];
+            for (r, row) in M4_INV_8.iter().enumerate() {
+                for (c, &coeff) in row.iter().enumerate() {
+                    block[r] += Self::from_noncanonical_i64(coeff) * input[start_index + c];
+                }
+            }
+            for (r, b) in block.into_iter().enumerate() {
+                input[start_index + r] = b * inv_8;
+            }
+        }
+    }
+
+    // M_E^{-1} * x
+    // M_E = (I + J) ⊗ M4 with J the 3x3 all-ones matrix, and (I + J)^{-1} = I - J/4,
+    // so M_E^{-1} = M4^{-1} on each 4-element part followed by subtracting a
+    // quarter of the sum of the parts.
+    #[inline]
+    fn matmul_external_inverse(input: &mut [Self]) {
+        Self::matmul_m4_inverse(input);
+
+        let inv_4 = Self::inverse_2exp(2);
+        let mut stored = [Self::ZERO; 4];
+        for (i, x) in input.iter().enumerate() {
+            stored[i % 4] += *x;
+        }
+        for (i, x) in input.iter_mut().enumerate() {
+            *x -= stored[i % 4] * inv_4;
+        }
+    }
+
+    // M_I^{-1} * x
+    // M_I = D + 1 * 1^T with D = diag(u_0 - 1, ..., u_11 - 1), so by the
+    // Sherman-Morrison formula
+    // M_I^{-1} * x = D^{-1} * x - D^{-1} * 1 * (1^T * D^{-1} * x) / (1 + 1^T * D^{-1} * 1)
+    #[inline]
+    fn matmul_internal_inverse(input: &mut [Self], mat_internal_diag_m_1: &[u64]) {
+        let diag = mat_internal_diag_m_1[..WIDTH]
+            .iter()
+            .map(|&d| Self::from_canonical_u64(d))
+            .collect::<Vec<_>>();
+        let diag_inv = Self::batch_multiplicative_inverse(&diag);
+
+        let mut sum = Self::ZERO;
+        let mut denominator = Self::ONE;
+        for i in 0..WIDTH {
+            input[i] *= diag_inv[i];
+            sum += input[i];
+            denominator += diag_inv[i];
+        }
+        let scale = sum * denominator.inverse();
+        for i in 0..WIDTH {
+            input[i] -= diag_inv[i] * scale;
+        }
+    }
+
+    // x |--> x^{1/7}, i.e. x^e with 7 * e = 1 mod (p - 1), which exists since
+    // gcd(7, p - 1) = 1.
+    #[inline]
+    fn sbox_monomial_inverse(x: Self) -> Self {
+        let order = Self::ORDER as u128;
+        let e = (1..7)
+            .map(|k| k * (order - 1) + 1)
+            .find(|n| n % 7 == 0)
+            .expect("x^7 is not a permutation of the field")
+            / 7;
+        x.exp_u64(e as u64)
+    }
+
+    #[inline]
+    fn sbox_layer_inverse(state: &mut [Self; WIDTH]) {
+        for x in state.iter_mut() {
+            *x = Self::sbox_monomial_inverse(*x);
+        }
+    }
+
+    #[inline]
+    fn constant_layer_inverse(state: &mut [Self; WIDTH], round_ctr: usize) {
+        let round_constants = &Self::RC12[round_ctr * WIDTH..(round_ctr + 1) * WIDTH];
+        for (x, &c) in state.iter_mut().zip(round_constants) {
+            *x -= Self::from_canonical_u64(c);
+        }
+    }
+
+    // P^{-1}: the layers of `poseidon2` inverted, in reverse order.
+    fn poseidon2_inverse(output: [Self; WIDTH]) -> [Self; WIDTH] {
+        let mut current_state = output;
+
+        for round_ctr in (ROUND_F_BEGIN..ROUND_F_END).rev() {
+            Self::matmul_external_inverse(&mut current_state);
+            Self::sbox_layer_inverse(&mut current_state);
+            Self::constant_layer_inverse(&mut current_state, round_ctr);
+        }
+
+        for r in (0..ROUND_P).rev() {
+            Self::matmul_internal_inverse(&mut current_state, &Self::MAT_DIAG12_M_1);
+            current_state[0] = Self::sbox_monomial_inverse(current_state[0]);
+            current_state[0] -= Self::from_canonical_u64(Self::RC12_MID[r]);
+        }
+
+        for round_ctr in (0..ROUND_F_BEGIN).rev() {
+            Self::matmul_external_inverse(&mut current_state);
+            Self::sbox_layer_inverse(&mut current_state);
+            Self::constant_layer_inverse(&mut current_state, round_ctr);
+        }
+
+        Self::matmul_external_inverse(&mut current_state);
+        current_state
+    }
+
+    // `poseidon2`, recording the state after each layer.
+    fn poseidon2_trace(input: [Self; WIDTH]) -> Poseidon2Trace<Self> {
+        let mut current_state = input;
+        Self::matmul_external(&mut current_state);
+        let after_initial_linear = current_state;
+
+        let mut rounds = Vec::with_capacity(ROUNDS);
+        let full_round = |state: &mut [Self; WIDTH], round_ctr: usize| {
+            Self::constant_layer(state, round_ctr);
+            let after_constants = *state;
+            Self::sbox_layer(state);
+            let after_sbox = *state;
+            Self::matmul_external(state);
+            let (phase, round) = if round_ctr < ROUND_F_BEGIN {
+                (Poseidon2RoundPhase::FullRoundBegin, round_ctr)
+            } else {
+                (Poseidon2RoundPhase::FullRoundEnd, round_ctr - ROUND_F_BEGIN)
+            };
+            Poseidon2RoundTrace {
+                phase,
+                round,
+                after_constants,
+                after_sbox,
+                after_linear: *state,
+            }
+        };
+
+        for round_ctr in 0..ROUND_F_BEGIN {
+            rounds.push(full_round(&mut current_state, round_ctr));
+        }
+
+        for r in 0..ROUND_P {
+            current_state[0] += Self::from_canonical_u64(Self::RC12_MID[r]);
+            let after_constants = current_state;
+            current_state[0] = Self::sbox_monomial(current_state[0]);
+            let after_sbox = current_state;
+            Self::matmul_internal(&mut current_state, &Self::MAT_DIAG12_M_1);
+            rounds.push(Poseidon2RoundTrace {
+                phase: Poseidon2RoundPhase::PartialRound,
+                round: r,
+                after_constants,
+                after_sbox,
+                after_linear: current_state,
+            });
+        }
+
+        for round_ctr in ROUND_F_BEGIN..ROUND_F_END {
+            rounds.push(full_round(&mut current_state, round_ctr));
+        }
+
+        Poseidon2Trace {
+            input,
+            after_initial_linear,
+            rounds,
+        }
+    }
+
     // -------------------------------------- field ------------------------------------------
     #[inline]
     fn matmul_external_field<F: FieldExtension<D, BaseField = Self>, const D: usize>(
@@ -305,8 +488,86 @@ pub trait Poseidon2: PrimeField64 {
             state[i] = Self::sbox_monomial(state[i]);
         }
     }
+
+    // -------------------------------------- packed -----------------------------------------
+    // Same as the field versions, over packed extension fields, as used by
+    // STARK constraint evaluation.
+    fn matmul_external_packed<FE, P, const D2: usize>(input: &mut [P; WIDTH])
+    where
+        FE: FieldExtension<D2, BaseField = Self>,
+        P: PackedField<Scalar = FE>,
+    {
+        Self::matmul_m4_packed(input);
+
+        let t4 = WIDTH / 4;
+        let mut stored = [P::ZEROS; 4];
+        for l in 0..4 {
+            stored[l] = input[l];
+            for j in 1..t4 {
+                stored[l] += input[4 * j + l];
+            }
+        }
+        for i in 0..WIDTH {
+            input[i] += stored[i % 4];
+        }
+    }
+
+    fn matmul_internal_packed<FE, P, const D2: usize>(
+        input: &mut [P; WIDTH],
+        mat_internal_diag_m_1: &[u64],
+    ) where
+        FE: FieldExtension<D2, BaseField = Self>,
+        P: PackedField<Scalar = FE>,
+    {
+        let sum = input.iter().copied().sum::<P>();
+        for i in 0..WIDTH {
+            input[i] = input[i] * FE::from_canonical_u64(mat_internal_diag_m_1[i]) + sum;
+        }
+    }
+
+    fn matmul_m4_packed<P: PackedField>(input: &mut [P; WIDTH]) {
+        for x in input.chunks_exact_mut(4) {
+            let t_0 = x[0] + x[1];
+            let t_1 = x[2] + x[3];
+            let t_2 = t_1 + x[1].doubles();
+            let t_3 = t_0 + x[3].doubles();
+            let t_4 = t_3 + t_1.doubles().doubles();
+            let t_5 = t_2 + t_0.doubles().doubles();
+            x[0] = t_3 + t_5;
+            x[1] = t_5;
+            x[2] = t_2 + t_4;
+            x[3] = t_4;
+        }
+    }
+
+    fn constant_layer_packed<FE, P, const D2: usize>(state: &mut [P; WIDTH], round_ctr: usize)
+    where
+        FE: FieldExtension<D2, BaseField = Self>,
+        P: PackedField<Scalar = FE>,
+    {
+        let round_constants = &Self::RC12[round_ctr * WIDTH..(round_ctr + 1) * WIDTH];
+        for (x, &c) in state.iter_mut().zip(round_constants) {
+            *x += FE::from_canonical_u64(c);
+        }
+    }
+
+    fn sbox_monomial_packed<P: PackedField>(x: P) -> P {
+        let x2 = x.square();
+        let x4 = x2.square();
+        x * x2 * x4
+    }
+
+    fn sbox_layer_packed<P: PackedField>(state: &mut [P; WIDTH]) {
+        for x in state.iter_mut() {
+            *x = Self::sbox_monomial_packed(*x);
+        }
+    }
+
     // -------------------------------------- circuit ----------------------------------------
     // matmul_external_circuit
+    // M_E * x = [M4, M4, M4] * x + circ[M4,0,0] * x, computed as in `matmul_external`:
+    // M4 on each 4-element part, then the three parts are summed once and the
+    // sum is added to every part.
     fn matmul_external_circuit<const D: usize>(
         builder: &mut CircuitBuilder<Self, D>,
         input: &mut [ExtensionTarget<D>; WIDTH],
@@ -314,48 +575,40 @@ pub trait Poseidon2: PrimeField64 {
     where
         Self: RichField + Extendable<D>,
     {
-        let mut result = [builder.zero_extension(); WIDTH];
-
         Self::matmul_m4_circuit(builder, input);
 
-        result[0] = builder.add_many_extension([input[0], input[0], input[4], input[8]]);
-        result[1] = builder.add_many_extension([input[1], input[1], input[5], input[9]]);
-        result[2] = builder.add_many_extension([input[2], input[2], input[6], input[10]]);
-        result[3] = builder.add_many_extension([input[3], input[3], input[7], input[11]]);
-
-        result[4] = builder.add_many_extension([input[0], input[4], input[4], input[8]]);
-        result[5] = builder.add_many_extension([input[1], input[5], input[5], input[9]]);
-        result[6] = builder.add_many_extension([input[2], input[6], input[6], input[10]]);
-        result[7] = builder.add_many_extension([input[3], input[7], input[7], input[11]]);
-
-        result[8] = builder.add_many_extension([input[0], input[4], input[8], input[8]]);
-        result[9] = builder.add_many_extension([input[1], input[5], input[9], input[9]]);
-        result[10] = builder.add_many_extension([input[2], input[6], input[10], input[10]]);
-        result[11] = builder.add_many_extension([input[3], input[7], input[11], input[11]]);
+        let mut stored = [input[0], input[1], input[2], input[3]];
+        for (i, &x) in input.iter().enumerate().skip(4) {
+            stored[i % 4] = builder.add_extension(stored[i % 4], x);
+        }
 
+        let mut result = *input;
+        for (i, r) in result.iter_mut().enumerate() {
+            *r = builder.add_extension(*r, stored[i % 4]);
+        }
         result
     }
 
     // matmul_m4_circuit
+    // The small coefficients of M4 are passed as constants of the arithmetic
+    // gate, so no constant targets need to be routed.
     fn matmul_m4_circuit<const D: usize>(
         builder: &mut CircuitBuilder<Self, D>,
         input: &mut [ExtensionTarget<D>; WIDTH],
     ) where
         Self: RichField + Extendable<D>,
     {
+        let one = builder.one_extension();
+        let four = Self::TWO.double();
         for i in 0..3 {
-            let t_0 = builder.mul_const_add_extension(Self::ONE, input[i * 4], input[i * 4 + 1]);
-            let t_1 =
-                builder.mul_const_add_extension(Self::ONE, input[i * 4 + 2], input[i * 4 + 3]);
-            let t_2 = builder.mul_const_add_extension(Self::TWO, input[i * 4 + 1], t_1);
-            let t_3 = builder.mul_const_add_extension(Self::TWO, input[i * 4 + 3], t_0);
-
-            let four = Self::TWO + Self::TWO;
-
-            let t_4 = builder.mul_const_add_extension(four, t_1, t_3);
-            let t_5 = builder.mul_const_add_extension(four, t_0, t_2);
-            let t_6 = builder.mul_const_add_extension(Self::ONE, t_3, t_5);
-            let t_7 = builder.mul_const_add_extension(Self::ONE, t_2, t_4);
+            let t_0 = builder.add_extension(input[i * 4], input[i * 4 + 1]);
+            let t_1 = builder.add_extension(input[i * 4 + 2], input[i * 4 + 3]);
+            let t_2 = builder.arithmetic_extension(Self::TWO, Self::ONE, one, input[i * 4 + 1], t_1);
+            let t_3 = builder.arithmetic_extension(Self::TWO, Self::ONE, one, input[i * 4 + 3], t_0);
+            let t_4 = builder.arithmetic_extension(four, Self::ONE, one, t_1, t_3);
+            let t_5 = builder.arithmetic_extension(four, Self::ONE, one, t_0, t_2);
+            let t_6 = builder.add_extension(t_3, t_5);
+            let t_7 = builder.add_extension(t_2, t_4);
 
             input[i * 4] = t_6;
             input[i * 4 + 1] = t_5;
@@ -388,11 +641,11 @@ pub trait Poseidon2: PrimeField64 {
         Self: RichField + Extendable<D>,
     {
         for i in 0..WIDTH {
-            input[i] = builder.exp_u64_extension(input[i], 7);
+            input[i] = <Self as Poseidon2>::sbox_monomial_circuit(builder, input[i]);
         }
     }
 
-    // add round_constant layer circuit
+    // sbox_monomial circuit
     fn sbox_monomial_circuit<const D: usize>(
         builder: &mut CircuitBuilder<Self, D>,
         input: ExtensionTarget<D>,
@@ -400,7 +653,25 @@ pub trait Poseidon2: PrimeField64 {
     where
         Self: RichField + Extendable<D>,
     {
-        builder.exp_u64_extension(input, 7)
+        let zero = builder.zero_extension();
+        Self::sbox_monomial_add_circuit(builder, input, zero)
+    }
+
+    // x |--> x^7 + addend
+    // The addition is fused into the last multiplication of the S-box, which
+    // lets callers fold a following constant layer into the S-box for free.
+    fn sbox_monomial_add_circuit<const D: usize>(
+        builder: &mut CircuitBuilder<Self, D>,
+        input: ExtensionTarget<D>,
+        addend: ExtensionTarget<D>,
+    ) -> ExtensionTarget<D>
+    where
+        Self: RichField + Extendable<D>,
+    {
+        let x2 = builder.square_extension(input);
+        let x4 = builder.square_extension(x2);
+        let x3 = builder.mul_extension(input, x2);
+        builder.mul_add_extension(x3, x4, addend)
     }
 
     // matmul_internal_circuit
@@ -422,6 +693,49 @@ pub trait Poseidon2: PrimeField64 {
             input[i] = builder.mul_add_extension(round_constant, input[i], sum);
         }
     }
+
+    // Same as `poseidon2`, but evaluated in a circuit with generic arithmetic
+    // gates only, for circuits where adding a `Poseidon2Gate` is undesirable.
+    // A single permutation takes 141 rows of arithmetic gates with the
+    // standard recursion config and D = 2, compared to one row of the
+    // `Poseidon2Gate`, but it does not require 143 wires.
+    fn poseidon2_circuit_arith<const D: usize>(
+        builder: &mut CircuitBuilder<Self, D>,
+        input: [ExtensionTarget<D>; WIDTH],
+    ) -> [ExtensionTarget<D>; WIDTH]
+    where
+        Self: RichField + Extendable<D>,
+    {
+        let mut current_state = input;
+
+        // M_E * X
+        current_state = <Self as Poseidon2>::matmul_external_circuit(builder, &mut current_state);
+
+        // External_i, i in {0 - R_F/2 -1}
+        for round_ctr in 0..ROUND_F_BEGIN {
+            <Self as Poseidon2>::constant_layer_circuit(builder, &mut current_state, round_ctr);
+            <Self as Poseidon2>::sbox_layer_circuit(builder, &mut current_state);
+            current_state = <Self as Poseidon2>::matmul_external_circuit(builder, &mut current_state);
+        }
+
+        // Internal_i
+        for r in 0..ROUND_P {
+            let round_constant = Self::Extension::from_canonical_u64(Self::RC12_MID[r]);
+            let round_constant = builder.constant_extension(round_constant);
+            current_state[0] = builder.add_extension(current_state[0], round_constant);
+            current_state[0] = <Self as Poseidon2>::sbox_monomial_circuit(builder, current_state[0]);
+            <Self as Poseidon2>::matmul_internal_circuit(builder, &mut current_state);
+        }
+
+        // External_i, i in {R_F/2 = R/F - 1}
+        for round_ctr in ROUND_F_BEGIN..ROUND_F_END {
+            <Self as Poseidon2>::constant_layer_circuit(builder, &mut current_state, round_ctr);
+            <Self as Poseidon2>::sbox_layer_circuit(builder, &mut current_state);
+            current_state = <Self as Poseidon2>::matmul_external_circuit(builder, &mut current_state);
+        }
+
+        current_state
+    }
 }
 
 #[derive(Default, Clone, Copy, Debug, PartialEq)]
@@ -431,7 +745,7 @@ pub struct Poseidon2Permutation<T> {
 
 impl<T: Eq> Eq for Poseidon2Permutation<T> {}
 
-trait Permuter: Sized {
+pub(crate) trait Permuter: Sized {
     fn permute(input: [Self; WIDTH]) -> [Self; WIDTH];
 }
 
@@ -574,18 +888,103 @@ pub(crate) mod test_consistency {
     use plonky2_field::types::Field;
 
     #[test]
-    pub(crate) fn check_con()
-    {
+    fn circuit_arith_matches_native() -> anyhow::Result<()> {
+        use plonky2::iop::witness::{PartialWitness, WitnessWrite};
+        use plonky2::plonk::circuit_builder::CircuitBuilder;
+        use plonky2::plonk::circuit_data::CircuitConfig;
+        use plonky2_field::types::Sample;
+
+        use crate::config::Poseidon2GoldilocksConfig;
+        use crate::poseidon2_hash::poseidon2::Poseidon2Hash;
+        use plonky2::plonk::config::AlgebraicHasher;
+        use plonky2::hash::hashing::PlonkyPermutation;
+
+        const D: usize = 2;
+        let config = CircuitConfig::standard_recursion_config();
+
+        let mut builder = CircuitBuilder::<F, D>::new(config.clone());
+        let inputs = builder.add_virtual_targets(WIDTH);
+        let state = inputs
+            .iter()
+            .map(|&t| builder.convert_to_ext(t))
+            .collect::<Vec<_>>();
+        let output = F::poseidon2_circuit_arith(&mut builder, state.try_into().unwrap());
+        for o in output {
+            // The output of a base field input stays in the base field.
+            let limbs = o.to_target_array();
+            for &limb in &limbs[1..] {
+                builder.assert_zero(limb);
+            }
+            builder.register_public_input(limbs[0]);
+        }
+        let arith_gates = builder.num_gates();
+        let data = builder.build::<Poseidon2GoldilocksConfig>();
+
+        // The same permutation with the `Poseidon2Gate`, for comparison.
+        let mut gate_builder = CircuitBuilder::<F, D>::new(config);
+        let gate_inputs = gate_builder.add_virtual_targets(WIDTH);
+        let perm = <Poseidon2Hash as AlgebraicHasher<F>>::AlgebraicPermutation::new(gate_inputs);
+        gate_builder.permute::<Poseidon2Hash>(perm);
+        let poseidon2_gates = gate_builder.num_gates();
+        assert_eq!(poseidon2_gates, 1);
+        assert_eq!(
+            arith_gates,
+            141,
+            "poseidon2 permutation: {} gates with arithmetic gates, {} with Poseidon2Gate",
+            arith_gates,
+            poseidon2_gates
+        );
+
+        let input = F::rand_array::<WIDTH>();
+        let mut pw = PartialWitness::new();
+        for (&t, &x) in inputs.iter().zip(&input) {
+            pw.set_target(t, x);
+        }
+        let proof = data.prove(pw)?;
+        assert_eq!(proof.public_inputs, F::poseidon2(input).to_vec());
+        data.verify(proof)
+    }
+
+    #[test]
+    fn inverse_round_trip() {
+        use plonky2_field::types::Sample;
+
+        for _ in 0..16 {
+            let x = F::rand();
+            assert_eq!(F::sbox_monomial_inverse(F::sbox_monomial(x)), x);
+
+            let state = F::rand_array::<WIDTH>();
+            let mut internal = state;
+            F::matmul_internal(&mut internal, &F::MAT_DIAG12_M_1);
+            F::matmul_internal_inverse(&mut internal, &F::MAT_DIAG12_M_1);
+            assert_eq!(internal, state);
+
+            assert_eq!(F::poseidon2_inverse(F::poseidon2(state)), state);
+            assert_eq!(F::poseidon2(F::poseidon2_inverse(state)), state);
+        }
+
+        let zero = [F::ZERO; WIDTH];
+        assert_eq!(F::poseidon2_inverse(F::poseidon2(zero)), zero);
+        let minus_one = [F::NEG_ONE; WIDTH];
+        assert_eq!(F::poseidon2_inverse(F::poseidon2(minus_one)), minus_one);
+    }
+
+    #[test]
+    pub(crate) fn check_con() {
+        use crate::poseidon2_hash::kat::{KnownAnswerTests, HORIZEN_LABS};
+
         let mut input = [F::ZERO; WIDTH];
         for i in 0..WIDTH {
             input[i] = F::from_canonical_u64(i as u64);
         }
         let output = F::poseidon2(input);
-        for i in 0..WIDTH {
-            println!("input {} = {}", i, input[i]);
-        }
-        for i in 0..WIDTH {
-            println!("out {} = {}", i, output[i]);
-        }
+
+        let kats = KnownAnswerTests::load_default().unwrap();
+        let reference = kats
+            .permutation
+            .iter()
+            .find(|v| v.source == HORIZEN_LABS && v.input == input)
+            .expect("Missing the HorizenLabs vector for 0..WIDTH");
+        assert_eq!(output.to_vec(), reference.output);
     }
 }
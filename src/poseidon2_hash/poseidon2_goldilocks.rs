@@ -153,44 +153,27 @@ impl Poseidon2 for GoldilocksField {
 mod tests {
 
     use plonky2_field::goldilocks_field::GoldilocksField as F;
-    use plonky2_field::types::{Field, PrimeField64};
+    use plonky2_field::types::PrimeField64;
 
+    use crate::poseidon2_hash::kat::KnownAnswerTests;
     use crate::poseidon2_hash::poseidon2::test_helpers::check_test_vectors;
 
     #[test]
     fn test_vectors() {
-        // Test inputs are:
-        // 1. all zeros
-        // 2. range 0..WIDTH
-        // 3. all -1's
-        // 4. random elements of GoldilocksField.
-        // expected output calculated with (modified) hadeshash reference implementation.
-
-        let neg_one: u64 = F::NEG_ONE.to_canonical_u64();
-
-        #[rustfmt::skip]
-        let test_vectors12: Vec<([u64; 12], [u64; 12])> = vec![
-            ([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, ],
-             [0xeaa2b931446b3e24, 0x1f5685597f6bae90, 0xf93b507697401312, 0xb82968593e74eb4d,
-             0x55e23edad63bc317, 0xea33d8cff1dd00b8, 0xe72634709a6eda14, 0x42cba11d36e7cf75,
-             0xce0431accde990fe, 0x8e2515ae1174b08b, 0x5242485e489e3fe7, 0xef26be0b5628bac1,]),
-             ([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, ],
-              [0x985f182676698596, 0xdf9a0783cc8f10c4, 0x00b159c1cbb8b509, 0xf73460d5159905a4,
-              0x404b61d3ebba2f58, 0xd4ff011e53d91a8a, 0xc18d60e2e5086150, 0x3b87c1d7425110a5,
-              0x550623e3fcdff248, 0xa969e116592e324e, 0xcba56d75a4b34ca7, 0x3ab71229927b3665,]),
-              ([neg_one, neg_one, neg_one, neg_one,
-               neg_one, neg_one, neg_one, neg_one,
-               neg_one, neg_one, neg_one, neg_one, ],
-               [0x0666d2130f5d0b60, 0xf83156c4e58242bf, 0xc008cbf9d184c244, 0x0536ae0c679535ad,
-               0x5648de4174d66cc0, 0xf7dfde0ab089d640, 0x8353a043f8837202, 0xc87021530f3c790c,
-               0x04255cecaeda7093, 0xb146993edf05995b, 0x0aa7530111c86885, 0x37859112bf0153b6,]),
-               ([0x8ccbbbea4fe5d2b7, 0xc2af59ee9ec49970, 0x90f7e1a9e658446a, 0xdcc0630a3ab8b1b8,
-                0x7ff8256bca20588c, 0x5d99a7ca0c44ecfb, 0x48452b17a70fbee3, 0xeb09d654690b6c88,
-                0x4a55d3a39c676a88, 0xc0407a38d2285139, 0xa234bac9356386d1, 0xe1633f2bad98a52f, ],
-                [0x6ea0df41f68f4eaf, 0x3dc3968360e28e69, 0x9c40b801991286b7, 0x35dff3d906ef11d6,
-                0x82a81ad3414f45db, 0x77cbb242f7eda718, 0x4ced4ca245bf6c30, 0x6de579ad2477f04e,
-                0xc2a04a38a9b6b13d, 0x94a8b42a0adc8b45, 0x81bc5490a1c89b1e, 0x167e90b8942cff51,]),
-        ];
+        // The permutation vectors of `test_vectors/`: the HorizenLabs reference
+        // vectors, then all zeros, all -1's and random elements.
+        let kats = KnownAnswerTests::load_default().unwrap();
+        let test_vectors12 = kats
+            .permutation
+            .iter()
+            .map(|v| {
+                let to_u64 = |x: &F| x.to_canonical_u64();
+                (
+                    v.input.iter().map(to_u64).collect::<Vec<_>>().try_into().unwrap(),
+                    v.output.iter().map(to_u64).collect::<Vec<_>>().try_into().unwrap(),
+                )
+            })
+            .collect();
 
         check_test_vectors::<F>(test_vectors12);
     }
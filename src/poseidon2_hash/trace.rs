@@ -0,0 +1,176 @@
+//! Intermediate states of the Poseidon2 permutation.
+//!
+//! [`Poseidon2::poseidon2_trace`] records the state after every layer of the
+//! permutation: the initial M_E layer, then the constant, S-box and linear
+//! layers of each round. The gate generators fill their round wires from it,
+//! and it serializes to JSON, with field elements as hexadecimal strings, to
+//! be compared against other implementations round by round.
+
+use serde::{Deserialize, Serialize};
+
+use crate::poseidon2_hash::poseidon2::{Poseidon2, ROUNDS, WIDTH};
+
+/// The phase of the permutation a round belongs to.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Poseidon2RoundPhase {
+    /// The first `ROUND_F_BEGIN` full rounds.
+    FullRoundBegin,
+    /// The `ROUND_P` partial rounds, whose constant and S-box layers only
+    /// change the first element of the state.
+    PartialRound,
+    /// The last `ROUND_F_END - ROUND_F_BEGIN` full rounds.
+    FullRoundEnd,
+}
+
+/// The states of a round after each of its layers. `round` counts from 0 in
+/// each phase, like the wire functions of `Poseidon2Gate`.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Poseidon2RoundTrace<F: Poseidon2> {
+    pub phase: Poseidon2RoundPhase,
+    pub round: usize,
+    #[serde(with = "hex_state")]
+    pub after_constants: [F; WIDTH],
+    #[serde(with = "hex_state")]
+    pub after_sbox: [F; WIDTH],
+    #[serde(with = "hex_state")]
+    pub after_linear: [F; WIDTH],
+}
+
+/// Every intermediate state of a permutation, see
+/// [`Poseidon2::poseidon2_trace`].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Poseidon2Trace<F: Poseidon2> {
+    #[serde(with = "hex_state")]
+    pub input: [F; WIDTH],
+    /// The state after the M_E layer applied before the first round.
+    #[serde(with = "hex_state")]
+    pub after_initial_linear: [F; WIDTH],
+    /// The `ROUNDS` rounds, in order.
+    pub rounds: Vec<Poseidon2RoundTrace<F>>,
+}
+
+impl<F: Poseidon2> Poseidon2Trace<F> {
+    /// The output of the permutation.
+    pub fn output(&self) -> [F; WIDTH] {
+        self.rounds
+            .last()
+            .map_or(self.after_initial_linear, |round| round.after_linear)
+    }
+
+    /// The rounds of the given phase.
+    pub fn phase(
+        &self,
+        phase: Poseidon2RoundPhase,
+    ) -> impl Iterator<Item = &Poseidon2RoundTrace<F>> {
+        self.rounds.iter().filter(move |round| round.phase == phase)
+    }
+
+    /// Whether this is a trace of the permutation of its input, i.e. whether
+    /// every state follows from the previous one. Useful on traces read from
+    /// JSON.
+    pub fn is_consistent(&self) -> bool {
+        self.rounds.len() == ROUNDS && *self == F::poseidon2_trace(self.input)
+    }
+}
+
+/// Serializes a state as `0x`-prefixed hexadecimal strings.
+mod hex_state {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::poseidon2_hash::kat::{from_hex, to_hex};
+    use crate::poseidon2_hash::poseidon2::{Poseidon2, WIDTH};
+
+    pub fn serialize<F: Poseidon2, S: Serializer>(
+        state: &[F; WIDTH],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(state.iter().map(to_hex))
+    }
+
+    pub fn deserialize<'de, F: Poseidon2, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<[F; WIDTH], D::Error> {
+        let state = Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|s| from_hex(s).map_err(D::Error::custom))
+            .collect::<Result<Vec<F>, _>>()?;
+        let len = state.len();
+        state
+            .try_into()
+            .map_err(|_| D::Error::invalid_length(len, &"a Poseidon2 state"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use plonky2_field::goldilocks_field::GoldilocksField;
+    use plonky2_field::types::{Field, Sample};
+
+    use crate::poseidon2_hash::poseidon2::{
+        Poseidon2, ROUNDS, ROUND_F_BEGIN, ROUND_F_END, ROUND_P, WIDTH,
+    };
+    use crate::poseidon2_hash::trace::{Poseidon2RoundPhase, Poseidon2Trace};
+
+    type F = GoldilocksField;
+
+    #[test]
+    fn layers() {
+        let input = F::rand_array::<WIDTH>();
+        let trace = F::poseidon2_trace(input);
+        assert_eq!(trace.output(), F::poseidon2(input));
+        assert_eq!(trace.rounds.len(), ROUNDS);
+        assert_eq!(
+            trace.phase(Poseidon2RoundPhase::FullRoundBegin).count(),
+            ROUND_F_BEGIN
+        );
+        assert_eq!(
+            trace.phase(Poseidon2RoundPhase::PartialRound).count(),
+            ROUND_P
+        );
+        assert_eq!(
+            trace.phase(Poseidon2RoundPhase::FullRoundEnd).count(),
+            ROUND_F_END - ROUND_F_BEGIN
+        );
+
+        let mut state = input;
+        F::matmul_external(&mut state);
+        assert_eq!(trace.after_initial_linear, state);
+        for round in &trace.rounds {
+            let mut sbox = round.after_constants;
+            match round.phase {
+                Poseidon2RoundPhase::PartialRound => {
+                    assert_eq!(round.after_constants[1..], state[1..]);
+                    sbox[0] = F::sbox_monomial(sbox[0]);
+                }
+                _ => F::sbox_layer(&mut sbox),
+            }
+            assert_eq!(round.after_sbox, sbox);
+            state = round.after_linear;
+        }
+
+        // The inverse permutation walks the trace backwards.
+        assert_eq!(F::poseidon2_inverse(trace.output()), input);
+    }
+
+    #[test]
+    fn json() -> Result<()> {
+        let trace = F::poseidon2_trace(core::array::from_fn(F::from_canonical_usize));
+        let json = serde_json::to_string_pretty(&trace)?;
+        assert!(json.contains("\"phase\": \"PartialRound\""));
+        let parsed: Poseidon2Trace<F> = serde_json::from_str(&json)?;
+        assert_eq!(parsed, trace);
+        assert!(parsed.is_consistent());
+
+        let mut tampered = parsed;
+        tampered.rounds[10].after_sbox[0] += F::ONE;
+        assert!(!tampered.is_consistent());
+
+        let short = json.replacen("\"0x0000000000000000\",", "", 1);
+        assert!(serde_json::from_str::<Poseidon2Trace<F>>(&short).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,284 @@
+//! Labelled Fiat-Shamir transcripts over the Poseidon2 sponge, for protocols
+//! built outside of plonky2, and their twin in circuits which derives the same
+//! challenges.
+//!
+//! Every message and challenge is preceded by its label, so that transcripts
+//! of different protocols or of messages in a different order diverge. Byte
+//! strings, labels included, are absorbed as their length followed by their
+//! bytes packed in little-endian order, 7 bytes per field element. Field
+//! elements are absorbed as their number followed by the elements.
+
+use plonky2::hash::hash_types::{HashOut, HashOutTarget, RichField};
+use plonky2::iop::challenger::{Challenger, RecursiveChallenger};
+use plonky2::iop::ext_target::ExtensionTarget;
+use plonky2::iop::target::{BoolTarget, Target};
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+use plonky2_field::extension::Extendable;
+
+use crate::poseidon2_hash::poseidon2::{Poseidon2, Poseidon2Hash};
+
+/// Number of bytes packed in a field element, such that any packing is below
+/// the order of a 64-bit field.
+const BYTES_PER_ELEMENT: usize = 7;
+
+/// The field elements absorbed for `bytes`.
+pub(crate) fn encode_bytes<F: RichField>(bytes: &[u8]) -> Vec<F> {
+    let mut elements = vec![F::from_canonical_usize(bytes.len())];
+    elements.extend(bytes.chunks(BYTES_PER_ELEMENT).map(|chunk| {
+        let packed = chunk
+            .iter()
+            .rev()
+            .fold(0u64, |acc, &byte| (acc << 8) | byte as u64);
+        F::from_canonical_u64(packed)
+    }));
+    elements
+}
+
+/// A labelled Fiat-Shamir transcript.
+#[derive(Clone, Debug)]
+pub struct Poseidon2Transcript<F: RichField + Poseidon2> {
+    challenger: Challenger<F, Poseidon2Hash>,
+}
+
+impl<F: RichField + Poseidon2> Poseidon2Transcript<F> {
+    /// A transcript starting with `domain_separator`, which should identify the
+    /// protocol.
+    pub fn new(domain_separator: &[u8]) -> Self {
+        let mut transcript = Self {
+            challenger: Challenger::new(),
+        };
+        transcript.append_label(domain_separator);
+        transcript
+    }
+
+    fn append_label(&mut self, label: &[u8]) {
+        self.challenger.observe_elements(&encode_bytes(label));
+    }
+
+    pub fn append_field(&mut self, label: &[u8], elements: &[F]) {
+        self.append_label(label);
+        self.challenger
+            .observe_element(F::from_canonical_usize(elements.len()));
+        self.challenger.observe_elements(elements);
+    }
+
+    pub fn append_hash(&mut self, label: &[u8], hash: HashOut<F>) {
+        self.append_field(label, &hash.elements);
+    }
+
+    pub fn append_bytes(&mut self, label: &[u8], bytes: &[u8]) {
+        self.append_label(label);
+        self.challenger.observe_elements(&encode_bytes(bytes));
+    }
+
+    pub fn challenge_field(&mut self, label: &[u8]) -> F {
+        self.append_label(label);
+        self.challenger.get_challenge()
+    }
+
+    pub fn challenge_ext<const D: usize>(&mut self, label: &[u8]) -> F::Extension
+    where
+        F: Extendable<D>,
+    {
+        self.append_label(label);
+        self.challenger.get_extension_challenge::<D>()
+    }
+
+    /// Draws `num_indices` indices below `2^log_bound`, from the low bits of
+    /// field challenges. Their bias is negligible as long as `log_bound` is far
+    /// below the field size.
+    pub fn challenge_indices(
+        &mut self,
+        label: &[u8],
+        num_indices: usize,
+        log_bound: usize,
+    ) -> Vec<usize> {
+        self.append_label(label);
+        self.challenger
+            .get_n_challenges(num_indices)
+            .into_iter()
+            .map(|x| x.to_canonical_u64() as usize % (1 << log_bound))
+            .collect()
+    }
+}
+
+/// The twin of [`Poseidon2Transcript`] in a circuit. Labels are constants of
+/// the circuit, and messages must have the same lengths as the native ones.
+#[derive(Debug)]
+pub struct Poseidon2TranscriptTarget<F: RichField + Extendable<D> + Poseidon2, const D: usize> {
+    challenger: RecursiveChallenger<F, Poseidon2Hash, D>,
+}
+
+impl<F: RichField + Extendable<D> + Poseidon2, const D: usize> Poseidon2TranscriptTarget<F, D> {
+    pub fn new(builder: &mut CircuitBuilder<F, D>, domain_separator: &[u8]) -> Self {
+        let mut transcript = Self {
+            challenger: RecursiveChallenger::new(builder),
+        };
+        transcript.append_label(builder, domain_separator);
+        transcript
+    }
+
+    fn append_label(&mut self, builder: &mut CircuitBuilder<F, D>, label: &[u8]) {
+        let elements = builder.constants(&encode_bytes(label));
+        self.challenger.observe_elements(&elements);
+    }
+
+    pub fn append_field(
+        &mut self,
+        builder: &mut CircuitBuilder<F, D>,
+        label: &[u8],
+        elements: &[Target],
+    ) {
+        self.append_label(builder, label);
+        let len = builder.constant(F::from_canonical_usize(elements.len()));
+        self.challenger.observe_element(len);
+        self.challenger.observe_elements(elements);
+    }
+
+    pub fn append_hash(
+        &mut self,
+        builder: &mut CircuitBuilder<F, D>,
+        label: &[u8],
+        hash: HashOutTarget,
+    ) {
+        self.append_field(builder, label, &hash.elements);
+    }
+
+    /// Appends `bytes`, one target per byte. Each of them is range checked to
+    /// 8 bits.
+    pub fn append_bytes(
+        &mut self,
+        builder: &mut CircuitBuilder<F, D>,
+        label: &[u8],
+        bytes: &[Target],
+    ) {
+        self.append_label(builder, label);
+        let len = builder.constant(F::from_canonical_usize(bytes.len()));
+        self.challenger.observe_element(len);
+        for chunk in bytes.chunks(BYTES_PER_ELEMENT) {
+            let mut packed = builder.zero();
+            for (i, &byte) in chunk.iter().enumerate() {
+                builder.range_check(byte, 8);
+                packed = builder.mul_const_add(F::from_canonical_u64(1 << (8 * i)), byte, packed);
+            }
+            self.challenger.observe_element(packed);
+        }
+    }
+
+    pub fn challenge_field(&mut self, builder: &mut CircuitBuilder<F, D>, label: &[u8]) -> Target {
+        self.append_label(builder, label);
+        self.challenger.get_challenge(builder)
+    }
+
+    pub fn challenge_ext(
+        &mut self,
+        builder: &mut CircuitBuilder<F, D>,
+        label: &[u8],
+    ) -> ExtensionTarget<D> {
+        self.append_label(builder, label);
+        self.challenger.get_extension_challenge(builder)
+    }
+
+    /// Same as [`Poseidon2Transcript::challenge_indices`], returning the
+    /// `log_bound` little-endian bits of each index.
+    pub fn challenge_indices(
+        &mut self,
+        builder: &mut CircuitBuilder<F, D>,
+        label: &[u8],
+        num_indices: usize,
+        log_bound: usize,
+    ) -> Vec<Vec<BoolTarget>> {
+        self.append_label(builder, label);
+        self.challenger
+            .get_n_challenges(builder, num_indices)
+            .into_iter()
+            .map(|x| builder.low_bits(x, log_bound, F::BITS))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use plonky2::hash::hash_types::HashOut;
+    use plonky2::iop::witness::{PartialWitness, WitnessWrite};
+    use plonky2::plonk::circuit_builder::CircuitBuilder;
+    use plonky2::plonk::circuit_data::CircuitConfig;
+    use plonky2::plonk::config::{GenericConfig, PoseidonGoldilocksConfig};
+    use plonky2_field::types::{Field, Sample};
+
+    use crate::poseidon2_hash::transcript::{Poseidon2Transcript, Poseidon2TranscriptTarget};
+
+    const D: usize = 2;
+    // A config whose own hasher is Poseidon, not Poseidon2.
+    type C = PoseidonGoldilocksConfig;
+    type F = <C as GenericConfig<D>>::F;
+
+    #[test]
+    fn labels() {
+        let mut transcript = Poseidon2Transcript::<F>::new(b"test");
+        transcript.append_field(b"a", &[F::ONE]);
+        let challenge = transcript.clone().challenge_field(b"c");
+
+        let mut other_label = Poseidon2Transcript::<F>::new(b"test");
+        other_label.append_field(b"b", &[F::ONE]);
+        assert_ne!(other_label.challenge_field(b"c"), challenge);
+
+        let mut other_domain = Poseidon2Transcript::<F>::new(b"test2");
+        other_domain.append_field(b"a", &[F::ONE]);
+        assert_ne!(other_domain.challenge_field(b"c"), challenge);
+
+        assert_ne!(transcript.clone().challenge_field(b"d"), challenge);
+        assert_eq!(transcript.challenge_field(b"c"), challenge);
+    }
+
+    #[test]
+    fn matches_native() -> Result<()> {
+        let elements = F::rand_vec(11);
+        let hash = HashOut::<F>::rand();
+        let bytes = b"a message of 17 b".to_vec();
+        let log_bound = 10;
+
+        let mut transcript = Poseidon2Transcript::<F>::new(b"sigma protocol");
+        transcript.append_field(b"commitment", &elements);
+        let mut expected = vec![transcript.challenge_field(b"alpha")];
+        transcript.append_hash(b"root", hash);
+        transcript.append_bytes(b"statement", &bytes);
+        expected.extend(transcript.challenge_ext::<D>(b"beta").0);
+        let indices = transcript.challenge_indices(b"queries", 5, log_bound);
+        expected.extend(indices.iter().map(|&i| F::from_canonical_usize(i)));
+        expected.push(transcript.challenge_field(b"gamma"));
+        assert!(indices.iter().all(|&i| i < 1 << log_bound));
+
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let mut pw = PartialWitness::new();
+        let elements_t = builder.add_virtual_targets(elements.len());
+        pw.set_target_arr(&elements_t, &elements);
+        let hash_t = builder.add_virtual_hash();
+        pw.set_hash_target(hash_t, hash);
+        let bytes_t = builder.add_virtual_targets(bytes.len());
+        for (&t, &b) in bytes_t.iter().zip(&bytes) {
+            pw.set_target(t, F::from_canonical_u8(b));
+        }
+
+        let mut transcript = Poseidon2TranscriptTarget::new(&mut builder, b"sigma protocol");
+        transcript.append_field(&mut builder, b"commitment", &elements_t);
+        let alpha = transcript.challenge_field(&mut builder, b"alpha");
+        builder.register_public_input(alpha);
+        transcript.append_hash(&mut builder, b"root", hash_t);
+        transcript.append_bytes(&mut builder, b"statement", &bytes_t);
+        let beta = transcript.challenge_ext(&mut builder, b"beta");
+        builder.register_public_inputs(&beta.0);
+        for bits in transcript.challenge_indices(&mut builder, b"queries", 5, log_bound) {
+            let index = builder.le_sum(bits.into_iter());
+            builder.register_public_input(index);
+        }
+        let gamma = transcript.challenge_field(&mut builder, b"gamma");
+        builder.register_public_input(gamma);
+
+        let data = builder.build::<C>();
+        let proof = data.prove(pw)?;
+        assert_eq!(proof.public_inputs, expected);
+        data.verify(proof)
+    }
+}
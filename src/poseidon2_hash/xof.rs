@@ -0,0 +1,215 @@
+//! An extendable-output function over the Poseidon2 sponge, and a
+//! deterministic RNG built on it.
+//!
+//! The seed is padded with the `pad10*1` rule and absorbed in overwrite mode,
+//! then outputs are squeezed from the rate, so that the first digest of
+//! outputs is `Poseidon2Hash::hash_pad(seed)`. Byte seeds are absorbed as
+//! their length followed by their bytes, 7 per field element.
+//! [`CircuitBuilderPoseidon2::poseidon2_squeeze`](crate::gadgets::poseidon2::CircuitBuilderPoseidon2::poseidon2_squeeze)
+//! computes the same outputs in circuits.
+
+use core::fmt::Debug;
+
+use plonky2::hash::hash_types::RichField;
+use plonky2::hash::hashing::PlonkyPermutation;
+use plonky2_field::extension::{Extendable, FieldExtension};
+use rand_core::{impls, Error, RngCore, SeedableRng};
+
+use crate::poseidon2_hash::poseidon2::{Permuter, Poseidon2, Poseidon2Permutation};
+use crate::poseidon2_hash::transcript::encode_bytes;
+
+/// Pads `seed` with the `pad10*1` rule, like `Hasher::hash_pad`.
+pub(crate) fn pad<T: Copy + Debug + Default + Eq + Permuter + Send + Sync>(
+    seed: &[T],
+    zero: T,
+    one: T,
+) -> Vec<T> {
+    let rate = Poseidon2Permutation::<T>::RATE;
+    let mut padded = seed.to_vec();
+    padded.push(one);
+    while !(padded.len() + 1).is_multiple_of(rate) {
+        padded.push(zero);
+    }
+    padded.push(one);
+    padded
+}
+
+/// The Poseidon2 extendable-output function.
+#[derive(Clone, Debug)]
+pub struct Poseidon2Xof<F: RichField + Poseidon2> {
+    perm: Poseidon2Permutation<F>,
+    /// The number of outputs already squeezed from the rate of `perm`.
+    squeezed: usize,
+}
+
+impl<F: RichField + Poseidon2> Poseidon2Xof<F> {
+    pub fn new(seed: &[F]) -> Self {
+        let mut perm = Poseidon2Permutation::new(core::iter::repeat(F::ZERO));
+        for chunk in pad(seed, F::ZERO, F::ONE).chunks(Poseidon2Permutation::<F>::RATE) {
+            perm.set_from_slice(chunk, 0);
+            perm.permute();
+        }
+        Self { perm, squeezed: 0 }
+    }
+
+    pub fn from_bytes(seed: &[u8]) -> Self {
+        Self::new(&encode_bytes(seed))
+    }
+
+    /// The next output, a uniformly distributed field element.
+    pub fn squeeze(&mut self) -> F {
+        if self.squeezed == Poseidon2Permutation::<F>::RATE {
+            self.perm.permute();
+            self.squeezed = 0;
+        }
+        self.squeezed += 1;
+        self.perm.squeeze()[self.squeezed - 1]
+    }
+
+    pub fn squeeze_n(&mut self, n: usize) -> Vec<F> {
+        (0..n).map(|_| self.squeeze()).collect()
+    }
+
+    /// The next `D` outputs, as an extension field element.
+    pub fn squeeze_ext<const D: usize>(&mut self) -> F::Extension
+    where
+        F: Extendable<D>,
+    {
+        F::Extension::from_basefield_array(core::array::from_fn(|_| self.squeeze()))
+    }
+}
+
+/// A deterministic RNG reading the outputs of a [`Poseidon2Xof`]. Uniform
+/// `u32`s are the low bits of outputs, rejecting those above the largest
+/// multiple of `2^32` below the field order.
+#[derive(Clone, Debug)]
+pub struct Poseidon2Rng<F: RichField + Poseidon2> {
+    xof: Poseidon2Xof<F>,
+}
+
+impl<F: RichField + Poseidon2> Poseidon2Rng<F> {
+    pub fn from_field_seed(seed: &[F]) -> Self {
+        Self {
+            xof: Poseidon2Xof::new(seed),
+        }
+    }
+
+    pub fn from_byte_seed(seed: &[u8]) -> Self {
+        Self {
+            xof: Poseidon2Xof::from_bytes(seed),
+        }
+    }
+
+    pub fn gen_field(&mut self) -> F {
+        self.xof.squeeze()
+    }
+
+    pub fn gen_ext<const D: usize>(&mut self) -> F::Extension
+    where
+        F: Extendable<D>,
+    {
+        self.xof.squeeze_ext::<D>()
+    }
+}
+
+impl<F: RichField + Poseidon2> RngCore for Poseidon2Rng<F> {
+    fn next_u32(&mut self) -> u32 {
+        let bound = F::ORDER - F::ORDER % (1 << 32);
+        loop {
+            let x = self.xof.squeeze().to_canonical_u64();
+            if x < bound {
+                return x as u32;
+            }
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        impls::next_u64_via_u32(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        impls::fill_bytes_via_next(self, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl<F: RichField + Poseidon2> SeedableRng for Poseidon2Rng<F> {
+    type Seed = [u8; 32];
+
+    fn from_seed(seed: Self::Seed) -> Self {
+        Self::from_byte_seed(&seed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use plonky2::hash::hashing::hash_n_to_m_no_pad;
+    use plonky2::plonk::config::Hasher;
+    use plonky2_field::goldilocks_field::GoldilocksField;
+    use plonky2_field::types::{Field, Sample};
+    use rand_core::{RngCore, SeedableRng};
+
+    use crate::poseidon2_hash::poseidon2::{Poseidon2Hash, Poseidon2Permutation};
+    use crate::poseidon2_hash::xof::{pad, Poseidon2Rng, Poseidon2Xof};
+
+    type F = GoldilocksField;
+
+    #[test]
+    fn xof() {
+        for len in [0, 1, 6, 7, 8, 21] {
+            let seed = F::rand_vec(len);
+            let outputs = Poseidon2Xof::new(&seed).squeeze_n(27);
+            assert_eq!(outputs[..4], Poseidon2Hash::hash_pad(&seed).elements);
+            let padded = pad(&seed, F::ZERO, F::ONE);
+            assert_eq!(
+                outputs,
+                hash_n_to_m_no_pad::<F, Poseidon2Permutation<F>>(&padded, 27)
+            );
+
+            // Squeezing is independent of how outputs are grouped.
+            let mut xof = Poseidon2Xof::new(&seed);
+            let mut grouped = xof.squeeze_n(5);
+            grouped.extend(xof.squeeze_ext::<4>().0);
+            grouped.extend(xof.squeeze_n(18));
+            assert_eq!(outputs, grouped);
+        }
+
+        assert_ne!(
+            Poseidon2Xof::<F>::new(&[]).squeeze(),
+            Poseidon2Xof::<F>::new(&[F::ZERO]).squeeze()
+        );
+        assert_ne!(
+            Poseidon2Xof::<F>::from_bytes(b"").squeeze(),
+            Poseidon2Xof::<F>::from_bytes(b"\0").squeeze()
+        );
+    }
+
+    #[test]
+    fn rng() {
+        let mut rng = Poseidon2Rng::<F>::from_seed([7; 32]);
+        let mut same = Poseidon2Rng::<F>::from_byte_seed(&[7; 32]);
+        let mut other = Poseidon2Rng::<F>::from_seed([8; 32]);
+        let values = (0..16).map(|_| rng.next_u64()).collect::<Vec<_>>();
+        assert_eq!(values, (0..16).map(|_| same.next_u64()).collect::<Vec<_>>());
+        assert_ne!(
+            values,
+            (0..16).map(|_| other.next_u64()).collect::<Vec<_>>()
+        );
+        assert_eq!(rng.gen_field(), same.gen_field());
+        assert_eq!(rng.gen_ext::<2>(), same.gen_ext::<2>());
+
+        // Every bit of the outputs is about as often set as not.
+        let mut counts = [0; 64];
+        for _ in 0..4096 {
+            let x = rng.next_u64();
+            for (i, count) in counts.iter_mut().enumerate() {
+                *count += (x >> i) & 1;
+            }
+        }
+        assert!(counts.iter().all(|&c| (1792..2304).contains(&c)));
+    }
+}
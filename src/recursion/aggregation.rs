@@ -0,0 +1,296 @@
+//! Aggregation of many [`Poseidon2GoldilocksConfig`] proofs of the same
+//! circuit into a single proof.
+//!
+//! An [`AggregationCircuit`] verifies `arity` proofs and commits to their
+//! number and public inputs with a Poseidon2 hash, which is its only public
+//! input. The proofs of one aggregation circuit are in turn aggregated by
+//! another one, up to a single root proof, see [`aggregate_tree`]. The
+//! commitment of the root proof is given natively by [`tree_commitment`].
+
+use anyhow::{ensure, Result};
+use plonky2::hash::hash_types::{HashOut, NUM_HASH_OUT_ELTS};
+use plonky2::iop::target::BoolTarget;
+use plonky2::iop::witness::{PartialWitness, WitnessWrite};
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+use plonky2::plonk::circuit_data::{CircuitConfig, CircuitData, VerifierCircuitData};
+use plonky2::plonk::config::Hasher;
+use plonky2::plonk::proof::{ProofWithPublicInputs, ProofWithPublicInputsTarget};
+use plonky2_field::goldilocks_field::GoldilocksField;
+use plonky2_field::types::Field;
+
+use crate::config::Poseidon2GoldilocksConfig;
+use crate::poseidon2_hash::poseidon2::Poseidon2Hash;
+
+type F = GoldilocksField;
+type C = Poseidon2GoldilocksConfig;
+const D: usize = 2;
+
+/// A circuit verifying `arity` proofs of the same inner circuit, whose public
+/// inputs are the Poseidon2 hash of the number of aggregated proofs and of
+/// their concatenated public inputs, see [`aggregation_commitment`].
+pub struct AggregationCircuit {
+    pub data: CircuitData<F, C, D>,
+    proofs: Vec<ProofWithPublicInputsTarget<D>>,
+    /// Whether each slot holds one of the aggregated proofs rather than
+    /// padding. The used slots come first, and there is at least one.
+    used: Vec<BoolTarget>,
+}
+
+impl AggregationCircuit {
+    /// Builds a circuit aggregating `arity` proofs for `inner`. Its verifier
+    /// data is fixed in the circuit.
+    pub fn new(inner: &VerifierCircuitData<F, C, D>, arity: usize, config: CircuitConfig) -> Self {
+        assert!(arity > 0, "Aggregation arity must be positive");
+        let mut builder = CircuitBuilder::<F, D>::new(config);
+        let verifier_t = builder.constant_verifier_data(&inner.verifier_only);
+        let proofs = (0..arity)
+            .map(|_| {
+                let proof_t = builder.add_virtual_proof_with_pis(&inner.common);
+                builder.verify_proof::<C>(&proof_t, &verifier_t, &inner.common);
+                proof_t
+            })
+            .collect::<Vec<_>>();
+
+        let used = (0..arity)
+            .map(|_| builder.add_virtual_bool_target_safe())
+            .collect::<Vec<_>>();
+        builder.assert_one(used[0].target);
+        for pair in used.windows(2) {
+            // A slot is used only if the previous one is.
+            let both = builder.mul(pair[0].target, pair[1].target);
+            builder.connect(both, pair[1].target);
+        }
+
+        // The public inputs of padding slots are replaced by zeros.
+        let mut hash_inputs = vec![builder.add_many(used.iter().map(|b| b.target))];
+        for (proof_t, slot_used) in proofs.iter().zip(&used) {
+            for &input in &proof_t.public_inputs {
+                hash_inputs.push(builder.mul(slot_used.target, input));
+            }
+        }
+        let commitment = builder.hash_n_to_hash_no_pad::<Poseidon2Hash>(hash_inputs);
+        builder.register_public_inputs(&commitment.elements);
+        Self {
+            data: builder.build::<C>(),
+            proofs,
+            used,
+        }
+    }
+
+    pub fn arity(&self) -> usize {
+        self.proofs.len()
+    }
+
+    /// Aggregates between one and [`Self::arity`] proofs. Missing proofs are
+    /// replaced by copies of the last one, which are verified but not part of
+    /// the commitment.
+    pub fn prove(
+        &self,
+        proofs: &[ProofWithPublicInputs<F, C, D>],
+    ) -> Result<ProofWithPublicInputs<F, C, D>> {
+        ensure!(
+            !proofs.is_empty() && proofs.len() <= self.arity(),
+            "Expected between 1 and {} proofs, got {}",
+            self.arity(),
+            proofs.len()
+        );
+        let mut pw = PartialWitness::new();
+        for (proof_t, proof) in self.proofs.iter().zip(padded(proofs, self.arity())) {
+            pw.set_proof_with_pis_target(proof_t, &proof);
+        }
+        for (i, &slot_used) in self.used.iter().enumerate() {
+            pw.set_bool_target(slot_used, i < proofs.len());
+        }
+        self.data.prove(pw)
+    }
+}
+
+/// Aggregates `proofs` of the circuit `inner` into a single proof, with a
+/// tree of [`AggregationCircuit`]s of the given arity. Each level of the tree
+/// has its own circuit, and the circuit of the root level is returned along
+/// with the root proof. Its public inputs are [`tree_commitment`] of the
+/// public inputs of `proofs`.
+pub fn aggregate_tree(
+    inner: &VerifierCircuitData<F, C, D>,
+    proofs: &[ProofWithPublicInputs<F, C, D>],
+    arity: usize,
+    config: &CircuitConfig,
+) -> Result<(AggregationCircuit, ProofWithPublicInputs<F, C, D>)> {
+    ensure!(arity >= 2, "Aggregation trees need an arity of at least 2");
+    ensure!(!proofs.is_empty(), "No proofs to aggregate");
+
+    let mut circuit = AggregationCircuit::new(inner, arity, config.clone());
+    let mut level = prove_level(&circuit, proofs)?;
+    while level.len() > 1 {
+        circuit = AggregationCircuit::new(&circuit.data.verifier_data(), arity, config.clone());
+        level = prove_level(&circuit, &level)?;
+    }
+    Ok((circuit, level.pop().unwrap()))
+}
+
+fn prove_level(
+    circuit: &AggregationCircuit,
+    proofs: &[ProofWithPublicInputs<F, C, D>],
+) -> Result<Vec<ProofWithPublicInputs<F, C, D>>> {
+    proofs
+        .chunks(circuit.arity())
+        .map(|chunk| circuit.prove(chunk))
+        .collect()
+}
+
+/// The public inputs of a proof of an [`AggregationCircuit`] of the given
+/// arity, aggregating between one and `arity` proofs with the given public
+/// inputs. This is the hash of the number of proofs, followed by their public
+/// inputs and zeros for each missing proof.
+pub fn aggregation_commitment(public_inputs: &[Vec<F>], arity: usize) -> HashOut<F> {
+    assert!(!public_inputs.is_empty() && public_inputs.len() <= arity);
+    let mut hash_inputs = vec![F::from_canonical_usize(public_inputs.len())];
+    hash_inputs.extend(public_inputs.concat());
+    let num_missing = arity - public_inputs.len();
+    hash_inputs.extend(vec![F::ZERO; num_missing * public_inputs[0].len()]);
+    Poseidon2Hash::hash_no_pad(&hash_inputs)
+}
+
+/// The public inputs of the root proof returned by [`aggregate_tree`] for
+/// proofs with the given public inputs.
+pub fn tree_commitment(public_inputs: &[Vec<F>], arity: usize) -> HashOut<F> {
+    assert!(arity >= 2 && !public_inputs.is_empty());
+    let mut level = public_inputs.to_vec();
+    loop {
+        level = level
+            .chunks(arity)
+            .map(|chunk| aggregation_commitment(chunk, arity).elements.to_vec())
+            .collect();
+        if level.len() == 1 {
+            let elements: [F; NUM_HASH_OUT_ELTS] = level[0].as_slice().try_into().unwrap();
+            return HashOut { elements };
+        }
+    }
+}
+
+/// Extends `items` to `len` items by repeating its last one.
+fn padded<T: Clone>(items: &[T], len: usize) -> Vec<T> {
+    let mut items = items.to_vec();
+    items.resize(len, items.last().unwrap().clone());
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use plonky2::iop::witness::{PartialWitness, WitnessWrite};
+    use plonky2::plonk::circuit_builder::CircuitBuilder;
+    use plonky2::plonk::circuit_data::{CircuitConfig, CircuitData};
+    use plonky2::plonk::proof::ProofWithPublicInputs;
+    use plonky2_field::types::Field;
+
+    use crate::poseidon2_hash::poseidon2::{Poseidon2Hash, WIDTH};
+    use crate::recursion::aggregation::{
+        aggregate_tree, aggregation_commitment, tree_commitment, AggregationCircuit, C, D, F,
+    };
+
+    type Proofs = Vec<ProofWithPublicInputs<F, C, D>>;
+
+    /// `n` proofs of the Poseidon2 hash of `WIDTH` inputs.
+    fn hash_proofs(n: usize) -> Result<(CircuitData<F, C, D>, Proofs)> {
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let inputs = builder.add_virtual_targets(WIDTH);
+        let hash = builder.hash_n_to_hash_no_pad::<Poseidon2Hash>(inputs.clone());
+        builder.register_public_inputs(&hash.elements);
+        let data = builder.build::<C>();
+
+        let proofs = (0..n)
+            .map(|i| {
+                let mut pw = PartialWitness::new();
+                for (j, &t) in inputs.iter().enumerate() {
+                    pw.set_target(t, F::from_canonical_usize(i * WIDTH + j));
+                }
+                data.prove(pw)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok((data, proofs))
+    }
+
+    #[test]
+    fn aggregate_pair() -> Result<()> {
+        let (data, proofs) = hash_proofs(2)?;
+        let circuit = AggregationCircuit::new(
+            &data.verifier_data(),
+            2,
+            CircuitConfig::standard_recursion_config(),
+        );
+        let proof = circuit.prove(&proofs)?;
+        let public_inputs = proofs
+            .iter()
+            .map(|p| p.public_inputs.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            proof.public_inputs,
+            aggregation_commitment(&public_inputs, 2).elements
+        );
+        assert!(circuit.prove(&[]).is_err());
+        circuit.data.verify(proof)?;
+
+        // A single proof is not committed like the pair of its copies.
+        let proof = circuit.prove(&proofs[..1])?;
+        assert_eq!(
+            proof.public_inputs,
+            aggregation_commitment(&public_inputs[..1], 2).elements
+        );
+        assert_ne!(
+            aggregation_commitment(&public_inputs[..1], 2),
+            aggregation_commitment(&[public_inputs[0].clone(), public_inputs[0].clone()], 2)
+        );
+        circuit.data.verify(proof)
+    }
+
+    #[test]
+    fn aggregate_eight() -> Result<()> {
+        let (data, proofs) = hash_proofs(8)?;
+        let (root, proof) = aggregate_tree(
+            &data.verifier_data(),
+            &proofs,
+            2,
+            &CircuitConfig::standard_recursion_config(),
+        )?;
+        let public_inputs = proofs
+            .iter()
+            .map(|p| p.public_inputs.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            proof.public_inputs,
+            tree_commitment(&public_inputs, 2).elements
+        );
+        root.data.verify(proof)
+    }
+
+    #[test]
+    fn aggregate_with_padding() -> Result<()> {
+        // 5 proofs, aggregated by 4: the last node of the first level and the
+        // root are both padded.
+        let (data, proofs) = hash_proofs(5)?;
+        let (root, proof) = aggregate_tree(
+            &data.verifier_data(),
+            &proofs,
+            4,
+            &CircuitConfig::standard_recursion_config(),
+        )?;
+        let public_inputs = proofs
+            .iter()
+            .map(|p| p.public_inputs.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            proof.public_inputs,
+            tree_commitment(&public_inputs, 4).elements
+        );
+
+        // Padding differs from repeating the last proof.
+        let mut repeated = public_inputs.clone();
+        repeated.push(public_inputs[4].clone());
+        assert_ne!(
+            tree_commitment(&repeated, 4),
+            tree_commitment(&public_inputs, 4)
+        );
+        root.data.verify(proof)
+    }
+}
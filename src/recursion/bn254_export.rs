@@ -0,0 +1,356 @@
+//! Export of [`Poseidon2Bn254GoldilocksConfig`] proofs for BN254 circuits.
+//!
+//! The proof, the verifier-only data and the common data are serialized to
+//! JSON with the serde implementations of plonky2, so the field names and
+//! nesting are those of `ProofWithPublicInputs`, `VerifierOnlyCircuitData`
+//! and `CommonCircuitData`. This is the layout read by the
+//! `ProofWithPublicInputsRaw`, `VerifierOnlyCircuitDataRaw` and
+//! `CommonCircuitDataRaw` types of gnark-plonky2-verifier. Within it:
+//!
+//! - Goldilocks elements are JSON numbers holding their canonical value, in
+//!   `[0, p)`, and extension elements arrays of `D` such numbers.
+//! - BN254 digests, i.e. Merkle cap entries and the circuit digest, are
+//!   strings of their canonical value in decimal, without leading zeros.
+//! - Gates of the common data are their `id()` strings.
+//!
+//! The digests are Poseidon2 digests, so a BN254 verifier must hash with the
+//! same Poseidon2 instance, see [`crate::poseidon2_hash::poseidon2_bn254`].
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use plonky2::plonk::circuit_data::{CircuitData, CommonCircuitData, VerifierOnlyCircuitData};
+use plonky2::plonk::proof::ProofWithPublicInputs;
+use plonky2_field::goldilocks_field::GoldilocksField;
+use plonky2_field::types::Field64;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::config::Poseidon2Bn254GoldilocksConfig;
+
+type F = GoldilocksField;
+type C = Poseidon2Bn254GoldilocksConfig;
+const D: usize = 2;
+
+pub const PROOF_FILE: &str = "proof_with_public_inputs.json";
+pub const VERIFIER_ONLY_FILE: &str = "verifier_only_circuit_data.json";
+pub const COMMON_FILE: &str = "common_circuit_data.json";
+
+/// Everything a BN254 circuit needs to verify a proof.
+#[derive(Debug, Serialize)]
+pub struct Bn254VerifierExport<'a> {
+    pub proof_with_public_inputs: &'a ProofWithPublicInputs<F, C, D>,
+    pub verifier_only_circuit_data: &'a VerifierOnlyCircuitData<C, D>,
+    pub common_circuit_data: &'a CommonCircuitData<F, D>,
+}
+
+impl<'a> Bn254VerifierExport<'a> {
+    pub fn new(data: &'a CircuitData<F, C, D>, proof: &'a ProofWithPublicInputs<F, C, D>) -> Self {
+        Self {
+            proof_with_public_inputs: proof,
+            verifier_only_circuit_data: &data.verifier_only,
+            common_circuit_data: &data.common,
+        }
+    }
+
+    /// Serializes everything into a single JSON document.
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(&to_canonical_value(self)?)?)
+    }
+
+    /// Writes [`PROOF_FILE`], [`VERIFIER_ONLY_FILE`] and [`COMMON_FILE`] into
+    /// `dir`, creating it if needed.
+    pub fn write_to_dir(&self, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        let write = |file: &str, value: Value| -> Result<()> {
+            Ok(fs::write(dir.join(file), serde_json::to_string(&value)?)?)
+        };
+        write(
+            PROOF_FILE,
+            to_canonical_value(self.proof_with_public_inputs)?,
+        )?;
+        write(
+            VERIFIER_ONLY_FILE,
+            to_canonical_value(self.verifier_only_circuit_data)?,
+        )?;
+        write(COMMON_FILE, to_canonical_value(self.common_circuit_data)?)
+    }
+}
+
+/// Serializes `value` with its serde implementation, then reduces the
+/// Goldilocks elements, which plonky2 writes as their internal `u64`, to
+/// their canonical value. All the other integers of the export, i.e. sizes
+/// and indices, are far below the field order and are left as they are.
+fn to_canonical_value<T: Serialize>(value: &T) -> Result<Value> {
+    fn canonicalize(value: &mut Value) {
+        match value {
+            Value::Number(n) => {
+                if let Some(x) = n.as_u64().filter(|&x| x >= F::ORDER) {
+                    *n = (x - F::ORDER).into();
+                }
+            }
+            Value::Array(values) => values.iter_mut().for_each(canonicalize),
+            Value::Object(values) => values.values_mut().for_each(canonicalize),
+            _ => {}
+        }
+    }
+
+    let mut value = serde_json::to_value(value)?;
+    canonicalize(&mut value);
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use plonky2::iop::witness::{PartialWitness, WitnessWrite};
+    use plonky2::plonk::circuit_builder::CircuitBuilder;
+    use plonky2::plonk::circuit_data::{CircuitConfig, CircuitData};
+    use plonky2::plonk::config::GenericConfig;
+    use plonky2::plonk::proof::ProofWithPublicInputs;
+    use plonky2_field::goldilocks_field::GoldilocksField;
+    use plonky2_field::types::{Field, Field64, PrimeField64, Sample};
+    use serde_json::{json, Value};
+
+    use crate::config::{Poseidon2Bn254GoldilocksConfig, Poseidon2GoldilocksConfig};
+    use crate::poseidon2_hash::poseidon2::{Poseidon2Hash, WIDTH};
+    use crate::recursion::bn254_export::{Bn254VerifierExport, PROOF_FILE};
+    use crate::recursion::wrap_proof_bn254;
+
+    const D: usize = 2;
+    type C = Poseidon2GoldilocksConfig;
+    type F = <C as GenericConfig<D>>::F;
+    type OuterC = Poseidon2Bn254GoldilocksConfig;
+
+    /// The wrapper circuit, the wrapped proof and the inner proof.
+    type WrappedProof = (
+        CircuitData<F, OuterC, D>,
+        ProofWithPublicInputs<F, OuterC, D>,
+        ProofWithPublicInputs<F, C, D>,
+    );
+
+    /// The BN254 scalar field modulus in decimal.
+    const BN254_MODULUS: &str =
+        "21888242871839275222246405745257275088548364400416034343698204186575808495617";
+
+    fn wrapped_proof() -> Result<WrappedProof> {
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let inputs = builder.add_virtual_targets(WIDTH);
+        let hash = builder.hash_n_to_hash_no_pad::<Poseidon2Hash>(inputs.clone());
+        builder.register_public_inputs(&hash.elements);
+        let inner_data = builder.build::<C>();
+
+        let mut pw = PartialWitness::new();
+        for (i, &t) in inputs.iter().enumerate() {
+            pw.set_target(t, F::from_canonical_usize(i));
+        }
+        let inner_proof = inner_data.prove(pw)?;
+
+        let (data, proof) = wrap_proof_bn254(&inner_proof, &inner_data)?;
+        Ok((data, proof, inner_proof))
+    }
+
+    /// The layout of the `*Raw` types of gnark-plonky2-verifier, into which it
+    /// unmarshals the proof and the verifier-only data. A string is the kind
+    /// of a leaf, a one-element array a list of its element and an object a
+    /// struct with these fields.
+    fn gnark_layout() -> Value {
+        let ext_list = json!(["ext"]);
+        json!({
+            "proof": {
+                "proof": {
+                    "wires_cap": ["digest"],
+                    "plonk_zs_partial_products_cap": ["digest"],
+                    "quotient_polys_cap": ["digest"],
+                    "openings": {
+                        "constants": ext_list,
+                        "plonk_sigmas": ext_list,
+                        "wires": ext_list,
+                        "plonk_zs": ext_list,
+                        "plonk_zs_next": ext_list,
+                        "partial_products": ext_list,
+                        "quotient_polys": ext_list,
+                        "lookup_zs": ext_list,
+                        "lookup_zs_next": ext_list,
+                    },
+                    "opening_proof": {
+                        "commit_phase_merkle_caps": [["digest"]],
+                        "query_round_proofs": [{
+                            "initial_trees_proof": { "evals_proofs": ["evals_proof"] },
+                            "steps": [{
+                                "evals": ext_list,
+                                "merkle_proof": { "siblings": ["digest"] },
+                            }],
+                        }],
+                        "final_poly": { "coeffs": ext_list },
+                        "pow_witness": "goldilocks",
+                    },
+                },
+                "public_inputs": ["goldilocks"],
+            },
+            "verifier_only": {
+                "constants_sigmas_cap": ["digest"],
+                "circuit_digest": "digest",
+            },
+        })
+    }
+
+    fn check_layout(value: &Value, layout: &Value, path: &str) {
+        let is_goldilocks = |v: &Value| v.as_u64().is_some_and(|x| x < F::ORDER);
+        match layout {
+            Value::String(kind) => match kind.as_str() {
+                "goldilocks" => assert!(is_goldilocks(value), "{path}: {value}"),
+                "ext" => {
+                    let limbs = value
+                        .as_array()
+                        .unwrap_or_else(|| panic!("{path}: {value}"));
+                    assert!(
+                        limbs.len() == D && limbs.iter().all(is_goldilocks),
+                        "{path}"
+                    );
+                }
+                "digest" => {
+                    let s = value.as_str().unwrap_or_else(|| panic!("{path}: {value}"));
+                    assert!(
+                        !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()),
+                        "{path}"
+                    );
+                    assert!(s == "0" || !s.starts_with('0'), "{path}: {s}");
+                    let below_modulus = s.len() < BN254_MODULUS.len()
+                        || (s.len() == BN254_MODULUS.len() && s < BN254_MODULUS);
+                    assert!(below_modulus, "{path}: {s}");
+                }
+                // Unmarshalled by `EvalProofRaw` from `[leaf_elements, merkle_proof]`.
+                "evals_proof" => {
+                    let tuple = value
+                        .as_array()
+                        .unwrap_or_else(|| panic!("{path}: {value}"));
+                    assert_eq!(tuple.len(), 2, "{path}");
+                    check_layout(&tuple[0], &json!(["goldilocks"]), path);
+                    check_layout(&tuple[1], &json!({ "siblings": ["digest"] }), path);
+                }
+                _ => unreachable!(),
+            },
+            Value::Array(element) => {
+                let values = value
+                    .as_array()
+                    .unwrap_or_else(|| panic!("{path}: {value}"));
+                for (i, v) in values.iter().enumerate() {
+                    check_layout(v, &element[0], &format!("{path}[{i}]"));
+                }
+            }
+            Value::Object(fields) => {
+                for (name, field_layout) in fields {
+                    let field = value
+                        .get(name)
+                        .unwrap_or_else(|| panic!("{path}: missing {name}"));
+                    check_layout(field, field_layout, &format!("{path}.{name}"));
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Converts a decimal string to big-endian hexadecimal by schoolbook
+    /// multiplication, independently of [`Bn254Fr`]'s decimal parsing.
+    fn decimal_to_hex(s: &str) -> String {
+        // Little-endian base 2^32 digits.
+        let mut digits = vec![0u32];
+        for byte in s.bytes() {
+            let mut carry = (byte - b'0') as u64;
+            for digit in digits.iter_mut() {
+                let cur = *digit as u64 * 10 + carry;
+                *digit = cur as u32;
+                carry = cur >> 32;
+            }
+            if carry != 0 {
+                digits.push(carry as u32);
+            }
+        }
+        let hex: String = digits.iter().rev().map(|d| format!("{d:08x}")).collect();
+        let hex = hex.trim_start_matches('0');
+        format!("0x{}", if hex.is_empty() { "0" } else { hex })
+    }
+
+    #[test]
+    fn bn254_wrap_and_export() -> Result<()> {
+        let (data, proof, inner_proof) = wrapped_proof()?;
+        assert_eq!(proof.public_inputs, inner_proof.public_inputs);
+        data.verify(proof.clone())?;
+
+        let export = Bn254VerifierExport::new(&data, &proof);
+        let json: serde_json::Value = serde_json::from_str(&export.to_json()?)?;
+        let digest = json["verifier_only_circuit_data"]["circuit_digest"]
+            .as_str()
+            .unwrap();
+        assert_eq!(
+            decimal_to_hex(digest),
+            data.verifier_only.circuit_digest.0.to_string()
+        );
+
+        let dir = std::env::temp_dir().join(format!(
+            "plonky2_poseidon2_bn254_export_{}_{:016x}",
+            std::process::id(),
+            F::rand().to_canonical_u64()
+        ));
+        export.write_to_dir(&dir)?;
+        let read_proof = std::fs::read_to_string(dir.join(PROOF_FILE));
+        std::fs::remove_dir_all(&dir)?;
+        let read_proof: ProofWithPublicInputs<F, Poseidon2Bn254GoldilocksConfig, D> =
+            serde_json::from_str(&read_proof?)?;
+        assert_eq!(read_proof, proof);
+        data.verify(read_proof)
+    }
+
+    #[test]
+    fn bn254_export_matches_gnark_layout() -> Result<()> {
+        let (data, mut proof, _) = wrapped_proof()?;
+        // A non-canonical encoding of 5, which must be exported as 5.
+        proof.public_inputs[0] = GoldilocksField(GoldilocksField::ORDER + 5);
+
+        let export = Bn254VerifierExport::new(&data, &proof);
+        let json: Value = serde_json::from_str(&export.to_json()?)?;
+        let layout = gnark_layout();
+        check_layout(
+            &json["proof_with_public_inputs"],
+            &layout["proof"],
+            "proof_with_public_inputs",
+        );
+        check_layout(
+            &json["verifier_only_circuit_data"],
+            &layout["verifier_only"],
+            "verifier_only_circuit_data",
+        );
+        let gates = json["common_circuit_data"]["gates"].as_array().unwrap();
+        assert!(gates.iter().all(Value::is_string));
+
+        let public_inputs = &json["proof_with_public_inputs"]["public_inputs"];
+        assert_eq!(public_inputs[0], 5);
+        for (value, x) in public_inputs
+            .as_array()
+            .unwrap()
+            .iter()
+            .zip(&proof.public_inputs)
+        {
+            assert_eq!(value.as_u64(), Some(x.to_canonical_u64()));
+        }
+        let caps = json["verifier_only_circuit_data"]["constants_sigmas_cap"]
+            .as_array()
+            .unwrap();
+        assert_eq!(caps.len(), data.verifier_only.constants_sigmas_cap.0.len());
+        for (value, digest) in caps.iter().zip(&data.verifier_only.constants_sigmas_cap.0) {
+            assert_eq!(
+                decimal_to_hex(value.as_str().unwrap()),
+                digest.0.to_string()
+            );
+        }
+
+        // The canonical export reads back as the same proof.
+        let read_proof: ProofWithPublicInputs<F, OuterC, D> =
+            serde_json::from_value(json["proof_with_public_inputs"].clone())?;
+        assert_eq!(read_proof.public_inputs[0], F::from_canonical_u64(5));
+        assert_eq!(read_proof.proof, proof.proof);
+        Ok(())
+    }
+}
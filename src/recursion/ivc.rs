@@ -0,0 +1,269 @@
+//! Incrementally verifiable computation of a Poseidon2 hash chain, with
+//! plonky2's cyclic recursion.
+//!
+//! Each step absorbs a batch of elements into the chain,
+//! `hash = Poseidon2Hash::hash_no_pad(hash || batch)`, see
+//! [`extend_hash_chain`], and verifies the proof of the previous step. Proofs
+//! have the same size whatever the length of the chain. Their public inputs
+//! are:
+//! - the initial hash of the chain (4),
+//! - the current hash of the chain (4),
+//! - the number of steps (1),
+//! - the verifier data of the cyclic circuit (`4 + 4 * num_cap_elements`).
+
+use anyhow::{ensure, Result};
+use plonky2::gates::constant::ConstantGate;
+use plonky2::gates::gate::GateRef;
+use plonky2::gates::noop::NoopGate;
+use plonky2::hash::hash_types::{HashOut, HashOutTarget, NUM_HASH_OUT_ELTS};
+use plonky2::iop::target::{BoolTarget, Target};
+use plonky2::iop::witness::{PartialWitness, WitnessWrite};
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+use plonky2::plonk::circuit_data::{
+    CircuitConfig, CircuitData, CommonCircuitData, VerifierCircuitTarget,
+};
+use plonky2::plonk::config::Hasher;
+use plonky2::plonk::proof::{ProofWithPublicInputs, ProofWithPublicInputsTarget};
+use plonky2::recursion::cyclic_recursion::check_cyclic_proof_verifier_data;
+use plonky2::recursion::dummy_circuit::cyclic_base_proof;
+use plonky2_field::goldilocks_field::GoldilocksField;
+use plonky2_field::types::PrimeField64;
+
+use crate::config::Poseidon2GoldilocksConfig;
+use crate::poseidon2_hash::poseidon2::Poseidon2Hash;
+
+type F = GoldilocksField;
+type C = Poseidon2GoldilocksConfig;
+const D: usize = 2;
+
+/// Degree bits of the cyclic circuit. A step must fit next to the verifier of
+/// the previous step, which takes a bit more than `2^12` rows.
+const CYCLIC_DEGREE_BITS: usize = 13;
+
+/// Rows kept free for the gates added by `CircuitBuilder::build`, which hash
+/// the public inputs and hold the constants.
+const BUILD_MARGIN: usize = 128;
+
+/// Absorbs `batch` into the hash chain whose current hash is `hash`.
+pub fn extend_hash_chain(hash: HashOut<F>, batch: &[F]) -> HashOut<F> {
+    let mut inputs = hash.elements.to_vec();
+    inputs.extend_from_slice(batch);
+    Poseidon2Hash::hash_no_pad(&inputs)
+}
+
+/// The cyclic circuit proving one step of a hash chain, for batches of a fixed
+/// size.
+pub struct HashChainIvc {
+    pub data: CircuitData<F, C, D>,
+    initial_hash: HashOutTarget,
+    batch: Vec<Target>,
+    condition: BoolTarget,
+    inner_proof: ProofWithPublicInputsTarget<D>,
+    verifier_data: VerifierCircuitTarget,
+}
+
+impl HashChainIvc {
+    /// Builds the cyclic circuit for batches of `batch_size` elements. Fails if
+    /// a step does not fit in `2^13` rows, i.e. for batches of more than about
+    /// 30000 elements.
+    pub fn new(batch_size: usize) -> Result<Self> {
+        let config = CircuitConfig::standard_recursion_config();
+        let mut builder = CircuitBuilder::<F, D>::new(config);
+        let one = builder.one();
+
+        let initial_hash = builder.add_virtual_hash();
+        builder.register_public_inputs(&initial_hash.elements);
+        let current_hash = builder.add_virtual_hash();
+        builder.register_public_inputs(&current_hash.elements);
+        let num_steps = builder.add_virtual_public_input();
+        let verifier_data = builder.add_verifier_data_public_inputs();
+
+        let mut common = common_data_for_recursion();
+        common.num_public_inputs = builder.num_public_inputs();
+
+        let condition = builder.add_virtual_bool_target_safe();
+        let inner_proof = builder.add_virtual_proof_with_pis(&common);
+        let inner_pis = &inner_proof.public_inputs;
+        let inner_initial_hash = HashOutTarget::try_from(&inner_pis[0..4]).unwrap();
+        let inner_current_hash = HashOutTarget::try_from(&inner_pis[4..8]).unwrap();
+        let inner_num_steps = inner_pis[8];
+
+        // In the base case there is no inner proof, and the initial hash is
+        // chosen by the prover.
+        builder.connect_hashes(initial_hash, inner_initial_hash);
+        let previous_hash = HashOutTarget::from_vec(
+            (0..NUM_HASH_OUT_ELTS)
+                .map(|i| {
+                    builder.select(
+                        condition,
+                        inner_current_hash.elements[i],
+                        initial_hash.elements[i],
+                    )
+                })
+                .collect(),
+        );
+        let batch = builder.add_virtual_targets(batch_size);
+        let mut inputs = previous_hash.elements.to_vec();
+        inputs.extend(&batch);
+        let next_hash = builder.hash_n_to_hash_no_pad::<Poseidon2Hash>(inputs);
+        builder.connect_hashes(current_hash, next_hash);
+        let next_num_steps = builder.mul_add(condition.target, inner_num_steps, one);
+        builder.connect(num_steps, next_num_steps);
+
+        builder.conditionally_verify_cyclic_proof_or_dummy::<C>(
+            condition,
+            &inner_proof,
+            &common,
+        )?;
+        ensure!(
+            builder.num_gates() + BUILD_MARGIN <= 1 << CYCLIC_DEGREE_BITS,
+            "A step with {} elements does not fit in 2^{} rows",
+            batch_size,
+            CYCLIC_DEGREE_BITS
+        );
+
+        Ok(Self {
+            data: builder.build::<C>(),
+            initial_hash,
+            batch,
+            condition,
+            inner_proof,
+            verifier_data,
+        })
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.batch.len()
+    }
+
+    /// Proves the first step of the chain starting at `initial_hash`.
+    pub fn prove_first_step(
+        &self,
+        initial_hash: HashOut<F>,
+        batch: &[F],
+    ) -> Result<ProofWithPublicInputs<F, C, D>> {
+        let base_proof = cyclic_base_proof(
+            &self.data.common,
+            &self.data.verifier_only,
+            initial_hash.elements.into_iter().enumerate().collect(),
+        );
+        let mut pw = PartialWitness::new();
+        pw.set_hash_target(self.initial_hash, initial_hash);
+        pw.set_bool_target(self.condition, false);
+        pw.set_proof_with_pis_target(&self.inner_proof, &base_proof);
+        self.prove(pw, batch)
+    }
+
+    /// Proves the step following the one proven by `previous`.
+    pub fn prove_step(
+        &self,
+        previous: &ProofWithPublicInputs<F, C, D>,
+        batch: &[F],
+    ) -> Result<ProofWithPublicInputs<F, C, D>> {
+        let mut pw = PartialWitness::new();
+        pw.set_bool_target(self.condition, true);
+        pw.set_proof_with_pis_target(&self.inner_proof, previous);
+        self.prove(pw, batch)
+    }
+
+    fn prove(
+        &self,
+        mut pw: PartialWitness<F>,
+        batch: &[F],
+    ) -> Result<ProofWithPublicInputs<F, C, D>> {
+        ensure!(
+            batch.len() == self.batch_size(),
+            "Expected a batch of {} elements, got {}",
+            self.batch_size(),
+            batch.len()
+        );
+        pw.set_target_arr(&self.batch, batch);
+        pw.set_verifier_data_target(&self.verifier_data, &self.data.verifier_only);
+        self.data.prove(pw)
+    }
+
+    /// Verifies a proof of any step, including that the verifier data in its
+    /// public inputs is the one of this circuit.
+    pub fn verify(&self, proof: &ProofWithPublicInputs<F, C, D>) -> Result<()> {
+        check_cyclic_proof_verifier_data(proof, &self.data.verifier_only, &self.data.common)?;
+        self.data.verify(proof.clone())
+    }
+
+    pub fn initial_hash(proof: &ProofWithPublicInputs<F, C, D>) -> HashOut<F> {
+        HashOut::from_partial(&proof.public_inputs[..NUM_HASH_OUT_ELTS])
+    }
+
+    pub fn current_hash(proof: &ProofWithPublicInputs<F, C, D>) -> HashOut<F> {
+        HashOut::from_partial(&proof.public_inputs[NUM_HASH_OUT_ELTS..2 * NUM_HASH_OUT_ELTS])
+    }
+
+    pub fn num_steps(proof: &ProofWithPublicInputs<F, C, D>) -> u64 {
+        proof.public_inputs[2 * NUM_HASH_OUT_ELTS].to_canonical_u64()
+    }
+}
+
+/// Common data of a circuit with `2^CYCLIC_DEGREE_BITS` rows verifying a
+/// proof of a circuit of the same shape, which the cyclic circuit must match.
+fn common_data_for_recursion() -> CommonCircuitData<F, D> {
+    let config = CircuitConfig::standard_recursion_config();
+    let builder = CircuitBuilder::<F, D>::new(config.clone());
+    let data = builder.build::<C>();
+
+    let mut builder = CircuitBuilder::<F, D>::new(config.clone());
+    let proof = builder.add_virtual_proof_with_pis(&data.common);
+    let verifier_data = builder.add_virtual_verifier_data(data.common.config.fri_config.cap_height);
+    builder.verify_proof::<C>(&proof, &verifier_data, &data.common);
+    let data = builder.build::<C>();
+
+    let mut builder = CircuitBuilder::<F, D>::new(config.clone());
+    let proof = builder.add_virtual_proof_with_pis(&data.common);
+    let verifier_data = builder.add_virtual_verifier_data(data.common.config.fri_config.cap_height);
+    builder.verify_proof::<C>(&proof, &verifier_data, &data.common);
+    while builder.num_gates() + BUILD_MARGIN < 1 << CYCLIC_DEGREE_BITS {
+        builder.add_gate(NoopGate, vec![]);
+    }
+    // The dummy proofs used in the base case need a `ConstantGate` to hash
+    // their public inputs.
+    builder.add_gate_to_gate_set(GateRef::new(ConstantGate::new(config.num_constants)));
+    let common = builder.build::<C>().common;
+    debug_assert_eq!(common.degree_bits(), CYCLIC_DEGREE_BITS);
+    common
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use plonky2::hash::hash_types::HashOut;
+    use plonky2_field::types::{Field, Sample};
+
+    use crate::recursion::ivc::{extend_hash_chain, HashChainIvc, F};
+
+    #[test]
+    fn hash_chain() -> Result<()> {
+        let ivc = HashChainIvc::new(16)?;
+        let initial_hash = HashOut::<F>::rand();
+        let mut hash = initial_hash;
+
+        let batch = F::rand_vec(ivc.batch_size());
+        let mut proof = ivc.prove_first_step(initial_hash, &batch)?;
+        hash = extend_hash_chain(hash, &batch);
+        ivc.verify(&proof)?;
+        for _ in 0..3 {
+            let batch = F::rand_vec(ivc.batch_size());
+            proof = ivc.prove_step(&proof, &batch)?;
+            hash = extend_hash_chain(hash, &batch);
+            ivc.verify(&proof)?;
+        }
+
+        assert_eq!(HashChainIvc::initial_hash(&proof), initial_hash);
+        assert_eq!(HashChainIvc::current_hash(&proof), hash);
+        assert_eq!(HashChainIvc::num_steps(&proof), 4);
+        assert!(ivc.prove_step(&proof, &[F::ONE]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn batch_too_large() {
+        assert!(HashChainIvc::new(1 << 16).is_err());
+    }
+}
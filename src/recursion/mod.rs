@@ -0,0 +1,223 @@
+//! Helpers to recursively verify Poseidon2 proofs.
+
+pub mod aggregation;
+pub mod bn254_export;
+pub mod ivc;
+pub mod shrink;
+
+use anyhow::Result;
+use plonky2::hash::hash_types::RichField;
+use plonky2::iop::witness::{PartialWitness, WitnessWrite};
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+use plonky2::plonk::circuit_data::{CircuitConfig, CircuitData, CommonCircuitData, VerifierOnlyCircuitData};
+use plonky2::plonk::config::{AlgebraicHasher, GenericConfig, PoseidonGoldilocksConfig};
+use plonky2::plonk::proof::ProofWithPublicInputs;
+use plonky2_field::extension::Extendable;
+use plonky2_field::goldilocks_field::GoldilocksField;
+
+use crate::config::{
+    Poseidon2Bn254GoldilocksConfig, Poseidon2GoldilocksConfig, Poseidon2KeccakGoldilocksConfig,
+};
+
+/// Proves, under the config `OC`, that `inner_proof` is a valid proof for the
+/// circuit described by `inner_verifier` and `inner_common`. The verifier data
+/// of the inner circuit is fixed in the wrapper circuit, and the public inputs
+/// of the inner proof are forwarded as the public inputs of the wrapper proof.
+pub fn wrap_proof<F, C, OC, const D: usize>(
+    inner_proof: &ProofWithPublicInputs<F, C, D>,
+    inner_verifier: &VerifierOnlyCircuitData<C, D>,
+    inner_common: &CommonCircuitData<F, D>,
+    config: CircuitConfig,
+) -> Result<(CircuitData<F, OC, D>, ProofWithPublicInputs<F, OC, D>)>
+where
+    F: RichField + Extendable<D>,
+    C: GenericConfig<D, F = F>,
+    OC: GenericConfig<D, F = F>,
+    C::Hasher: AlgebraicHasher<F>,
+{
+    let mut builder = CircuitBuilder::<F, D>::new(config);
+    let proof_t = builder.add_virtual_proof_with_pis(inner_common);
+    let verifier_t = builder.constant_verifier_data(inner_verifier);
+    builder.verify_proof::<C>(&proof_t, &verifier_t, inner_common);
+    builder.register_public_inputs(&proof_t.public_inputs);
+    let data = builder.build::<OC>();
+
+    let mut pw = PartialWitness::new();
+    pw.set_proof_with_pis_target(&proof_t, inner_proof);
+    let proof = data.prove(pw)?;
+    Ok((data, proof))
+}
+
+/// Verifies a [`PoseidonGoldilocksConfig`] proof inside a
+/// [`Poseidon2GoldilocksConfig`] circuit. The resulting circuit uses both the
+/// Poseidon and the Poseidon2 gates, which
+/// [`crate::serialization::Poseidon2GateSerializer`] both supports.
+pub fn wrap_poseidon_proof_in_poseidon2(
+    inner_proof: &ProofWithPublicInputs<GoldilocksField, PoseidonGoldilocksConfig, 2>,
+    inner_data: &CircuitData<GoldilocksField, PoseidonGoldilocksConfig, 2>,
+) -> Result<(
+    CircuitData<GoldilocksField, Poseidon2GoldilocksConfig, 2>,
+    ProofWithPublicInputs<GoldilocksField, Poseidon2GoldilocksConfig, 2>,
+)> {
+    wrap_proof(
+        inner_proof,
+        &inner_data.verifier_only,
+        &inner_data.common,
+        CircuitConfig::standard_recursion_config(),
+    )
+}
+
+/// Verifies a [`Poseidon2GoldilocksConfig`] proof inside a
+/// [`PoseidonGoldilocksConfig`] circuit.
+pub fn wrap_poseidon2_proof_in_poseidon(
+    inner_proof: &ProofWithPublicInputs<GoldilocksField, Poseidon2GoldilocksConfig, 2>,
+    inner_data: &CircuitData<GoldilocksField, Poseidon2GoldilocksConfig, 2>,
+) -> Result<(
+    CircuitData<GoldilocksField, PoseidonGoldilocksConfig, 2>,
+    ProofWithPublicInputs<GoldilocksField, PoseidonGoldilocksConfig, 2>,
+)> {
+    wrap_proof(
+        inner_proof,
+        &inner_data.verifier_only,
+        &inner_data.common,
+        CircuitConfig::standard_recursion_config(),
+    )
+}
+
+/// Wraps a Poseidon2 proof into a [`Poseidon2KeccakGoldilocksConfig`] proof,
+/// whose Merkle caps and Fiat-Shamir challenges use Keccak so that it can be
+/// checked cheaply by an on-chain verifier.
+pub fn wrap_proof_keccak(
+    inner_proof: &ProofWithPublicInputs<GoldilocksField, Poseidon2GoldilocksConfig, 2>,
+    inner_data: &CircuitData<GoldilocksField, Poseidon2GoldilocksConfig, 2>,
+) -> Result<(
+    CircuitData<GoldilocksField, Poseidon2KeccakGoldilocksConfig, 2>,
+    ProofWithPublicInputs<GoldilocksField, Poseidon2KeccakGoldilocksConfig, 2>,
+)> {
+    wrap_proof(
+        inner_proof,
+        &inner_data.verifier_only,
+        &inner_data.common,
+        CircuitConfig::standard_recursion_config(),
+    )
+}
+
+/// Wraps a Poseidon2 proof into a [`Poseidon2Bn254GoldilocksConfig`] proof,
+/// the last recursion layer before verifying it inside a BN254-based SNARK.
+/// See [`bn254_export`] to export the result.
+pub fn wrap_proof_bn254(
+    inner_proof: &ProofWithPublicInputs<GoldilocksField, Poseidon2GoldilocksConfig, 2>,
+    inner_data: &CircuitData<GoldilocksField, Poseidon2GoldilocksConfig, 2>,
+) -> Result<(
+    CircuitData<GoldilocksField, Poseidon2Bn254GoldilocksConfig, 2>,
+    ProofWithPublicInputs<GoldilocksField, Poseidon2Bn254GoldilocksConfig, 2>,
+)> {
+    wrap_proof(
+        inner_proof,
+        &inner_data.verifier_only,
+        &inner_data.common,
+        CircuitConfig::standard_recursion_config(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use plonky2::iop::witness::{PartialWitness, WitnessWrite};
+    use plonky2::plonk::circuit_builder::CircuitBuilder;
+    use plonky2::plonk::circuit_data::{CircuitConfig, CircuitData};
+    use plonky2::plonk::config::{AlgebraicHasher, GenericConfig, PoseidonGoldilocksConfig};
+    use plonky2::plonk::proof::ProofWithPublicInputs;
+    use plonky2_field::goldilocks_field::GoldilocksField;
+    use plonky2_field::types::Field;
+
+    use crate::config::{
+        Poseidon2GoldilocksConfig, Poseidon2PoseidonGoldilocksConfig,
+        PoseidonPoseidon2GoldilocksConfig,
+    };
+    use crate::poseidon2_hash::poseidon2::{Poseidon2Hash, WIDTH};
+    use crate::recursion::{
+        wrap_poseidon2_proof_in_poseidon, wrap_poseidon_proof_in_poseidon2, wrap_proof,
+        wrap_proof_keccak,
+    };
+    use crate::serialization::{Poseidon2GateSerializer, Poseidon2GeneratorSerializer};
+
+    const D: usize = 2;
+    type F = GoldilocksField;
+
+    /// A proof of a single Poseidon2 hash under the config `C`.
+    fn hash_proof<C: GenericConfig<D, F = F>>(
+    ) -> Result<(CircuitData<F, C, D>, ProofWithPublicInputs<F, C, D>)> {
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let inputs = builder.add_virtual_targets(WIDTH);
+        let hash = builder.hash_n_to_hash_no_pad::<Poseidon2Hash>(inputs.clone());
+        builder.register_public_inputs(&hash.elements);
+        let data = builder.build::<C>();
+
+        let mut pw = PartialWitness::new();
+        for (i, &t) in inputs.iter().enumerate() {
+            pw.set_target(t, F::from_canonical_usize(i));
+        }
+        let proof = data.prove(pw)?;
+        Ok((data, proof))
+    }
+
+    /// Round-trips the circuit data through the serializers of this crate.
+    fn assert_serializable<C: GenericConfig<D, F = F> + Default + 'static>(
+        data: &CircuitData<F, C, D>,
+    ) -> Result<()>
+    where
+        C::Hasher: AlgebraicHasher<F>,
+    {
+        let gate_serializer = Poseidon2GateSerializer;
+        let generator_serializer = Poseidon2GeneratorSerializer::<C, D>::default();
+        let bytes = data
+            .to_bytes(&gate_serializer, &generator_serializer)
+            .map_err(|_| anyhow::anyhow!("failed to serialize circuit data"))?;
+        let data_from_bytes =
+            CircuitData::<F, C, D>::from_bytes(&bytes, &gate_serializer, &generator_serializer)
+                .map_err(|_| anyhow::anyhow!("failed to deserialize circuit data"))?;
+        assert_eq!(*data, data_from_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn poseidon_in_poseidon2() -> Result<()> {
+        let (inner_data, inner_proof) = hash_proof::<PoseidonGoldilocksConfig>()?;
+        let (data, proof) = wrap_poseidon_proof_in_poseidon2(&inner_proof, &inner_data)?;
+        assert_eq!(proof.public_inputs, inner_proof.public_inputs);
+        assert_serializable(&data)?;
+        data.verify(proof)
+    }
+
+    #[test]
+    fn poseidon2_in_poseidon() -> Result<()> {
+        let (inner_data, inner_proof) = hash_proof::<Poseidon2GoldilocksConfig>()?;
+        let (data, proof) = wrap_poseidon2_proof_in_poseidon(&inner_proof, &inner_data)?;
+        assert_eq!(proof.public_inputs, inner_proof.public_inputs);
+        assert_serializable(&data)?;
+        data.verify(proof)
+    }
+
+    #[test]
+    fn mixed_configs() -> Result<()> {
+        let (inner_data, inner_proof) = hash_proof::<PoseidonPoseidon2GoldilocksConfig>()?;
+        inner_data.verify(inner_proof.clone())?;
+        let (data, proof) = wrap_proof::<F, _, Poseidon2PoseidonGoldilocksConfig, D>(
+            &inner_proof,
+            &inner_data.verifier_only,
+            &inner_data.common,
+            CircuitConfig::standard_recursion_config(),
+        )?;
+        assert_eq!(proof.public_inputs, inner_proof.public_inputs);
+        data.verify(proof)
+    }
+
+    #[test]
+    fn keccak_wrap() -> Result<()> {
+        let (inner_data, inner_proof) = hash_proof::<Poseidon2GoldilocksConfig>()?;
+        let (data, proof) = wrap_proof_keccak(&inner_proof, &inner_data)?;
+        assert_eq!(proof.public_inputs, inner_proof.public_inputs);
+        data.verify(proof)
+    }
+}
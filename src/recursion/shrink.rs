@@ -0,0 +1,173 @@
+//! Shrinking of [`Poseidon2GoldilocksConfig`] proofs by recursion.
+//!
+//! Each layer proves that the proof of the previous layer is valid. The first
+//! layers bring the circuit down to the size of a recursive verifier, and the
+//! last ones trade proving time for a smaller proof, e.g. with
+//! [`small_proof_config`].
+
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use plonky2::plonk::circuit_data::{
+    CircuitConfig, CircuitData, CommonCircuitData, VerifierOnlyCircuitData,
+};
+use plonky2::plonk::proof::ProofWithPublicInputs;
+use plonky2_field::goldilocks_field::GoldilocksField;
+
+use crate::config::presets::{recursion_friendly_config, small_proof_config};
+use crate::config::Poseidon2GoldilocksConfig;
+use crate::recursion::wrap_proof;
+
+type F = GoldilocksField;
+type C = Poseidon2GoldilocksConfig;
+const D: usize = 2;
+
+/// Statistics of one recursion layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LayerReport {
+    pub degree_bits: usize,
+    /// Size in bytes of the proof of the layer, as given by
+    /// `ProofWithPublicInputs::to_bytes`.
+    pub proof_size: usize,
+    /// Time taken to build the layer circuit and prove it.
+    pub time: Duration,
+}
+
+/// The last layer of a shrinking pipeline.
+pub struct ShrunkProof {
+    pub data: CircuitData<F, C, D>,
+    pub proof: ProofWithPublicInputs<F, C, D>,
+    /// One report per layer, in order.
+    pub layers: Vec<LayerReport>,
+}
+
+/// The layers used by [`shrink_proof`] by default: one with
+/// [`recursion_friendly_config`], after which the circuit has the size of a
+/// recursive verifier (`2^12` rows), then one with [`small_proof_config`].
+/// Further layers with the same configs would not make the proof smaller.
+pub fn default_shrink_configs() -> Vec<CircuitConfig> {
+    vec![recursion_friendly_config(), small_proof_config()]
+}
+
+/// Recursively wraps `inner_proof` with one layer per config of `configs`,
+/// stopping as soon as the proof is at most `target_size` bytes. At least one
+/// layer is always proven, so that the result has its own verifier data.
+///
+/// The public inputs of `inner_proof` are forwarded through every layer.
+/// Fails if the proof is still larger than `target_size` after the last layer.
+pub fn shrink_proof(
+    inner_proof: &ProofWithPublicInputs<F, C, D>,
+    inner_verifier: &VerifierOnlyCircuitData<C, D>,
+    inner_common: &CommonCircuitData<F, D>,
+    configs: &[CircuitConfig],
+    target_size: usize,
+) -> Result<ShrunkProof> {
+    let Some((first, rest)) = configs.split_first() else {
+        bail!("At least one recursion layer is needed");
+    };
+
+    let mut layers = Vec::with_capacity(configs.len());
+    let start = Instant::now();
+    let (mut data, mut proof) =
+        wrap_proof::<F, C, C, D>(inner_proof, inner_verifier, inner_common, first.clone())?;
+    layers.push(report(&data, &proof, start.elapsed()));
+
+    for config in rest {
+        if layers.last().unwrap().proof_size <= target_size {
+            break;
+        }
+        let start = Instant::now();
+        (data, proof) =
+            wrap_proof::<F, C, C, D>(&proof, &data.verifier_only, &data.common, config.clone())?;
+        layers.push(report(&data, &proof, start.elapsed()));
+    }
+
+    let size = layers.last().unwrap().proof_size;
+    if size > target_size {
+        bail!(
+            "Proof is {} bytes after {} layers, above the target of {} bytes",
+            size,
+            layers.len(),
+            target_size
+        );
+    }
+    Ok(ShrunkProof {
+        data,
+        proof,
+        layers,
+    })
+}
+
+fn report(
+    data: &CircuitData<F, C, D>,
+    proof: &ProofWithPublicInputs<F, C, D>,
+    time: Duration,
+) -> LayerReport {
+    let layer = LayerReport {
+        degree_bits: data.common.degree_bits(),
+        proof_size: proof.to_bytes().len(),
+        time,
+    };
+    log::info!(
+        "Shrinking layer: degree bits {}, proof size {} bytes, {:?}",
+        layer.degree_bits,
+        layer.proof_size,
+        layer.time
+    );
+    layer
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use plonky2::hash::hashing::PlonkyPermutation;
+    use plonky2::iop::witness::{PartialWitness, WitnessWrite};
+    use plonky2::plonk::circuit_builder::CircuitBuilder;
+    use plonky2_field::types::Field;
+
+    use crate::config::presets::recursion_friendly_config;
+    use crate::poseidon2_hash::poseidon2::{Poseidon2Hash, Poseidon2Permutation, WIDTH};
+    use crate::recursion::shrink::{default_shrink_configs, shrink_proof, C, D, F};
+
+    #[test]
+    fn shrink() -> Result<()> {
+        let mut builder = CircuitBuilder::<F, D>::new(recursion_friendly_config());
+        let inputs = builder.add_virtual_targets(WIDTH);
+        let mut state = Poseidon2Permutation::new(inputs.clone());
+        for _ in 0..1024 {
+            state = builder.permute::<Poseidon2Hash>(state);
+        }
+        builder.register_public_inputs(state.as_ref());
+        let data = builder.build::<C>();
+        let mut pw = PartialWitness::new();
+        for (i, &t) in inputs.iter().enumerate() {
+            pw.set_target(t, F::from_canonical_usize(i));
+        }
+        let proof = data.prove(pw)?;
+        let size = proof.to_bytes().len();
+        println!(
+            "initial proof: degree bits {}, proof size {} bytes",
+            data.common.degree_bits(),
+            size
+        );
+
+        let configs = default_shrink_configs();
+        assert!(shrink_proof(&proof, &data.verifier_only, &data.common, &configs[..1], 1).is_err());
+
+        let target_size = 80_000;
+        let shrunk = shrink_proof(
+            &proof,
+            &data.verifier_only,
+            &data.common,
+            &configs,
+            target_size,
+        )?;
+        for (i, layer) in shrunk.layers.iter().enumerate() {
+            println!("layer {}: {:?}", i, layer);
+        }
+        assert!(size > target_size);
+        assert!(shrunk.layers.last().unwrap().proof_size <= target_size);
+        assert_eq!(shrunk.proof.public_inputs, proof.public_inputs);
+        shrunk.data.verify(shrunk.proof)
+    }
+}
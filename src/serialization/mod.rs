@@ -0,0 +1,384 @@
+//! Gate and witness generator serializers that know about the Poseidon2 gates,
+//! so that circuit data built with this crate can be written to and read from bytes.
+
+use core::marker::PhantomData;
+
+use plonky2::gadgets::arithmetic::EqualityGenerator;
+use plonky2::gadgets::arithmetic_extension::QuotientGeneratorExtension;
+use plonky2::gadgets::range_check::LowHighGenerator;
+use plonky2::gadgets::split_base::BaseSumGenerator;
+use plonky2::gadgets::split_join::{SplitGenerator, WireSplitGenerator};
+use plonky2::gates::arithmetic_base::{ArithmeticBaseGenerator, ArithmeticGate};
+use plonky2::gates::arithmetic_extension::{ArithmeticExtensionGate, ArithmeticExtensionGenerator};
+use plonky2::gates::base_sum::{BaseSplitGenerator, BaseSumGate};
+use plonky2::gates::constant::ConstantGate;
+use plonky2::gates::gate::GateRef;
+use plonky2::gates::coset_interpolation::{CosetInterpolationGate, InterpolationGenerator};
+use plonky2::gates::exponentiation::{ExponentiationGate, ExponentiationGenerator};
+use plonky2::gates::lookup::{LookupGate, LookupGenerator};
+use plonky2::gates::lookup_table::{LookupTableGate, LookupTableGenerator};
+use plonky2::gates::multiplication_extension::{MulExtensionGate, MulExtensionGenerator};
+use plonky2::gates::noop::NoopGate;
+use plonky2::gates::poseidon::{PoseidonGate, PoseidonGenerator};
+use plonky2::gates::poseidon_mds::{PoseidonMdsGate, PoseidonMdsGenerator};
+use plonky2::gates::public_input::PublicInputGate;
+use plonky2::gates::random_access::{RandomAccessGate, RandomAccessGenerator};
+use plonky2::gates::reducing::{ReducingGate, ReducingGenerator};
+use plonky2::gates::reducing_extension::{
+    ReducingExtensionGate, ReducingGenerator as ReducingExtensionGenerator,
+};
+use plonky2::hash::hash_types::RichField;
+use plonky2::iop::generator::{
+    ConstantGenerator, CopyGenerator, NonzeroTestGenerator, RandomValueGenerator, WitnessGenerator,
+    WitnessGeneratorRef,
+};
+use plonky2::plonk::config::{AlgebraicHasher, GenericConfig};
+use plonky2::recursion::dummy_circuit::DummyProofGenerator;
+use plonky2::plonk::circuit_data::CommonCircuitData;
+use plonky2::util::serialization::{
+    Buffer, GateSerializer, IoResult, Read, WitnessGeneratorSerializer, Write,
+};
+use plonky2::{get_gate_tag_impl, get_generator_tag_impl, read_gate_impl, read_generator_impl};
+use plonky2_field::extension::Extendable;
+
+use crate::gate::poseidon2::{Poseidon2Gate, Poseidon2Generator};
+use crate::gate::poseidon2_batch::Poseidon2BatchGenerator;
+use crate::gate::poseidon2_external::{
+    Poseidon2ExternalMatmulGate, Poseidon2ExternalMatmulGenerator,
+};
+use crate::gate::poseidon2_position::{Poseidon2PositionGate, Poseidon2PositionGenerator};
+use crate::gate::poseidon2_sponge::{Poseidon2SpongeGate, Poseidon2SpongeGenerator};
+use crate::poseidon2_hash::poseidon2::Poseidon2;
+
+/// A gate serializer supporting all default `plonky2` gates as well as the
+/// Poseidon2 gates of this crate.
+///
+/// `impl_gate_serializer!` cannot be used from outside `plonky2`, so the trait
+/// is implemented by hand with the same tag layout.
+#[derive(Debug)]
+pub struct Poseidon2GateSerializer;
+impl<F: RichField + Extendable<D> + Poseidon2, const D: usize> GateSerializer<F, D>
+    for Poseidon2GateSerializer
+{
+    fn read_gate(
+        &self,
+        buf: &mut Buffer,
+        common: &CommonCircuitData<F, D>,
+    ) -> IoResult<GateRef<F, D>> {
+        let tag = buf.read_u32()?;
+        read_gate_impl!(
+            buf,
+            tag,
+            common,
+            ArithmeticGate,
+            ArithmeticExtensionGate<D>,
+            BaseSumGate<2>,
+            ConstantGate,
+            CosetInterpolationGate<F, D>,
+            ExponentiationGate<F, D>,
+            LookupGate,
+            LookupTableGate,
+            MulExtensionGate<D>,
+            NoopGate,
+            PoseidonMdsGate<F, D>,
+            PoseidonGate<F, D>,
+            PublicInputGate,
+            RandomAccessGate<F, D>,
+            ReducingExtensionGate<D>,
+            ReducingGate<D>,
+            Poseidon2Gate<F, D>,
+            Poseidon2ExternalMatmulGate<F, D>,
+            Poseidon2PositionGate<F, D>,
+            Poseidon2SpongeGate<F, D>
+        )
+    }
+
+    fn write_gate(
+        &self,
+        buf: &mut Vec<u8>,
+        gate: &GateRef<F, D>,
+        common: &CommonCircuitData<F, D>,
+    ) -> IoResult<()> {
+        let tag = get_gate_tag_impl!(
+            gate,
+            ArithmeticGate,
+            ArithmeticExtensionGate<D>,
+            BaseSumGate<2>,
+            ConstantGate,
+            CosetInterpolationGate<F, D>,
+            ExponentiationGate<F, D>,
+            LookupGate,
+            LookupTableGate,
+            MulExtensionGate<D>,
+            NoopGate,
+            PoseidonMdsGate<F, D>,
+            PoseidonGate<F, D>,
+            PublicInputGate,
+            RandomAccessGate<F, D>,
+            ReducingExtensionGate<D>,
+            ReducingGate<D>,
+            Poseidon2Gate<F, D>,
+            Poseidon2ExternalMatmulGate<F, D>,
+            Poseidon2PositionGate<F, D>,
+            Poseidon2SpongeGate<F, D>
+        )?;
+
+        buf.write_u32(tag)?;
+        gate.0.serialize(buf, common)?;
+        Ok(())
+    }
+}
+
+/// Tag of the [`Poseidon2BatchGenerator`], which is not a `SimpleGenerator` and
+/// so cannot be handled by `read_generator_impl!`.
+const BATCH_GENERATOR_TAG: u32 = u32::MAX;
+
+/// A witness generator serializer supporting all default `plonky2` generators
+/// as well as the generators of the Poseidon2 gates.
+///
+/// It is implemented by hand rather than with `impl_generator_serializer!` to
+/// also support the [`Poseidon2BatchGenerator`].
+#[derive(Debug, Default)]
+pub struct Poseidon2GeneratorSerializer<C: GenericConfig<D>, const D: usize> {
+    pub _phantom: PhantomData<C>,
+}
+
+impl<F, C, const D: usize> WitnessGeneratorSerializer<F, D> for Poseidon2GeneratorSerializer<C, D>
+where
+    F: RichField + Extendable<D> + Poseidon2,
+    C: GenericConfig<D, F = F> + 'static,
+    C::Hasher: AlgebraicHasher<F>,
+{
+    fn read_generator(
+        &self,
+        buf: &mut Buffer,
+        common: &CommonCircuitData<F, D>,
+    ) -> IoResult<WitnessGeneratorRef<F, D>> {
+        let tag = buf.read_u32()?;
+        if tag == BATCH_GENERATOR_TAG {
+            let generator = Poseidon2BatchGenerator::<F, D>::deserialize(buf, common)?;
+            return Ok(WitnessGeneratorRef::new(generator));
+        }
+        read_generator_impl!(
+            buf,
+            tag,
+            common,
+            ArithmeticBaseGenerator<F, D>,
+            ArithmeticExtensionGenerator<F, D>,
+            BaseSplitGenerator<2>,
+            BaseSumGenerator<2>,
+            ConstantGenerator<F>,
+            CopyGenerator,
+            DummyProofGenerator<F, C, D>,
+            EqualityGenerator,
+            ExponentiationGenerator<F, D>,
+            InterpolationGenerator<F, D>,
+            LookupGenerator,
+            LookupTableGenerator,
+            LowHighGenerator,
+            MulExtensionGenerator<F, D>,
+            NonzeroTestGenerator,
+            PoseidonGenerator<F, D>,
+            PoseidonMdsGenerator<D>,
+            QuotientGeneratorExtension<D>,
+            RandomAccessGenerator<F, D>,
+            RandomValueGenerator,
+            ReducingGenerator<D>,
+            ReducingExtensionGenerator<D>,
+            SplitGenerator,
+            WireSplitGenerator,
+            Poseidon2Generator<F, D>,
+            Poseidon2ExternalMatmulGenerator<F, D>,
+            Poseidon2PositionGenerator<F, D>,
+            Poseidon2SpongeGenerator<F, D>
+        )
+    }
+
+    fn write_generator(
+        &self,
+        buf: &mut Vec<u8>,
+        generator: &WitnessGeneratorRef<F, D>,
+        common: &CommonCircuitData<F, D>,
+    ) -> IoResult<()> {
+        let tag = if generator.0.id() == Poseidon2BatchGenerator::<F, D>::default().id() {
+            BATCH_GENERATOR_TAG
+        } else {
+            get_generator_tag_impl!(
+                generator,
+                ArithmeticBaseGenerator<F, D>,
+                ArithmeticExtensionGenerator<F, D>,
+                BaseSplitGenerator<2>,
+                BaseSumGenerator<2>,
+                ConstantGenerator<F>,
+                CopyGenerator,
+                DummyProofGenerator<F, C, D>,
+                EqualityGenerator,
+                ExponentiationGenerator<F, D>,
+                InterpolationGenerator<F, D>,
+                LookupGenerator,
+                LookupTableGenerator,
+                LowHighGenerator,
+                MulExtensionGenerator<F, D>,
+                NonzeroTestGenerator,
+                PoseidonGenerator<F, D>,
+                PoseidonMdsGenerator<D>,
+                QuotientGeneratorExtension<D>,
+                RandomAccessGenerator<F, D>,
+                RandomValueGenerator,
+                ReducingGenerator<D>,
+                ReducingExtensionGenerator<D>,
+                SplitGenerator,
+                WireSplitGenerator,
+                Poseidon2Generator<F, D>,
+                Poseidon2ExternalMatmulGenerator<F, D>,
+                Poseidon2PositionGenerator<F, D>,
+                Poseidon2SpongeGenerator<F, D>
+            )?
+        };
+
+        buf.write_u32(tag)?;
+        generator.0.serialize(buf, common)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use plonky2::gates::gate::GateRef;
+    use plonky2::iop::witness::{PartialWitness, WitnessWrite};
+    use plonky2::plonk::circuit_builder::CircuitBuilder;
+    use plonky2::plonk::circuit_data::{
+        CircuitConfig, CircuitData, ProverCircuitData, VerifierCircuitData,
+    };
+    use plonky2::plonk::config::GenericConfig;
+    use plonky2::util::serialization::{Buffer, GateSerializer};
+    use plonky2_field::types::Field;
+
+    use crate::config::Poseidon2GoldilocksConfig;
+    use crate::gate::poseidon2::Poseidon2Gate;
+    use crate::poseidon2_hash::poseidon2::{Poseidon2Hash, WIDTH};
+    use crate::serialization::{Poseidon2GateSerializer, Poseidon2GeneratorSerializer};
+
+    const D: usize = 2;
+    type C = Poseidon2GoldilocksConfig;
+    type F = <C as GenericConfig<D>>::F;
+
+    fn hash_circuit() -> (CircuitData<F, C, D>, PartialWitness<F>) {
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let inputs = builder.add_virtual_targets(WIDTH);
+        let hash = builder.hash_n_to_hash_no_pad::<Poseidon2Hash>(inputs.clone());
+        builder.register_public_inputs(&hash.elements);
+        let data = builder.build::<C>();
+
+        let mut pw = PartialWitness::new();
+        for (i, &t) in inputs.iter().enumerate() {
+            pw.set_target(t, F::from_canonical_usize(i));
+        }
+        (data, pw)
+    }
+
+    #[test]
+    fn circuit_data_round_trip() -> Result<()> {
+        let gate_serializer = Poseidon2GateSerializer;
+        let generator_serializer = Poseidon2GeneratorSerializer::<C, D>::default();
+
+        let (data, pw) = hash_circuit();
+        let bytes = data
+            .to_bytes(&gate_serializer, &generator_serializer)
+            .map_err(|_| anyhow::anyhow!("failed to serialize circuit data"))?;
+        let data_from_bytes =
+            CircuitData::<F, C, D>::from_bytes(&bytes, &gate_serializer, &generator_serializer)
+                .map_err(|_| anyhow::anyhow!("failed to deserialize circuit data"))?;
+        assert_eq!(data, data_from_bytes);
+
+        let proof = data_from_bytes.prove(pw)?;
+        data.verify(proof)
+    }
+
+    #[test]
+    fn recursive_circuit_data_round_trip() -> Result<()> {
+        let gate_serializer = Poseidon2GateSerializer;
+        let generator_serializer = Poseidon2GeneratorSerializer::<C, D>::default();
+
+        let (inner_data, pw) = hash_circuit();
+        let inner_proof = inner_data.prove(pw)?;
+
+        // The recursive verifier uses `Poseidon2ExternalMatmulGate` and its generator.
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let proof_t = builder.add_virtual_proof_with_pis(&inner_data.common);
+        let verifier_t = builder.constant_verifier_data(&inner_data.verifier_only);
+        builder.verify_proof::<C>(&proof_t, &verifier_t, &inner_data.common);
+        let data = builder.build::<C>();
+        assert!(data
+            .common
+            .gates
+            .iter()
+            .any(|gate| gate.0.id().starts_with("Poseidon2ExternalMatmulGate")));
+
+        let bytes = data
+            .to_bytes(&gate_serializer, &generator_serializer)
+            .map_err(|_| anyhow::anyhow!("failed to serialize circuit data"))?;
+        let data_from_bytes =
+            CircuitData::<F, C, D>::from_bytes(&bytes, &gate_serializer, &generator_serializer)
+                .map_err(|_| anyhow::anyhow!("failed to deserialize circuit data"))?;
+        assert_eq!(data, data_from_bytes);
+
+        let mut pw = PartialWitness::new();
+        pw.set_proof_with_pis_target(&proof_t, &inner_proof);
+        let proof = data_from_bytes.prove(pw)?;
+        data.verify(proof)
+    }
+
+    #[test]
+    fn prover_and_verifier_data_round_trip() -> Result<()> {
+        let gate_serializer = Poseidon2GateSerializer;
+        let generator_serializer = Poseidon2GeneratorSerializer::<C, D>::default();
+
+        let (data, pw) = hash_circuit();
+        let verifier_data = data.verifier_data();
+        let prover_data = data.prover_data();
+
+        let prover_bytes = prover_data
+            .to_bytes(&gate_serializer, &generator_serializer)
+            .map_err(|_| anyhow::anyhow!("failed to serialize prover data"))?;
+        let verifier_bytes = verifier_data
+            .to_bytes(&gate_serializer)
+            .map_err(|_| anyhow::anyhow!("failed to serialize verifier data"))?;
+
+        let prover_data = ProverCircuitData::<F, C, D>::from_bytes(
+            &prover_bytes,
+            &gate_serializer,
+            &generator_serializer,
+        )
+        .map_err(|_| anyhow::anyhow!("failed to deserialize prover data"))?;
+        let verifier_data =
+            VerifierCircuitData::<F, C, D>::from_bytes(verifier_bytes, &gate_serializer)
+                .map_err(|_| anyhow::anyhow!("failed to deserialize verifier data"))?;
+
+        let proof = prover_data.prove(pw)?;
+        verifier_data.verify(proof)
+    }
+
+    #[test]
+    fn gate_version_mismatch_is_rejected() {
+        let gate_serializer = Poseidon2GateSerializer;
+        let (data, _) = hash_circuit();
+        let gate = GateRef::<F, D>::new(Poseidon2Gate::<F, D>::new());
+
+        let mut bytes = Vec::new();
+        gate_serializer
+            .write_gate(&mut bytes, &gate, &data.common)
+            .unwrap();
+        let read = gate_serializer
+            .read_gate(&mut Buffer::new(&bytes), &data.common)
+            .unwrap();
+        assert_eq!(read.0.id(), gate.0.id());
+
+        // The version is written right after the 4-byte gate tag.
+        bytes[4] ^= 1;
+        assert!(gate_serializer
+            .read_gate(&mut Buffer::new(&bytes), &data.common)
+            .is_err());
+    }
+}
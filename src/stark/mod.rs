@@ -0,0 +1,4 @@
+//! Algebraic intermediate representations of Poseidon2, for STARK provers.
+
+pub mod poseidon2_air;
+pub mod poseidon2_stark;
@@ -0,0 +1,239 @@
+//! An AIR whose rows are Poseidon2 permutations over the Goldilocks field.
+//!
+//! Each row holds the inputs of a permutation, the S-box inputs of its rounds
+//! in the layout of [`Poseidon2RoundWires`], its outputs and a filter flag,
+//! so the constraints are those of `Poseidon2Gate` without the swap, of
+//! degree 7 and local to a row. Padding rows are permutations of the zero
+//! state with the filter unset. Cross-table lookups read the inputs and the
+//! outputs of the rows whose filter is set, see [`Poseidon2Air::ctl_columns`].
+//!
+//! This module provides the trace layout, its generation and the row
+//! constraints over the base field, over packed extension fields and in a
+//! circuit. The `starky::Stark` implementation on top of it is
+//! [`Poseidon2Stark`](crate::stark::poseidon2_stark::Poseidon2Stark).
+
+use core::ops::Range;
+
+use plonky2::field::polynomial::PolynomialValues;
+use plonky2::hash::hash_types::RichField;
+use plonky2::iop::ext_target::ExtensionTarget;
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+use plonky2::util::transpose;
+use plonky2_field::extension::{Extendable, FieldExtension};
+use plonky2_field::packed::PackedField;
+
+use crate::gate::poseidon2_external::matmul_external_circuit;
+use crate::gate::poseidon2_rounds::Poseidon2RoundWires;
+use crate::poseidon2_hash::poseidon2::{Poseidon2, WIDTH};
+
+/// The columns and constraints of the Poseidon2 AIR.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Poseidon2Air;
+
+impl Poseidon2Air {
+    /// The S-box input columns of the rounds, after the inputs.
+    pub const ROUNDS: Poseidon2RoundWires = Poseidon2RoundWires::new(WIDTH);
+
+    const START_OUTPUT: usize = WIDTH + Poseidon2RoundWires::NUM_WIRES;
+
+    /// Set to 1 on the rows holding a permutation to look up, and to 0 on the
+    /// padding rows.
+    pub const COL_FILTER: usize = Self::START_OUTPUT + WIDTH;
+
+    pub const NUM_COLUMNS: usize = Self::COL_FILTER + 1;
+
+    /// The filter is binary, plus one constraint per round and output column.
+    pub const NUM_CONSTRAINTS: usize = 1 + Poseidon2RoundWires::NUM_CONSTRAINTS + WIDTH;
+
+    pub const CONSTRAINT_DEGREE: usize = 7;
+
+    /// The column of the `i`th input of the permutation.
+    pub fn col_input(i: usize) -> usize {
+        debug_assert!(i < WIDTH);
+        i
+    }
+
+    /// The column of the `i`th output of the permutation.
+    pub fn col_output(i: usize) -> usize {
+        debug_assert!(i < WIDTH);
+        Self::START_OUTPUT + i
+    }
+
+    /// The columns to look up, inputs then outputs, and the filter column
+    /// selecting the rows to look up.
+    pub fn ctl_columns() -> (Range<usize>, Range<usize>, usize) {
+        (
+            0..WIDTH,
+            Self::START_OUTPUT..Self::START_OUTPUT + WIDTH,
+            Self::COL_FILTER,
+        )
+    }
+
+    /// The rows of the permutations of `inputs`, padded to the next power of
+    /// two, and to at least `min_rows` rows.
+    pub fn generate_rows<F: RichField + Poseidon2>(
+        inputs: &[[F; WIDTH]],
+        min_rows: usize,
+    ) -> Vec<Vec<F>> {
+        let num_rows = inputs.len().max(min_rows).max(1).next_power_of_two();
+        let mut rows = inputs
+            .iter()
+            .map(|&input| Self::generate_row(input, true))
+            .collect::<Vec<_>>();
+        rows.resize(num_rows, Self::generate_row([F::ZERO; WIDTH], false));
+        rows
+    }
+
+    /// The trace of the permutations of `inputs`, as column polynomials, see
+    /// [`Self::generate_rows`].
+    pub fn generate_trace<F: RichField + Poseidon2>(
+        inputs: &[[F; WIDTH]],
+        min_rows: usize,
+    ) -> Vec<PolynomialValues<F>> {
+        transpose(&Self::generate_rows(inputs, min_rows))
+            .into_iter()
+            .map(PolynomialValues::new)
+            .collect()
+    }
+
+    fn generate_row<F: RichField + Poseidon2>(input: [F; WIDTH], is_real: bool) -> Vec<F> {
+        let trace = <F as Poseidon2>::poseidon2_trace(input);
+        let mut row = Vec::with_capacity(Self::NUM_COLUMNS);
+        row.extend(input);
+        row.extend(Poseidon2RoundWires::round_values(&trace));
+        row.extend(trace.output());
+        row.push(F::from_bool(is_real));
+        debug_assert_eq!(row.len(), Self::NUM_COLUMNS);
+        row
+    }
+
+    /// Evaluates the constraints of `row`, which all vanish on a valid row.
+    pub fn eval_row<F: RichField + Poseidon2>(row: &[F], mut yield_constr: impl FnMut(F)) {
+        let filter = row[Self::COL_FILTER];
+        yield_constr(filter * (filter - F::ONE));
+
+        let mut state: [F; WIDTH] = core::array::from_fn(|i| row[Self::col_input(i)]);
+        <F as Poseidon2>::matmul_external(&mut state);
+        let state = Self::ROUNDS.eval_base(row, state, &mut yield_constr);
+        for (i, s) in state.into_iter().enumerate() {
+            yield_constr(s - row[Self::col_output(i)]);
+        }
+    }
+
+    /// Same as [`Self::eval_row`], over packed extension fields.
+    pub fn eval_row_packed<F, FE, P, const D2: usize>(row: &[P], mut yield_constr: impl FnMut(P))
+    where
+        F: RichField + Poseidon2,
+        FE: FieldExtension<D2, BaseField = F>,
+        P: PackedField<Scalar = FE>,
+    {
+        let filter = row[Self::COL_FILTER];
+        yield_constr(filter * (filter - P::ONES));
+
+        let mut state: [P; WIDTH] = core::array::from_fn(|i| row[Self::col_input(i)]);
+        <F as Poseidon2>::matmul_external_packed(&mut state);
+        let state = Self::ROUNDS.eval_packed::<F, FE, P, D2>(row, state, &mut yield_constr);
+        for (i, s) in state.into_iter().enumerate() {
+            yield_constr(s - row[Self::col_output(i)]);
+        }
+    }
+
+    /// Same as [`Self::eval_row`], in a circuit.
+    pub fn eval_row_circuit<F: RichField + Extendable<D> + Poseidon2, const D: usize>(
+        builder: &mut CircuitBuilder<F, D>,
+        row: &[ExtensionTarget<D>],
+    ) -> Vec<ExtensionTarget<D>> {
+        let mut constraints = Vec::with_capacity(Self::NUM_CONSTRAINTS);
+        let filter = row[Self::COL_FILTER];
+        constraints.push(builder.mul_sub_extension(filter, filter, filter));
+
+        let mut state: [ExtensionTarget<D>; WIDTH] =
+            core::array::from_fn(|i| row[Self::col_input(i)]);
+        state = matmul_external_circuit(builder, state);
+        let state = Self::ROUNDS.eval_circuit(builder, row, state, &mut constraints);
+        for (i, s) in state.into_iter().enumerate() {
+            constraints.push(builder.sub_extension(s, row[Self::col_output(i)]));
+        }
+        constraints
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use plonky2::iop::generator::generate_partial_witness;
+    use plonky2::iop::witness::{PartialWitness, Witness, WitnessWrite};
+    use plonky2::plonk::circuit_builder::CircuitBuilder;
+    use plonky2::plonk::circuit_data::CircuitConfig;
+    use plonky2_field::goldilocks_field::GoldilocksField;
+    use plonky2_field::types::{Field, Sample};
+
+    use crate::config::Poseidon2GoldilocksConfig;
+    use crate::poseidon2_hash::poseidon2::{Poseidon2, WIDTH};
+    use crate::stark::poseidon2_air::Poseidon2Air;
+
+    const D: usize = 2;
+    type F = GoldilocksField;
+
+    fn constraints(row: &[F]) -> Vec<F> {
+        let mut constraints = Vec::new();
+        Poseidon2Air::eval_row(row, |c| constraints.push(c));
+        assert_eq!(constraints.len(), Poseidon2Air::NUM_CONSTRAINTS);
+        constraints
+    }
+
+    #[test]
+    fn trace() {
+        let inputs = (0..5).map(|_| F::rand_array::<WIDTH>()).collect::<Vec<_>>();
+        let rows = Poseidon2Air::generate_rows(&inputs, 0);
+        assert_eq!(rows.len(), 8);
+
+        let (input_cols, output_cols, filter_col) = Poseidon2Air::ctl_columns();
+        for (i, row) in rows.iter().enumerate() {
+            assert_eq!(row.len(), Poseidon2Air::NUM_COLUMNS);
+            assert!(constraints(row).iter().all(|c| c.is_zero()));
+            let input = inputs.get(i).copied().unwrap_or([F::ZERO; WIDTH]);
+            assert_eq!(row[input_cols.clone()], input);
+            assert_eq!(row[output_cols.clone()], F::poseidon2(input));
+            assert_eq!(row[filter_col], F::from_bool(i < inputs.len()));
+        }
+
+        let trace = Poseidon2Air::generate_trace(&inputs, 32);
+        assert_eq!(trace.len(), Poseidon2Air::NUM_COLUMNS);
+        assert!(trace.iter().all(|column| column.len() == 32));
+    }
+
+    #[test]
+    fn tampered_rows() {
+        let row = &Poseidon2Air::generate_rows(&[F::rand_array::<WIDTH>()], 0)[0];
+        for column in 0..Poseidon2Air::NUM_COLUMNS {
+            let mut tampered = row.clone();
+            tampered[column] += F::ONE;
+            let constraints = constraints(&tampered);
+            assert!(
+                constraints.iter().any(|c| !c.is_zero()),
+                "Tampering column {} was not detected",
+                column
+            );
+        }
+    }
+
+    #[test]
+    fn circuit_constraints() {
+        let row = &Poseidon2Air::generate_rows(&[F::rand_array::<WIDTH>()], 0)[0];
+
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let row_t = builder.add_virtual_extension_targets(Poseidon2Air::NUM_COLUMNS);
+        let constraints_t = Poseidon2Air::eval_row_circuit(&mut builder, &row_t);
+        assert_eq!(constraints_t.len(), Poseidon2Air::NUM_CONSTRAINTS);
+        let data = builder.build::<Poseidon2GoldilocksConfig>();
+
+        let mut pw = PartialWitness::new();
+        for (&t, &value) in row_t.iter().zip(row) {
+            pw.set_extension_target(t, value.into());
+        }
+        let witness = generate_partial_witness(pw, &data.prover_only, &data.common);
+        assert!(constraints_t
+            .iter()
+            .all(|&c| witness.get_extension_target(c).is_zero()));
+    }
+}
@@ -0,0 +1,256 @@
+//! A starky STARK whose rows are Poseidon2 permutations over the Goldilocks
+//! field, on top of [`Poseidon2Air`].
+//!
+//! Other tables hash through cross-table lookups into the inputs and outputs
+//! of [`Poseidon2Stark::ctl_table`]. The constraints have degree 7, so the
+//! FRI rate must be at least 1/8, see [`Poseidon2Stark::config`].
+
+use core::marker::PhantomData;
+
+use plonky2::field::extension::{Extendable, FieldExtension};
+use plonky2::field::packed::PackedField;
+use plonky2::field::polynomial::PolynomialValues;
+use plonky2::fri::reduction_strategies::FriReductionStrategy;
+use plonky2::fri::FriConfig;
+use plonky2::hash::hash_types::RichField;
+use plonky2::iop::ext_target::ExtensionTarget;
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+use starky::config::StarkConfig;
+use starky::constraint_consumer::{ConstraintConsumer, RecursiveConstraintConsumer};
+use starky::cross_table_lookup::{TableIdx, TableWithColumns};
+use starky::evaluation_frame::{StarkEvaluationFrame, StarkFrame};
+use starky::lookup::{Column, Filter};
+use starky::stark::Stark;
+
+use crate::poseidon2_hash::poseidon2::{Poseidon2, WIDTH};
+use crate::stark::poseidon2_air::Poseidon2Air;
+
+const COLUMNS: usize = Poseidon2Air::NUM_COLUMNS;
+const PUBLIC_INPUTS: usize = 0;
+
+/// A STARK proving Poseidon2 permutations, one per row.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Poseidon2Stark<F: RichField + Extendable<D>, const D: usize> {
+    requires_ctls: bool,
+    _phantom: PhantomData<F>,
+}
+
+impl<F: RichField + Extendable<D> + Poseidon2, const D: usize> Poseidon2Stark<F, D> {
+    /// A standalone STARK, proven and verified on its own.
+    pub const fn new() -> Self {
+        Self {
+            requires_ctls: false,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// A STARK of a multi-STARK system, whose permutations are looked up by
+    /// other tables, see [`Self::ctl_table`].
+    pub const fn new_with_ctls() -> Self {
+        Self {
+            requires_ctls: true,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// A configuration with a rate of 1/8, the highest the degree of the
+    /// constraints allows, targeting ~100 bits of conjectured security.
+    pub const fn config() -> StarkConfig {
+        StarkConfig::new(
+            100,
+            2,
+            FriConfig {
+                rate_bits: 3,
+                cap_height: 4,
+                proof_of_work_bits: 16,
+                reduction_strategy: FriReductionStrategy::ConstantArityBits(4, 5),
+                num_query_rounds: 28,
+            },
+        )
+    }
+
+    /// The trace of the permutations of `inputs`, see
+    /// [`Poseidon2Air::generate_trace`].
+    pub fn generate_trace(
+        &self,
+        inputs: &[[F; WIDTH]],
+        min_rows: usize,
+    ) -> Vec<PolynomialValues<F>> {
+        Poseidon2Air::generate_trace(inputs, min_rows)
+    }
+
+    /// The columns to look up, the `WIDTH` inputs followed by the `WIDTH`
+    /// outputs of a permutation.
+    pub fn ctl_columns() -> Vec<Column<F>> {
+        let (inputs, outputs, _) = Poseidon2Air::ctl_columns();
+        Column::singles(inputs.chain(outputs)).collect()
+    }
+
+    /// Selects the rows holding a permutation of the inputs, i.e. not padding.
+    pub fn ctl_filter() -> Filter<F> {
+        let (_, _, filter) = Poseidon2Air::ctl_columns();
+        Filter::new_simple(Column::single(filter))
+    }
+
+    /// The looked table of a cross-table lookup of permutations, with this
+    /// STARK at index `table`.
+    pub fn ctl_table(table: TableIdx) -> TableWithColumns<F> {
+        TableWithColumns::new(table, Self::ctl_columns(), Self::ctl_filter())
+    }
+}
+
+impl<F: RichField + Extendable<D> + Poseidon2, const D: usize> Stark<F, D>
+    for Poseidon2Stark<F, D>
+{
+    type EvaluationFrame<FE, P, const D2: usize>
+        = StarkFrame<P, P::Scalar, COLUMNS, PUBLIC_INPUTS>
+    where
+        FE: FieldExtension<D2, BaseField = F>,
+        P: PackedField<Scalar = FE>;
+
+    type EvaluationFrameTarget =
+        StarkFrame<ExtensionTarget<D>, ExtensionTarget<D>, COLUMNS, PUBLIC_INPUTS>;
+
+    fn eval_packed_generic<FE, P, const D2: usize>(
+        &self,
+        vars: &Self::EvaluationFrame<FE, P, D2>,
+        yield_constr: &mut ConstraintConsumer<P>,
+    ) where
+        FE: FieldExtension<D2, BaseField = F>,
+        P: PackedField<Scalar = FE>,
+    {
+        Poseidon2Air::eval_row_packed::<F, FE, P, D2>(vars.get_local_values(), |c| {
+            yield_constr.constraint(c)
+        });
+    }
+
+    fn eval_ext_circuit(
+        &self,
+        builder: &mut CircuitBuilder<F, D>,
+        vars: &Self::EvaluationFrameTarget,
+        yield_constr: &mut RecursiveConstraintConsumer<F, D>,
+    ) {
+        for c in Poseidon2Air::eval_row_circuit(builder, vars.get_local_values()) {
+            yield_constr.constraint(builder, c);
+        }
+    }
+
+    fn constraint_degree(&self) -> usize {
+        Poseidon2Air::CONSTRAINT_DEGREE
+    }
+
+    fn requires_ctls(&self) -> bool {
+        self.requires_ctls
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use plonky2::iop::witness::PartialWitness;
+    use plonky2::plonk::circuit_builder::CircuitBuilder;
+    use plonky2::plonk::circuit_data::CircuitConfig;
+    use plonky2::plonk::config::GenericConfig;
+    use plonky2::util::timing::TimingTree;
+    use plonky2_field::polynomial::PolynomialValues;
+    use plonky2_field::types::{Field, Sample};
+    use starky::cross_table_lookup::debug_utils::check_ctls;
+    use starky::cross_table_lookup::{CrossTableLookup, TableWithColumns};
+    use starky::lookup::{Column, Filter};
+    use starky::prover::prove;
+    use starky::recursive_verifier::{
+        add_virtual_stark_proof_with_pis, set_stark_proof_with_pis_target,
+        verify_stark_proof_circuit,
+    };
+    use starky::stark_testing::{test_stark_circuit_constraints, test_stark_low_degree};
+    use starky::verifier::verify_stark_proof;
+
+    use crate::config::Poseidon2GoldilocksConfig;
+    use crate::poseidon2_hash::poseidon2::{Poseidon2, WIDTH};
+    use crate::stark::poseidon2_stark::Poseidon2Stark;
+
+    const D: usize = 2;
+    type C = Poseidon2GoldilocksConfig;
+    type F = <C as GenericConfig<D>>::F;
+    type S = Poseidon2Stark<F, D>;
+
+    #[test]
+    fn low_degree() -> Result<()> {
+        test_stark_low_degree(S::new())
+    }
+
+    #[test]
+    fn circuit_constraints() -> Result<()> {
+        test_stark_circuit_constraints::<F, C, S, D>(S::new())
+    }
+
+    /// A table holding `inputs` and the given outputs, all looking into the
+    /// permutation table.
+    fn looking_trace(inputs: &[[F; WIDTH]], outputs: &[[F; WIDTH]]) -> Vec<PolynomialValues<F>> {
+        (0..WIDTH)
+            .map(|i| inputs.iter().map(|input| input[i]).collect())
+            .chain((0..WIDTH).map(|i| outputs.iter().map(|output| output[i]).collect()))
+            .map(PolynomialValues::new)
+            .collect()
+    }
+
+    fn check_lookups(inputs: &[[F; WIDTH]], outputs: &[[F; WIDTH]]) {
+        let traces = [
+            S::new().generate_trace(inputs, 0),
+            looking_trace(inputs, outputs),
+        ];
+        let looking = TableWithColumns::new(
+            1,
+            Column::singles(0..2 * WIDTH).collect(),
+            Filter::default(),
+        );
+        let ctl = CrossTableLookup::new(vec![looking], S::ctl_table(0));
+        check_ctls(&traces, &[ctl], &Default::default());
+    }
+
+    #[test]
+    fn lookups() {
+        // The permutation table is padded to 8 rows, which are not looked up.
+        let inputs = (0..5).map(|_| F::rand_array::<WIDTH>()).collect::<Vec<_>>();
+        let outputs = inputs
+            .iter()
+            .map(|&input| F::poseidon2(input))
+            .collect::<Vec<_>>();
+        check_lookups(&inputs, &outputs);
+    }
+
+    #[test]
+    #[should_panic]
+    fn lookups_wrong_output() {
+        let inputs = (0..4).map(|_| F::rand_array::<WIDTH>()).collect::<Vec<_>>();
+        let mut outputs = inputs
+            .iter()
+            .map(|&input| F::poseidon2(input))
+            .collect::<Vec<_>>();
+        outputs[2][5] += F::ONE;
+        check_lookups(&inputs, &outputs);
+    }
+
+    #[test]
+    fn recursive_verifier() -> Result<()> {
+        let config = S::config();
+        config.check_config::<F, D>()?;
+        let stark = S::new();
+        let inputs = (0..6).map(|_| F::rand_array::<WIDTH>()).collect::<Vec<_>>();
+        let trace = stark.generate_trace(&inputs, 0);
+        let proof = prove::<F, C, S, D>(stark, &config, trace, &[], &mut TimingTree::default())?;
+        verify_stark_proof(stark, proof.clone(), &config)?;
+
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let mut pw = PartialWitness::new();
+        let degree_bits = proof.proof.recover_degree_bits(&config);
+        let proof_t =
+            add_virtual_stark_proof_with_pis(&mut builder, &stark, &config, degree_bits, 0, 0);
+        set_stark_proof_with_pis_target(&mut pw, &proof_t, &proof, builder.zero());
+        verify_stark_proof_circuit::<F, C, S, D>(&mut builder, stark, proof_t, &config);
+
+        let data = builder.build::<C>();
+        let proof = data.prove(pw)?;
+        data.verify(proof)
+    }
+}